@@ -0,0 +1,158 @@
+use std::str::FromStr;
+use wavs_wasi_utils::evm::alloy_primitives::U256;
+
+/// How an incoming order's quantity is shared among the resting orders of
+/// the price level it trades against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Allocation {
+    /// Strict time priority: the oldest resting order fills first.
+    #[default]
+    Fifo,
+    /// Each resting order gets a share proportional to its available size.
+    ProRata,
+    /// The order at the front of the queue first takes up to
+    /// `priority_percent` of the quantity, the rest is shared pro-rata.
+    Hybrid { priority_percent: u8 },
+}
+
+impl Allocation {
+    /// Splits `quantity` among `resting`, given as `(order id, available)`
+    /// in queue order. Returns the non-zero amounts in queue order; their
+    /// total is `quantity` or everything available, whichever is less.
+    pub fn allocate(self, quantity: U256, resting: &[(u64, U256)]) -> Vec<(u64, U256)> {
+        let capacities: Vec<U256> = resting.iter().map(|(_, available)| *available).collect();
+        let amounts = match self {
+            Self::Fifo => fill_in_order(quantity, &capacities, vec![U256::ZERO; resting.len()]),
+            Self::ProRata => pro_rata(quantity, &capacities),
+            Self::Hybrid { priority_percent } => {
+                let mut amounts = vec![U256::ZERO; resting.len()];
+                let mut left = quantity;
+                if let Some(front) = amounts.first_mut() {
+                    *front = (quantity * U256::from(priority_percent) / U256::from(100))
+                        .min(capacities[0]);
+                    left -= *front;
+                }
+                let rest: Vec<U256> =
+                    capacities.iter().zip(&amounts).map(|(cap, taken)| *cap - *taken).collect();
+                for (amount, share) in amounts.iter_mut().zip(pro_rata(left, &rest)) {
+                    *amount += share;
+                }
+                amounts
+            }
+        };
+        resting
+            .iter()
+            .zip(amounts)
+            .filter(|(_, amount)| !amount.is_zero())
+            .map(|((id, _), amount)| (*id, amount))
+            .collect()
+    }
+}
+
+/// Shares rounded down in proportion to `capacities`. What rounding leaves
+/// over goes to the front of the queue, so the result only depends on the
+/// inputs and the whole quantity is always placed.
+fn pro_rata(quantity: U256, capacities: &[U256]) -> Vec<U256> {
+    let total: U256 = capacities.iter().copied().sum();
+    if quantity >= total {
+        return capacities.to_vec();
+    }
+    let shares = capacities.iter().map(|cap| quantity * *cap / total).collect::<Vec<_>>();
+    let placed: U256 = shares.iter().copied().sum();
+    fill_in_order(quantity - placed, capacities, shares)
+}
+
+/// Tops up `amounts` in queue order until `quantity` more is placed or
+/// every order is at capacity.
+fn fill_in_order(mut quantity: U256, capacities: &[U256], mut amounts: Vec<U256>) -> Vec<U256> {
+    for (amount, cap) in amounts.iter_mut().zip(capacities) {
+        let extra = quantity.min(*cap - *amount);
+        *amount += extra;
+        quantity -= extra;
+    }
+    amounts
+}
+
+impl FromStr for Allocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "fifo" => Ok(Self::Fifo),
+            None if s == "pro_rata" => Ok(Self::ProRata),
+            Some(("hybrid", percent)) => match percent.parse::<u8>() {
+                Ok(priority_percent) if priority_percent <= 100 => {
+                    Ok(Self::Hybrid { priority_percent })
+                }
+                _ => Err(format!("hybrid priority {percent:?} must be a percentage from 0 to 100")),
+            },
+            _ => Err(format!(
+                "unknown allocation {s:?}, expected fifo, pro_rata or hybrid:<priority percent>"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Allocates `quantity` over orders 1, 2, ... with `available` each.
+    fn allocate(allocation: Allocation, quantity: u64, available: &[u64]) -> Vec<(u64, u64)> {
+        let resting: Vec<(u64, U256)> =
+            available.iter().zip(1..).map(|(amount, id)| (id, U256::from(*amount))).collect();
+        allocation
+            .allocate(U256::from(quantity), &resting)
+            .into_iter()
+            .map(|(id, amount)| (id, amount.to::<u64>()))
+            .collect()
+    }
+
+    #[test]
+    fn fifo_fills_the_front_first() {
+        assert_eq!(allocate(Allocation::Fifo, 5, &[3, 3, 4]), vec![(1, 3), (2, 2)]);
+    }
+
+    #[test]
+    fn pro_rata_gives_the_rounding_remainder_to_the_front() {
+        // Shares of 5 by 3:3:4 are 1.5, 1.5 and 2, rounded down to 1, 1 and
+        // 2; the unit left over goes to order 1
+        assert_eq!(allocate(Allocation::ProRata, 5, &[3, 3, 4]), vec![(1, 2), (2, 1), (3, 2)]);
+    }
+
+    #[test]
+    fn pro_rata_remainder_skips_orders_at_capacity() {
+        // Shares of 5 by 1:3:6 round down to 0, 1 and 3; order 1 can take
+        // the unit left over
+        assert_eq!(allocate(Allocation::ProRata, 5, &[1, 3, 6]), vec![(1, 1), (2, 1), (3, 3)]);
+        // Shares of 9 by 2:4:4 round down to 1, 3 and 3; order 1 takes one
+        // of the 2 units left over to reach its 2, order 2 the other
+        assert_eq!(allocate(Allocation::ProRata, 9, &[2, 4, 4]), vec![(1, 2), (2, 4), (3, 3)]);
+    }
+
+    #[test]
+    fn pro_rata_leaves_out_orders_whose_share_rounds_to_nothing() {
+        assert_eq!(allocate(Allocation::ProRata, 1, &[3, 3, 4]), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn pro_rata_fills_everything_when_the_quantity_covers_it() {
+        assert_eq!(allocate(Allocation::ProRata, 20, &[3, 3, 4]), vec![(1, 3), (2, 3), (3, 4)]);
+    }
+
+    #[test]
+    fn hybrid_front_takes_its_slice_before_pro_rata() {
+        // Order 1 takes 40% of 10 first; the 6 left are shared by what is
+        // still available, 1:5:10, as 0, 1 and 3, and the 2 units rounding
+        // leaves go to orders 1 and 2 in queue order
+        let hybrid = Allocation::Hybrid { priority_percent: 40 };
+        assert_eq!(allocate(hybrid, 10, &[5, 5, 10]), vec![(1, 5), (2, 2), (3, 3)]);
+    }
+
+    #[test]
+    fn hybrid_slice_is_capped_by_the_front_order() {
+        // 80% of 10 is 8, but order 1 only has 2
+        let hybrid = Allocation::Hybrid { priority_percent: 80 };
+        assert_eq!(allocate(hybrid, 10, &[2, 10]), vec![(1, 2), (2, 8)]);
+    }
+}
@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use wavs_wasi_utils::evm::alloy_primitives::U256;
+
+/// Where a market in auction mode is in its cycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuctionRound {
+    /// Close of the most recent auction. Orders placed before it have taken
+    /// part in an auction, later ones wait for the next.
+    pub cutoff: u64,
+    /// Clearing price of an auction the batch limit cut short. The next run
+    /// finishes it at the same price before a new auction opens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unfinished_price: Option<U256>,
+}
+
+impl AuctionRound {
+    pub fn is_idle(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The single price an auction fills at and the volume that trades there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clearing {
+    pub price: U256,
+    pub volume: U256,
+}
+
+/// Demand and supply if the auction cleared at `price`.
+struct Candidate {
+    price: U256,
+    demand: U256,
+    supply: U256,
+}
+
+impl Candidate {
+    fn volume(&self) -> U256 {
+        self.demand.min(self.supply)
+    }
+
+    fn imbalance(&self) -> U256 {
+        self.demand.max(self.supply) - self.volume()
+    }
+}
+
+/// Finds the uniform clearing price for `bids` and `asks`, each given as
+/// `(limit price, amount)`. Only limit prices are candidates, since volume
+/// can only change at one. Among them the price is chosen by:
+///
+/// 1. the largest volume that trades;
+/// 2. then the smallest imbalance between demand and supply;
+/// 3. then, if buyers are left over at every remaining price, the highest
+///    of them, otherwise the lowest.
+///
+/// `None` if no bid crosses any ask.
+pub fn clearing_price(bids: &[(U256, U256)], asks: &[(U256, U256)]) -> Option<Clearing> {
+    let prices: BTreeSet<U256> = bids.iter().chain(asks).map(|(price, _)| *price).collect();
+    let mut candidates: Vec<Candidate> = prices
+        .into_iter()
+        .map(|price| Candidate {
+            price,
+            demand: bids.iter().filter(|(bid, _)| *bid >= price).map(|(_, amount)| *amount).sum(),
+            supply: asks.iter().filter(|(ask, _)| *ask <= price).map(|(_, amount)| *amount).sum(),
+        })
+        .filter(|candidate| !candidate.volume().is_zero())
+        .collect();
+
+    let max_volume = candidates.iter().map(Candidate::volume).max()?;
+    candidates.retain(|candidate| candidate.volume() == max_volume);
+    let min_imbalance = candidates.iter().map(Candidate::imbalance).min()?;
+    candidates.retain(|candidate| candidate.imbalance() == min_imbalance);
+
+    let buy_pressure = candidates.iter().all(|candidate| candidate.demand > candidate.supply);
+    let chosen = if buy_pressure { candidates.last() } else { candidates.first() }?;
+    Some(Clearing { price: chosen.price, volume: max_volume })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn orders(orders: &[(u64, u64)]) -> Vec<(U256, U256)> {
+        orders.iter().map(|(price, amount)| (U256::from(*price), U256::from(*amount))).collect()
+    }
+
+    fn clear(bids: &[(u64, u64)], asks: &[(u64, u64)]) -> Option<(u64, u64)> {
+        clearing_price(&orders(bids), &orders(asks))
+            .map(|clearing| (clearing.price.to::<u64>(), clearing.volume.to::<u64>()))
+    }
+
+    #[test]
+    fn nothing_clears_without_a_cross() {
+        assert_eq!(clear(&[(99, 10)], &[(100, 10)]), None);
+        assert_eq!(clear(&[(99, 10)], &[]), None);
+        assert_eq!(clear(&[], &[]), None);
+    }
+
+    #[test]
+    fn volume_is_maximised_first() {
+        // At 101 demand is 10+5 and supply 4+8, so 12 trades. At 100 only
+        // 4 would and at 103 only 10.
+        let bids = [(103, 10), (101, 5)];
+        let asks = [(100, 4), (101, 8), (104, 6)];
+        assert_eq!(clear(&bids, &asks), Some((101, 12)));
+    }
+
+    #[test]
+    fn equal_volume_prefers_smallest_imbalance() {
+        // Volume is 10 at 100, 101 and 105. Demand exceeds supply by 3 at
+        // 100 and 101; at 105 the 101 bid drops out and the book balances.
+        let bids = [(105, 10), (101, 3)];
+        let asks = [(100, 10)];
+        assert_eq!(clear(&bids, &asks), Some((105, 10)));
+    }
+
+    #[test]
+    fn buy_pressure_picks_the_highest_tied_price() {
+        // 10 trades at 100 and 105 alike with 5 bids left over at both.
+        let bids = [(105, 15)];
+        let asks = [(100, 10)];
+        assert_eq!(clear(&bids, &asks), Some((105, 10)));
+    }
+
+    #[test]
+    fn sell_pressure_picks_the_lowest_tied_price() {
+        // 10 trades at 100 and 105 alike with 5 asks left over at both.
+        let bids = [(105, 10)];
+        let asks = [(100, 15)];
+        assert_eq!(clear(&bids, &asks), Some((100, 10)));
+    }
+
+    #[test]
+    fn balanced_ties_pick_the_lowest_price() {
+        // Demand meets supply exactly anywhere from 100 to 105.
+        let bids = [(105, 10)];
+        let asks = [(100, 10)];
+        assert_eq!(clear(&bids, &asks), Some((100, 10)));
+    }
+
+    #[test]
+    fn mixed_pressure_picks_the_lowest_tied_price() {
+        // 10 trades with 2 left over everywhere from 100 to 105: bids at 100
+        // and 101, asks at 103 and 105.
+        let bids = [(105, 10), (101, 2)];
+        let asks = [(100, 10), (103, 2)];
+        assert_eq!(clear(&bids, &asks), Some((100, 10)));
+    }
+}
@@ -0,0 +1,226 @@
+use crate::bindings::host;
+use crate::config::ClobConfig;
+use crate::market::{MarketKey, MarketPhase};
+use crate::solidity::{IPriceOracle, OrderMatched, OrderModified, OrderPlaced, ICLOB};
+use crate::trigger::{settled_match, MatchResult, Order};
+use alloy_provider::{network::Ethereum, Provider, RootProvider};
+use alloy_rpc_types::{BlockId, Filter, TransactionRequest};
+use alloy_sol_types::{SolCall, SolEvent};
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use wavs_wasi_utils::evm::{
+    alloy_primitives::{Address, U256},
+    new_evm_provider,
+};
+
+/// Read-only access to the CLOB contract's order state, and to the price
+/// oracle.
+pub struct ClobClient {
+    provider: RootProvider<Ethereum>,
+    address: Address,
+}
+
+impl ClobClient {
+    /// Connects to the contract at `clob_address` on `chain_name`.
+    pub fn from_config(config: &ClobConfig) -> Result<Self> {
+        let address =
+            config.clob_address.ok_or_else(|| anyhow!("CLOB contract address not configured"))?;
+        let chain_name =
+            config.chain_name.as_deref().ok_or_else(|| anyhow!("chain_name not configured"))?;
+        let chain_config = host::get_evm_chain_config(chain_name)
+            .ok_or_else(|| anyhow!("Could not get evm chain config for {chain_name}"))?;
+        let endpoint = chain_config
+            .http_endpoint
+            .ok_or_else(|| anyhow!("Could not get http endpoint for {chain_name}"))?;
+
+        println!("📋 CLOB Contract: {} on {}", address, chain_name);
+        Ok(Self { provider: new_evm_provider::<Ethereum>(endpoint), address })
+    }
+
+    /// Calls `to`, at `block` if given, otherwise at the latest block.
+    async fn call_at<C: SolCall>(
+        &self,
+        to: Address,
+        call: C,
+        block: Option<u64>,
+    ) -> Result<C::Return> {
+        let tx = TransactionRequest::default().to(to).input(call.abi_encode().into());
+        let mut request = self.provider.call(tx);
+        if let Some(block) = block {
+            request = request.block(BlockId::number(block));
+        }
+        let output =
+            request.await.map_err(|e| anyhow!("Failed to call {}: {}", C::SIGNATURE, e))?;
+        C::abi_decode_returns(&output)
+            .map_err(|e| anyhow!("Failed to decode {} result: {}", C::SIGNATURE, e))
+    }
+
+    /// Loads an order as of `block`, if given, otherwise as of the latest
+    /// block. `None` if no order with this id exists.
+    pub async fn get_order_at(&self, order_id: u64, block: Option<u64>) -> Result<Option<Order>> {
+        let call = ICLOB::getOrderCall { _orderId: U256::from(order_id) };
+        let order = self.call_at(self.address, call, block).await?;
+        if order.id.is_zero() {
+            return Ok(None);
+        }
+        Ok(Some(order.try_into()?))
+    }
+
+    /// What `trader` holds of `token` in `escrowBalances` as of `block`.
+    pub async fn escrow_balance(
+        &self,
+        trader: Address,
+        token: Address,
+        block: u64,
+    ) -> Result<U256> {
+        self.call_at(
+            self.address,
+            ICLOB::getEscrowBalanceCall { _user: trader, _token: token },
+            Some(block),
+        )
+        .await
+    }
+
+    /// The oracle's price for `market` as of `block`, so every operator
+    /// reads the same value for the same trigger.
+    pub async fn oracle_price(
+        &self,
+        oracle: Address,
+        market: MarketKey,
+        block: u64,
+    ) -> Result<U256> {
+        self.call_at(
+            oracle,
+            IPriceOracle::getPriceCall {
+                baseToken: market.base_token,
+                quoteToken: market.quote_token,
+            },
+            Some(block),
+        )
+        .await
+    }
+
+    /// Every market an order was placed in up to `block`.
+    pub async fn markets(&self, block: u64) -> Result<Vec<MarketKey>> {
+        let markets = self.call_at(self.address, ICLOB::getMarketsCall {}, Some(block)).await?;
+        Ok(markets.into_iter().map(|m| MarketKey::new(m.baseToken, m.quoteToken)).collect())
+    }
+
+    /// The phase the contract stores for `market` as of `block`, the last
+    /// one the admin, a circuit breaker halt or a finished call auction
+    /// moved it to. `None` if it was never moved.
+    pub async fn market_phase(&self, market: MarketKey, block: u64) -> Result<Option<MarketPhase>> {
+        let call = ICLOB::getMarketPhaseCall {
+            _baseToken: market.base_token,
+            _quoteToken: market.quote_token,
+        };
+        let stored = self.call_at(self.address, call, Some(block)).await?;
+        stored.isSet.then(|| MarketPhase::from_sol(stored.phase)).transpose()
+    }
+
+    /// Every trade the contract executed up to `block`, oldest first, as the
+    /// market, the block time it traded at and its price, read from the
+    /// `OrderMatched` logs.
+    pub async fn trades(&self, block: u64) -> Result<Vec<(MarketKey, u64, U256)>> {
+        let events = self.order_matched(0, block).await?;
+        Ok(events
+            .iter()
+            .map(|event| {
+                let market = MarketKey::new(event.baseToken, event.quoteToken);
+                (market, event.timestamp.to::<u64>(), event.price)
+            })
+            .collect())
+    }
+
+    /// Fills that settled on-chain from block `from` through `to`, in the
+    /// order they landed.
+    pub async fn settled_matches(&self, from: u64, to: u64) -> Result<Vec<MatchResult>> {
+        let events = self.order_matched(from, to).await?;
+        Ok(events.iter().map(settled_match).collect())
+    }
+
+    async fn order_matched(&self, from: u64, to: u64) -> Result<Vec<OrderMatched>> {
+        let filter = Filter::new()
+            .address(self.address)
+            .event_signature(OrderMatched::SIGNATURE_HASH)
+            .from_block(from)
+            .to_block(to);
+        let logs = self
+            .provider
+            .get_logs(&filter)
+            .await
+            .map_err(|e| anyhow!("Failed to read OrderMatched logs: {}", e))?;
+        logs.iter().map(|log| Ok(OrderMatched::decode_log_data(&log.inner.data)?)).collect()
+    }
+
+    /// Orders whose amendments up to `block` cost them their queue
+    /// priority, each with the newest order placed before the last such
+    /// amendment, in the order those amendments happened. An amendment
+    /// keeps the order's place only at the same price and no larger amount,
+    /// as in [`crate::order_book::OrderBook::modify_order`].
+    pub async fn requeued_orders(&self, block: u64) -> Result<Vec<(u64, u64)>> {
+        let filter = Filter::new()
+            .address(self.address)
+            .event_signature(vec![OrderPlaced::SIGNATURE_HASH, OrderModified::SIGNATURE_HASH])
+            .from_block(0)
+            .to_block(block);
+        let logs = self
+            .provider
+            .get_logs(&filter)
+            .await
+            .map_err(|e| anyhow!("Failed to read OrderPlaced and OrderModified logs: {}", e))?;
+
+        let mut newest_id = 0;
+        let mut amounts = BTreeMap::new();
+        let mut requeued: Vec<(u64, u64)> = Vec::new();
+        for log in &logs {
+            if log.topic0() == Some(&OrderPlaced::SIGNATURE_HASH) {
+                let event = OrderPlaced::decode_log_data(&log.inner.data)?;
+                newest_id = event.orderId.to::<u64>();
+                amounts.insert(newest_id, event.amount);
+                continue;
+            }
+            let event = OrderModified::decode_log_data(&log.inner.data)?;
+            let order_id = event.orderId.to::<u64>();
+            let old_amount = amounts.insert(order_id, event.newAmount).unwrap_or_default();
+            if event.newPrice != event.oldPrice || event.newAmount > old_amount {
+                requeued.retain(|(id, _)| *id != order_id);
+                requeued.push((order_id, newest_id));
+            }
+        }
+        Ok(requeued)
+    }
+
+    /// Ids of every order placed in a market up to `block`, in placement
+    /// order.
+    pub async fn order_book_orders(&self, market: MarketKey, block: u64) -> Result<Vec<u64>> {
+        let call = ICLOB::getOrderBookOrdersCall {
+            _baseToken: market.base_token,
+            _quoteToken: market.quote_token,
+        };
+        let ids = self.call_at(self.address, call, Some(block)).await?;
+        Ok(ids.into_iter().map(|id| id.to::<u64>()).collect())
+    }
+
+    /// Every order the contract would still match as of `block`, including
+    /// stop orders waiting to trigger, grouped by market and in the
+    /// contract's placement order within each market, with `filled_amount`
+    /// as stored on-chain. Reading at one block makes the result the same
+    /// for every operator, whenever it runs.
+    pub async fn open_orders(&self, block: u64) -> Result<Vec<Order>> {
+        let mut orders = Vec::new();
+        for market in self.markets(block).await? {
+            let order_ids = self.order_book_orders(market, block).await?;
+            println!("🔎 Scanning {} orders in market {}", order_ids.len(), market);
+            for order_id in order_ids {
+                if let Some(order) = self.get_order_at(order_id, Some(block)).await? {
+                    if order.status.is_live() && order.filled_amount < order.amount {
+                        orders.push(order);
+                    }
+                }
+            }
+        }
+
+        Ok(orders)
+    }
+}
@@ -0,0 +1,480 @@
+use crate::allocation::Allocation;
+use crate::bindings::host;
+use crate::fees::FeeSchedule;
+use crate::market::{MarketKey, MarketPhase};
+use crate::spec::MarketSpec;
+use crate::trade_price::TradePrice;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use wavs_wasi_utils::evm::alloy_primitives::{Address, U256};
+
+/// Used when `max_matches_per_batch` is not set in the component config.
+pub const DEFAULT_MAX_MATCHES_PER_BATCH: usize = 10;
+
+/// Used when `settlement_timeout_blocks` is not set in the component config.
+pub const DEFAULT_SETTLEMENT_TIMEOUT_BLOCKS: u64 = 20;
+
+/// Used when `max_reproposals` is not set in the component config.
+pub const DEFAULT_MAX_REPROPOSALS: u32 = 3;
+
+/// Used when `auction_interval_secs` is not set in the component config.
+pub const DEFAULT_AUCTION_INTERVAL_SECS: u64 = 60;
+
+/// Used when `twap_window_secs` is not set in the component config.
+pub const DEFAULT_TWAP_WINDOW_SECS: u64 = 300;
+
+/// Used when `price_decimals` is not set in the component config; CLOB.sol
+/// prices are fixed-point with 18 decimals.
+pub const DEFAULT_PRICE_DECIMALS: u8 = 18;
+
+/// Used when `circuit_breaker_window_secs` is not set in the component
+/// config.
+pub const DEFAULT_CIRCUIT_BREAKER_WINDOW_SECS: u64 = 300;
+
+/// What to do with a proposed fill that has not settled on-chain in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaleFillPolicy {
+    /// Give the amount back to both orders in the book.
+    Rollback,
+    /// Emit the fill again, rolling back once `max_reproposals` is reached.
+    Repropose,
+}
+
+impl FromStr for StaleFillPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rollback" => Ok(Self::Rollback),
+            "repropose" => Ok(Self::Repropose),
+            _ => Err(format!("unknown stale fill policy {s:?}, expected rollback or repropose")),
+        }
+    }
+}
+
+/// What the matching engine does when the best bid and ask it is about to
+/// match belong to the same trader. The order that arrived first is the
+/// resting one, the other the incoming one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SelfTradePrevention {
+    /// Cancel the resting order and keep matching the incoming one.
+    CancelResting,
+    /// Cancel the incoming order.
+    CancelIncoming,
+    /// Reduce both orders by the amount that would have traded.
+    DecrementBoth,
+    /// Leave both orders alone and match against the next order from a
+    /// different trader.
+    Skip,
+}
+
+impl FromStr for SelfTradePrevention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cancel_resting" => Ok(Self::CancelResting),
+            "cancel_incoming" => Ok(Self::CancelIncoming),
+            "decrement_both" => Ok(Self::DecrementBoth),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!(
+                "unknown self trade prevention {s:?}, expected cancel_resting, cancel_incoming, decrement_both or skip"
+            )),
+        }
+    }
+}
+
+/// The rules a matching pass follows for one market.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchPolicy {
+    pub stp: SelfTradePrevention,
+    pub allocation: Allocation,
+    pub trade_price: TradePrice,
+    /// Decimals of prices, to size quote-denominated orders.
+    pub price_decimals: u8,
+}
+
+/// How a market turns crossing orders into fills.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchingMode {
+    /// Match on every trigger, at the price the market's [`TradePrice`]
+    /// picks.
+    #[default]
+    Continuous,
+    /// Collect orders for `auction_interval_secs`, then fill everything that
+    /// crosses at a single clearing price.
+    Auction,
+}
+
+impl FromStr for MatchingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "continuous" => Ok(Self::Continuous),
+            "auction" => Ok(Self::Auction),
+            _ => Err(format!("unknown matching mode {s:?}, expected continuous or auction")),
+        }
+    }
+}
+
+/// The price a market's price band is centred on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PriceReference {
+    /// The market's most recent fill.
+    #[default]
+    LastTrade,
+    /// Time-weighted average of fills over `twap_window_secs`.
+    Twap,
+    /// `getPrice(baseToken, quoteToken)` on `price_oracle_address`, read at
+    /// the trigger's block.
+    Oracle,
+}
+
+impl FromStr for PriceReference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last_trade" => Ok(Self::LastTrade),
+            "twap" => Ok(Self::Twap),
+            "oracle" => Ok(Self::Oracle),
+            _ => Err(format!("unknown price reference {s:?}, expected last_trade, twap or oracle")),
+        }
+    }
+}
+
+/// Per-market settings that replace a component-wide default, written as
+/// comma separated `<base token>/<quote token>=<value>` entries.
+#[derive(Debug, Clone)]
+pub struct MarketOverrides<T>(BTreeMap<MarketKey, T>);
+
+impl<T> Default for MarketOverrides<T> {
+    fn default() -> Self {
+        Self(BTreeMap::new())
+    }
+}
+
+impl<T: Copy> MarketOverrides<T> {
+    /// The value set for `market`, if any.
+    pub fn get(&self, market: MarketKey) -> Option<T> {
+        self.0.get(&market).copied()
+    }
+
+    /// The value set for `market`, or `default` if it has none.
+    pub fn get_or(&self, market: MarketKey, default: T) -> T {
+        self.get(market).unwrap_or(default)
+    }
+}
+
+impl<T> FromStr for MarketOverrides<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut markets = BTreeMap::new();
+        for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (market, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected <base>/<quote>=<value>, got {entry:?}"))?;
+            let (base, quote) = market
+                .split_once('/')
+                .ok_or_else(|| format!("expected <base>/<quote>, got {market:?}"))?;
+            let parse_token = |token: &str| {
+                Address::from_str(token.trim()).map_err(|e| format!("invalid token {token:?}: {e}"))
+            };
+            let key = MarketKey::new(parse_token(base)?, parse_token(quote)?);
+            markets.insert(key, value.trim().parse().map_err(|e: T::Err| e.to_string())?);
+        }
+        Ok(Self(markets))
+    }
+}
+
+/// Settings read from the workflow's component `config_values`.
+#[derive(Debug, Clone)]
+pub struct ClobConfig {
+    /// The CLOB contract, used to read order state from chain.
+    pub clob_address: Option<Address>,
+    pub chain_name: Option<String>,
+    /// Rebuild the books from the contract when no usable order book file
+    /// exists, instead of starting empty.
+    pub bootstrap_from_chain: bool,
+    /// Upper bound on fills emitted per trigger. Crossing liquidity beyond
+    /// this stays in the book and is matched on the next run.
+    pub max_matches_per_batch: usize,
+    /// Blocks a proposed fill may stay unsettled before `stale_fill_policy`
+    /// applies.
+    pub settlement_timeout_blocks: u64,
+    pub stale_fill_policy: StaleFillPolicy,
+    pub max_reproposals: u32,
+    /// Put the book state root in the payload, so operators whose books
+    /// diverge sign different payloads instead of agreeing on matches alone.
+    pub include_state_root: bool,
+    /// Price increment limit prices must be a multiple of, and a sliding
+    /// post-only order is moved by, in the same units as order prices.
+    /// Markets may override it, like the rest of the market spec, in
+    /// `market_tick_sizes`.
+    pub tick_size: U256,
+    pub market_tick_sizes: MarketOverrides<U256>,
+    /// Order amounts must be a multiple of it.
+    pub lot_size: U256,
+    pub market_lot_sizes: MarketOverrides<U256>,
+    pub min_order_amount: U256,
+    pub market_min_order_amounts: MarketOverrides<U256>,
+    pub max_order_amount: Option<U256>,
+    pub market_max_order_amounts: MarketOverrides<U256>,
+    /// Smallest value of a limit order in quote token units.
+    pub min_notional: U256,
+    pub market_min_notionals: MarketOverrides<U256>,
+    /// Decimals of the fixed-point order prices; only CLOB.sol's 18 is
+    /// accepted.
+    pub price_decimals: u8,
+    /// Read every trader's escrow from the contract before matching and
+    /// only propose fills it backs.
+    pub check_escrow: bool,
+    /// Replay the payload against the contract's orders and escrow before
+    /// submitting it, leaving out matches that would revert it.
+    pub simulate_settlement: bool,
+    /// Maker and taker fees charged on every fill, from `maker_fee_bps` and
+    /// `taker_fee_bps`.
+    pub fees: FeeSchedule,
+    pub self_trade_prevention: SelfTradePrevention,
+    /// How fills are shared within a price level, unless the market has an
+    /// entry in `market_allocations`.
+    pub allocation: Allocation,
+    pub market_allocations: MarketOverrides<Allocation>,
+    /// Price crossing orders trade at in continuous matching, unless the
+    /// market has an entry in `market_trade_prices`.
+    pub trade_price: TradePrice,
+    pub market_trade_prices: MarketOverrides<TradePrice>,
+    /// Continuous matching or periodic auctions, unless the market has an
+    /// entry in `market_matching_modes`.
+    pub matching_mode: MatchingMode,
+    pub market_matching_modes: MarketOverrides<MatchingMode>,
+    /// Length of an auction period. Auctions close on multiples of it in
+    /// block time, so every operator cuts off the same orders.
+    pub auction_interval_secs: u64,
+    /// Phase of markets the contract has not moved to another phase yet,
+    /// unless the market has an entry in `market_phases`.
+    pub market_phase: MarketPhase,
+    pub market_phases: MarketOverrides<MarketPhase>,
+    /// Half-width of the price band in basis points of the reference price.
+    /// Matches outside it are refused. Zero disables the band.
+    pub price_band_bps: u64,
+    pub price_band_reference: PriceReference,
+    pub twap_window_secs: u64,
+    pub price_oracle_address: Option<Address>,
+    /// Largest move in basis points allowed within
+    /// `circuit_breaker_window_secs` before the market is halted. Zero
+    /// disables the breaker.
+    pub circuit_breaker_bps: u64,
+    pub circuit_breaker_window_secs: u64,
+}
+
+impl ClobConfig {
+    pub fn load() -> Result<Self> {
+        let max_matches_per_batch =
+            parse_var("max_matches_per_batch")?.unwrap_or(DEFAULT_MAX_MATCHES_PER_BATCH);
+        if max_matches_per_batch == 0 {
+            return Err(anyhow!("max_matches_per_batch must be greater than 0"));
+        }
+
+        let settlement_timeout_blocks =
+            parse_var("settlement_timeout_blocks")?.unwrap_or(DEFAULT_SETTLEMENT_TIMEOUT_BLOCKS);
+        let stale_fill_policy =
+            parse_var("stale_fill_policy")?.unwrap_or(StaleFillPolicy::Rollback);
+        let max_reproposals = parse_var("max_reproposals")?.unwrap_or(DEFAULT_MAX_REPROPOSALS);
+
+        let auction_interval_secs =
+            parse_var("auction_interval_secs")?.unwrap_or(DEFAULT_AUCTION_INTERVAL_SECS);
+        if auction_interval_secs == 0 {
+            return Err(anyhow!("auction_interval_secs must be greater than 0"));
+        }
+
+        let price_band_reference =
+            parse_var("price_band_reference")?.unwrap_or(PriceReference::LastTrade);
+        let price_oracle_address = parse_var("price_oracle_address")?;
+        if price_band_reference == PriceReference::Oracle && price_oracle_address.is_none() {
+            return Err(anyhow!("price_oracle_address is required for the oracle price reference"));
+        }
+
+        let tick_size = parse_var("tick_size")?.unwrap_or(U256::from(1));
+        let market_tick_sizes: MarketOverrides<U256> =
+            parse_var("market_tick_sizes")?.unwrap_or_default();
+        if tick_size.is_zero() || market_tick_sizes.0.values().any(U256::is_zero) {
+            return Err(anyhow!("tick_size must be greater than 0"));
+        }
+
+        let lot_size = parse_var("lot_size")?.unwrap_or(U256::from(1));
+        let market_lot_sizes: MarketOverrides<U256> =
+            parse_var("market_lot_sizes")?.unwrap_or_default();
+        if lot_size.is_zero() || market_lot_sizes.0.values().any(U256::is_zero) {
+            return Err(anyhow!("lot_size must be greater than 0"));
+        }
+
+        let fees = FeeSchedule {
+            maker_bps: parse_var("maker_fee_bps")?.unwrap_or(0),
+            taker_bps: parse_var("taker_fee_bps")?.unwrap_or(0),
+        };
+        if fees.maker_bps > 10_000 || fees.taker_bps > 10_000 {
+            return Err(anyhow!("maker_fee_bps and taker_fee_bps must be at most 10000"));
+        }
+
+        let price_decimals = parse_var("price_decimals")?.unwrap_or(DEFAULT_PRICE_DECIMALS);
+        // CLOB.sol divides by 1e18 wherever it values an amount, so any
+        // other scale would size and check fills it does not enforce
+        if price_decimals != DEFAULT_PRICE_DECIMALS {
+            return Err(anyhow!("price_decimals must be {DEFAULT_PRICE_DECIMALS}, as in CLOB.sol"));
+        }
+
+        Ok(Self {
+            clob_address: parse_var("clob_address")?,
+            chain_name: host::config_var("chain_name"),
+            bootstrap_from_chain: parse_var("bootstrap_from_chain")?.unwrap_or(true),
+            max_matches_per_batch,
+            settlement_timeout_blocks,
+            stale_fill_policy,
+            max_reproposals,
+            include_state_root: parse_var("include_state_root")?.unwrap_or(false),
+            tick_size,
+            market_tick_sizes,
+            lot_size,
+            market_lot_sizes,
+            min_order_amount: parse_var("min_order_amount")?.unwrap_or_default(),
+            market_min_order_amounts: parse_var("market_min_order_amounts")?.unwrap_or_default(),
+            max_order_amount: parse_var("max_order_amount")?,
+            market_max_order_amounts: parse_var("market_max_order_amounts")?.unwrap_or_default(),
+            min_notional: parse_var("min_notional")?.unwrap_or_default(),
+            market_min_notionals: parse_var("market_min_notionals")?.unwrap_or_default(),
+            price_decimals,
+            fees,
+            check_escrow: parse_var("check_escrow")?.unwrap_or(false),
+            simulate_settlement: parse_var("simulate_settlement")?.unwrap_or(false),
+            self_trade_prevention: parse_var("self_trade_prevention")?
+                .unwrap_or(SelfTradePrevention::CancelResting),
+            allocation: parse_var("allocation")?.unwrap_or_default(),
+            market_allocations: parse_var("market_allocations")?.unwrap_or_default(),
+            trade_price: parse_var("trade_price")?.unwrap_or_default(),
+            market_trade_prices: parse_var("market_trade_prices")?.unwrap_or_default(),
+            matching_mode: parse_var("matching_mode")?.unwrap_or_default(),
+            market_matching_modes: parse_var("market_matching_modes")?.unwrap_or_default(),
+            auction_interval_secs,
+            market_phase: parse_var("market_phase")?.unwrap_or_default(),
+            market_phases: parse_var("market_phases")?.unwrap_or_default(),
+            price_band_bps: parse_var("price_band_bps")?.unwrap_or(0),
+            price_band_reference,
+            twap_window_secs: parse_var("twap_window_secs")?.unwrap_or(DEFAULT_TWAP_WINDOW_SECS),
+            price_oracle_address,
+            circuit_breaker_bps: parse_var("circuit_breaker_bps")?.unwrap_or(0),
+            circuit_breaker_window_secs: parse_var("circuit_breaker_window_secs")?
+                .unwrap_or(DEFAULT_CIRCUIT_BREAKER_WINDOW_SECS),
+        })
+    }
+
+    /// The allocation strategy configured for `market`.
+    pub fn allocation_for(&self, market: MarketKey) -> Allocation {
+        self.market_allocations.get_or(market, self.allocation)
+    }
+
+    /// How continuous matching pairs and prices orders in `market`.
+    pub fn match_policy(&self, market: MarketKey) -> MatchPolicy {
+        MatchPolicy {
+            stp: self.self_trade_prevention,
+            allocation: self.allocation_for(market),
+            trade_price: self.market_trade_prices.get_or(market, self.trade_price),
+            price_decimals: self.price_decimals,
+        }
+    }
+
+    /// The matching mode configured for `market`.
+    pub fn matching_mode_for(&self, market: MarketKey) -> MatchingMode {
+        self.market_matching_modes.get_or(market, self.matching_mode)
+    }
+
+    /// The trading rules configured for `market`.
+    pub fn spec_for(&self, market: MarketKey) -> MarketSpec {
+        MarketSpec {
+            tick_size: self.market_tick_sizes.get_or(market, self.tick_size),
+            lot_size: self.market_lot_sizes.get_or(market, self.lot_size),
+            min_amount: self.market_min_order_amounts.get_or(market, self.min_order_amount),
+            max_amount: self.market_max_order_amounts.get(market).or(self.max_order_amount),
+            min_notional: self.market_min_notionals.get_or(market, self.min_notional),
+            price_decimals: self.price_decimals,
+        }
+    }
+
+    /// How long a market's price history keeps prints for: the longer of
+    /// the TWAP and circuit breaker windows.
+    pub fn price_history_secs(&self) -> u64 {
+        self.twap_window_secs.max(self.circuit_breaker_window_secs)
+    }
+
+    /// The phase configured for `market`.
+    pub fn phase_for(&self, market: MarketKey) -> MarketPhase {
+        self.market_phases.get_or(market, self.market_phase)
+    }
+}
+
+fn parse_var<T>(key: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    host::config_var(key)
+        .map(|value| value.parse().map_err(|e| anyhow!("Failed to parse {key}: {e}")))
+        .transpose()
+}
+
+#[cfg(test)]
+pub mod test_config {
+    use super::*;
+
+    /// The configuration `load` reads when no config values are set.
+    pub fn config() -> ClobConfig {
+        ClobConfig {
+            clob_address: None,
+            chain_name: None,
+            bootstrap_from_chain: true,
+            max_matches_per_batch: DEFAULT_MAX_MATCHES_PER_BATCH,
+            settlement_timeout_blocks: DEFAULT_SETTLEMENT_TIMEOUT_BLOCKS,
+            stale_fill_policy: StaleFillPolicy::Rollback,
+            max_reproposals: DEFAULT_MAX_REPROPOSALS,
+            include_state_root: false,
+            tick_size: U256::from(1),
+            market_tick_sizes: MarketOverrides::default(),
+            lot_size: U256::from(1),
+            market_lot_sizes: MarketOverrides::default(),
+            min_order_amount: U256::ZERO,
+            market_min_order_amounts: MarketOverrides::default(),
+            max_order_amount: None,
+            market_max_order_amounts: MarketOverrides::default(),
+            min_notional: U256::ZERO,
+            market_min_notionals: MarketOverrides::default(),
+            price_decimals: DEFAULT_PRICE_DECIMALS,
+            check_escrow: false,
+            simulate_settlement: false,
+            fees: FeeSchedule { maker_bps: 0, taker_bps: 0 },
+            self_trade_prevention: SelfTradePrevention::CancelResting,
+            allocation: Allocation::default(),
+            market_allocations: MarketOverrides::default(),
+            trade_price: TradePrice::default(),
+            market_trade_prices: MarketOverrides::default(),
+            matching_mode: MatchingMode::default(),
+            market_matching_modes: MarketOverrides::default(),
+            auction_interval_secs: DEFAULT_AUCTION_INTERVAL_SECS,
+            market_phase: MarketPhase::default(),
+            market_phases: MarketOverrides::default(),
+            price_band_bps: 0,
+            price_band_reference: PriceReference::LastTrade,
+            twap_window_secs: DEFAULT_TWAP_WINDOW_SECS,
+            price_oracle_address: None,
+            circuit_breaker_bps: 0,
+            circuit_breaker_window_secs: DEFAULT_CIRCUIT_BREAKER_WINDOW_SECS,
+        }
+    }
+}
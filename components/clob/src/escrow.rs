@@ -0,0 +1,155 @@
+use crate::trigger::{price_scale, Order};
+use std::collections::BTreeMap;
+use wavs_wasi_utils::evm::alloy_primitives::{Address, U256};
+
+/// Escrow each trader has left to back fills, per `(trader, token)`: the
+/// contract's `escrowBalances` at the trigger block, less what fills still
+/// awaiting settlement and fills matched in this run take out of it.
+#[derive(Debug, Clone, Default)]
+pub struct EscrowLedger {
+    /// `None` when escrow is not checked and every fill is backed.
+    balances: Option<BTreeMap<(Address, Address), U256>>,
+    price_decimals: u8,
+}
+
+impl EscrowLedger {
+    /// A ledger that lets every fill through.
+    pub fn unchecked() -> Self {
+        Self::default()
+    }
+
+    pub fn new(price_decimals: u8) -> Self {
+        Self { balances: Some(BTreeMap::new()), price_decimals }
+    }
+
+    /// Which of `accounts` still have to be loaded.
+    pub fn missing(
+        &self,
+        accounts: impl IntoIterator<Item = (Address, Address)>,
+    ) -> Vec<(Address, Address)> {
+        match &self.balances {
+            Some(balances) => {
+                accounts.into_iter().filter(|account| !balances.contains_key(account)).collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, trader: Address, token: Address, available: U256) {
+        if let Some(balances) = &mut self.balances {
+            balances.insert((trader, token), available);
+        }
+    }
+
+    /// Reserves what a fill of up to `amount` base units between `buy` and
+    /// `sell` at `price` takes from both traders. Returns the amount that is
+    /// backed, and the orders whose trader ran out of escrow short of
+    /// `amount`. The buyer's quote tokens are reserved at the trade price,
+    /// which is what settlement debits. Accounts that were never loaded are
+    /// not limited. A fill cut down so far that it would cost a
+    /// quote-denominated buy nothing is not backed at all.
+    pub fn reserve(
+        &mut self,
+        buy: &Order,
+        sell: &Order,
+        amount: U256,
+        price: U256,
+    ) -> (U256, Vec<u64>) {
+        let Some(balances) = &mut self.balances else {
+            return (amount, Vec::new());
+        };
+        let scale = price_scale(self.price_decimals);
+        let buyer = (buy.trader, buy.quote_token);
+        let seller = (sell.trader, sell.base_token);
+
+        let buyer_cap = balances
+            .get(&buyer)
+            .and_then(|quote| quote.saturating_mul(scale).checked_div(price))
+            .unwrap_or(amount);
+        let seller_cap = balances.get(&seller).copied().unwrap_or(amount);
+        let mut backed = amount.min(buyer_cap).min(seller_cap);
+        if buy.is_free_fill(backed, price, self.price_decimals) {
+            backed = U256::ZERO;
+        }
+
+        if let Some(quote) = balances.get_mut(&buyer) {
+            *quote -= backed * price / scale;
+        }
+        if let Some(base) = balances.get_mut(&seller) {
+            *base -= backed;
+        }
+        let unbacked = [(buy.id, buyer_cap), (sell.id, seller_cap)]
+            .into_iter()
+            .filter(|(_, cap)| *cap < amount)
+            .map(|(id, _)| id)
+            .collect();
+        (backed, unbacked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trigger::test_orders::{order, BASE, QUOTE};
+    use crate::trigger::{Denomination, OrderType};
+
+    const BUYER: Address = Address::repeat_byte(1);
+    const SELLER: Address = Address::repeat_byte(2);
+
+    /// Prices carry two decimals, so 200 is two quote tokens per base token.
+    const PRICE: u64 = 200;
+
+    fn ledger(buyer_quote: u64, seller_base: u64) -> EscrowLedger {
+        let mut ledger = EscrowLedger::new(2);
+        ledger.insert(BUYER, QUOTE, U256::from(buyer_quote));
+        ledger.insert(SELLER, BASE, U256::from(seller_base));
+        ledger
+    }
+
+    fn reserve(ledger: &mut EscrowLedger, amount: u64) -> (U256, Vec<u64>) {
+        let buy = order(1, BUYER, OrderType::Buy, PRICE, 100);
+        let sell = order(2, SELLER, OrderType::Sell, PRICE, 100);
+        ledger.reserve(&buy, &sell, U256::from(amount), U256::from(PRICE))
+    }
+
+    #[test]
+    fn short_buyer_backs_part_of_the_fill() {
+        // 50 quote tokens pay for 25 base tokens at the trade price.
+        let mut ledger = ledger(50, 40);
+        assert_eq!(reserve(&mut ledger, 30), (U256::from(25), vec![1]));
+
+        // The buyer is spent and the seller has 15 left.
+        assert_eq!(reserve(&mut ledger, 10), (U256::ZERO, vec![1]));
+        ledger.insert(BUYER, QUOTE, U256::from(1000));
+        assert_eq!(reserve(&mut ledger, 20), (U256::from(15), vec![2]));
+    }
+
+    #[test]
+    fn both_sides_short_are_both_reported() {
+        let mut ledger = ledger(40, 10);
+        assert_eq!(reserve(&mut ledger, 30), (U256::from(10), vec![1, 2]));
+    }
+
+    #[test]
+    fn unloaded_accounts_and_unchecked_ledgers_back_everything() {
+        let mut ledger = EscrowLedger::new(2);
+        assert_eq!(ledger.missing([(BUYER, QUOTE)]), vec![(BUYER, QUOTE)]);
+        assert_eq!(reserve(&mut ledger, 30), (U256::from(30), Vec::new()));
+
+        let mut ledger = EscrowLedger::unchecked();
+        ledger.insert(BUYER, QUOTE, U256::ZERO);
+        assert!(ledger.missing([(BUYER, QUOTE)]).is_empty());
+        assert_eq!(reserve(&mut ledger, 30), (U256::from(30), Vec::new()));
+    }
+
+    #[test]
+    fn free_quote_buy_fills_are_not_backed() {
+        // One base unit at 0.50 rounds down to no quote tokens at all.
+        let buy =
+            Order { denomination: Denomination::Quote, ..order(1, BUYER, OrderType::Buy, 50, 100) };
+        let sell = order(2, SELLER, OrderType::Sell, 50, 100);
+        let mut ledger = ledger(1000, 1000);
+        let (backed, _) = ledger.reserve(&buy, &sell, U256::from(1), U256::from(50));
+        assert_eq!(backed, U256::ZERO);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::trigger::{price_scale, Fill, OrderType};
+use wavs_wasi_utils::evm::alloy_primitives::U256;
+
+const BPS: u64 = 10_000;
+
+/// Whether an order provided the liquidity of a fill or took it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+/// Trading fees in basis points of what each side receives: the buyer pays
+/// in base tokens, the seller in quote tokens.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeeSchedule {
+    pub maker_bps: u64,
+    pub taker_bps: u64,
+}
+
+impl FeeSchedule {
+    /// The rate paid for `liquidity`. Every trader is on the same tier.
+    pub fn rate(&self, liquidity: Liquidity) -> u64 {
+        match liquidity {
+            Liquidity::Maker => self.maker_bps,
+            Liquidity::Taker => self.taker_bps,
+        }
+    }
+
+    /// Sets both fees of `fill`. The quote amount is worked out as CLOB.sol
+    /// does, with prices carrying `price_decimals` decimals.
+    pub fn charge(&self, fill: &mut Fill, price_decimals: u8) {
+        let result = &mut fill.result;
+        let liquidity =
+            |side| if side == result.taker_side { Liquidity::Taker } else { Liquidity::Maker };
+        let buyer_bps = self.rate(liquidity(OrderType::Buy));
+        let seller_bps = self.rate(liquidity(OrderType::Sell));
+
+        let quote_amount = result.match_amount * result.match_price / price_scale(price_decimals);
+        result.buyer_fee = fee(result.match_amount, buyer_bps);
+        result.seller_fee = fee(quote_amount, seller_bps);
+    }
+}
+
+/// `amount * bps / 10_000`, rounded down.
+fn fee(amount: U256, bps: u64) -> U256 {
+    amount * U256::from(bps) / U256::from(BPS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trigger::test_orders::order;
+    use crate::trigger::MatchResult;
+    use wavs_wasi_utils::evm::alloy_primitives::Address;
+
+    const BUYER: Address = Address::repeat_byte(1);
+    const SELLER: Address = Address::repeat_byte(2);
+
+    /// 10 bps for makers, 30 bps for takers.
+    const FEES: FeeSchedule = FeeSchedule { maker_bps: 10, taker_bps: 30 };
+
+    /// Fees of `amount` base tokens at `price`, with two price decimals.
+    fn charge(taker_side: OrderType, price: u64, amount: u64) -> (U256, U256) {
+        let mut fill = Fill {
+            result: MatchResult {
+                buy_order_id: U256::from(1),
+                sell_order_id: U256::from(2),
+                match_amount: U256::from(amount),
+                match_price: U256::from(price),
+                taker_side,
+                buyer_fee: U256::ZERO,
+                seller_fee: U256::ZERO,
+            },
+            buy_order: order(1, BUYER, OrderType::Buy, price, amount),
+            sell_order: order(2, SELLER, OrderType::Sell, price, amount),
+        };
+        FEES.charge(&mut fill, 2);
+        (fill.result.buyer_fee, fill.result.seller_fee)
+    }
+
+    #[test]
+    fn the_taker_side_pays_the_taker_rate() {
+        // 100_000 base tokens at 2.00 are 200_000 quote tokens.
+        assert_eq!(charge(OrderType::Buy, 200, 100_000), (U256::from(300), U256::from(200)));
+        assert_eq!(charge(OrderType::Sell, 200, 100_000), (U256::from(100), U256::from(600)));
+    }
+
+    #[test]
+    fn fees_round_down() {
+        // 999 * 30 / 10_000 is 2.997 and 1998 * 10 / 10_000 is 1.998.
+        assert_eq!(charge(OrderType::Buy, 200, 999), (U256::from(2), U256::from(1)));
+        assert_eq!(charge(OrderType::Buy, 200, 300), (U256::ZERO, U256::ZERO));
+    }
+}
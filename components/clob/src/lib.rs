@@ -1,170 +1,72 @@
+#[allow(warnings)]
 #[rustfmt::skip]
 pub mod bindings;
+mod allocation;
+mod auction;
+mod chain;
+mod config;
+mod escrow;
+mod fees;
+mod market;
+mod order_book;
+mod price_guard;
+mod reconcile;
+mod settlement;
 pub mod solidity;
+mod spec;
+mod stops;
+mod trade_price;
 mod trigger;
 
 use crate::bindings::{export, Guest, TriggerAction, WasmResponse};
+use alloy_sol_types::SolEvent;
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
-use std::fs;
-use std::path::Path;
+use chain::ClobClient;
+use config::{ClobConfig, MatchingMode, PriceReference};
+use escrow::EscrowLedger;
+use market::{MarketKey, MarketPhase, MarketRegistry};
+use price_guard::PriceGuard;
+use reconcile::FillTracker;
+use settlement::Settlement;
+use std::collections::{BTreeMap, BTreeSet};
+use stops::StopBook;
 use trigger::{
-    encode_matches_output, parse_clob_trigger, parse_order_placed_event, MatchResult, Order,
-    OrderBookEntry, OrderStatus, OrderType,
+    encode_output, parse_clob_trigger, parse_market_phase_changed_event,
+    parse_order_cancelled_event, parse_order_matched_event, parse_order_modified_event,
+    parse_order_partially_filled_event, parse_order_placed_event, CircuitBreak, MarketHalt,
+    MarketPhaseUpdate, MatchOutcome, MatchResult, Order, OrderCancellation, OrderStatus,
+    PostOnlyNotice, SelfTrade, TriggerOutput,
 };
-use wavs_wasi_utils::evm::alloy_primitives::{hex, Address, U256};
+use wavs_wasi_utils::evm::alloy_primitives::{Address, B256, U256};
 use wstd::runtime::block_on;
 
-struct Component;
-export!(Component with_types_in bindings);
+/// Files the component keeps its state in between runs.
+const ORDER_BOOK_FILE: &str = "clob_order_book.json";
+const STOP_BOOK_FILE: &str = "clob_stop_orders.json";
+const FILL_TRACKER_FILE: &str = "clob_fills.json";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct OrderBook {
-    buy_orders: BTreeMap<String, Vec<OrderBookEntry>>, // price -> orders
-    sell_orders: BTreeMap<String, Vec<OrderBookEntry>>, // price -> orders
+/// Everything the component keeps between runs.
+struct ClobState {
+    markets: MarketRegistry,
+    stops: StopBook,
+    fills: FillTracker,
 }
 
-impl OrderBook {
-    fn new() -> Self {
-        Self { buy_orders: BTreeMap::new(), sell_orders: BTreeMap::new() }
-    }
-
-    fn load_from_file(file_path: &str) -> Self {
-        if Path::new(file_path).exists() {
-            match fs::read_to_string(file_path) {
-                Ok(contents) => match serde_json::from_str::<OrderBook>(&contents) {
-                    Ok(order_book) => {
-                        println!("📂 Loaded order book from file with {} buy price levels, {} sell price levels", 
-                                order_book.buy_orders.len(), order_book.sell_orders.len());
-                        return order_book;
-                    }
-                    Err(e) => println!("⚠️ Failed to parse order book file: {}", e),
-                },
-                Err(e) => println!("⚠️ Failed to read order book file: {}", e),
-            }
-        } else {
-            println!("📁 Order book file not found, creating new order book");
-        }
-        Self::new()
-    }
-
-    fn save_to_file(&self, file_path: &str) -> Result<()> {
-        let contents = serde_json::to_string_pretty(self)?;
-        fs::write(file_path, contents)?;
-        println!(
-            "💾 Saved order book to file with {} buy price levels, {} sell price levels",
-            self.buy_orders.len(),
-            self.sell_orders.len()
-        );
-        Ok(())
-    }
-
-    fn add_order(&mut self, order: Order) {
-        let remaining = order.amount - order.filled_amount;
-        if remaining == U256::ZERO {
-            return;
-        }
-
-        let entry = OrderBookEntry { order: order.clone(), remaining_amount: remaining };
-
-        let price_key = order.price.to_string();
-
-        match order.order_type {
-            OrderType::Buy => {
-                self.buy_orders.entry(price_key).or_insert_with(Vec::new).push(entry);
-            }
-            OrderType::Sell => {
-                self.sell_orders.entry(price_key).or_insert_with(Vec::new).push(entry);
-            }
-        }
-    }
-
-    fn match_orders(&mut self) -> Vec<MatchResult> {
-        let mut matches = Vec::new();
-
-        // Get best buy price (highest)
-        let best_buy = self.buy_orders.keys().rev().next().cloned();
-        // Get best sell price (lowest)
-        let best_sell = self.sell_orders.keys().next().cloned();
-
-        if let (Some(buy_price_str), Some(sell_price_str)) = (best_buy, best_sell) {
-            let buy_price = U256::from_str_radix(&buy_price_str, 10).unwrap_or(U256::ZERO);
-            let sell_price = U256::from_str_radix(&sell_price_str, 10).unwrap_or(U256::MAX);
-
-            // Check if prices cross (buy price >= sell price)
-            if buy_price >= sell_price {
-                // Get orders at these price levels
-                let buy_orders = self.buy_orders.get_mut(&buy_price_str);
-                let sell_orders = self.sell_orders.get_mut(&sell_price_str);
-
-                if let (Some(buys), Some(sells)) = (buy_orders, sell_orders) {
-                    if !buys.is_empty() && !sells.is_empty() {
-                        let buy_order = &buys[0];
-                        let sell_order = &sells[0];
-
-                        // Calculate match amount
-                        let match_amount =
-                            buy_order.remaining_amount.min(sell_order.remaining_amount);
-
-                        if match_amount > U256::ZERO {
-                            // Use the sell price as the match price (price-time priority)
-                            let match_price = sell_price;
-
-                            matches.push(MatchResult {
-                                buy_order_id: U256::from(buy_order.order.id),
-                                sell_order_id: U256::from(sell_order.order.id),
-                                match_amount,
-                                match_price,
-                            });
-
-                            // Update remaining amounts
-                            buys[0].remaining_amount -= match_amount;
-                            sells[0].remaining_amount -= match_amount;
-
-                            // Remove filled orders
-                            if buys[0].remaining_amount == U256::ZERO {
-                                buys.remove(0);
-                            }
-                            if sells[0].remaining_amount == U256::ZERO {
-                                sells.remove(0);
-                            }
-
-                            // Clean up empty price levels
-                            if buys.is_empty() {
-                                self.buy_orders.remove(&buy_price_str);
-                            }
-                            if sells.is_empty() {
-                                self.sell_orders.remove(&sell_price_str);
-                            }
-                        }
-                    }
-                }
-            }
-        }
-
-        matches
-    }
-}
+struct Component;
+export!(Component with_types_in bindings);
 
 impl Guest for Component {
     fn run(action: TriggerAction) -> Result<Option<WasmResponse>, String> {
         println!("🚀 Starting CLOB component execution");
 
-        // Get contract address from config
-        // let clob_address = bindings::host::config_var("clob_address")
-        //     .ok_or_else(|| "CLOB contract address not configured".to_string())?;
-
-        // println!("📋 CLOB Contract: {}", clob_address);
-
         // Process the trigger event
         let result = block_on(async {
             match process_trigger(&action).await {
-                Ok(matches) if !matches.is_empty() => {
-                    println!("✅ Found {} order matches", matches.len());
+                Ok(output) if !output.is_empty() => {
+                    println!("✅ Found {} order matches", output.matches.len());
 
                     // Encode matches for contract
-                    let encoded = encode_matches_output(&matches)
+                    let encoded = encode_output(&output)
                         .map_err(|e| format!("Failed to encode matches: {}", e))?;
 
                     // Return the encoded matches as the response
@@ -189,7 +91,7 @@ impl Guest for Component {
     }
 }
 
-async fn process_trigger(action: &TriggerAction) -> Result<Vec<MatchResult>> {
+async fn process_trigger(action: &TriggerAction) -> Result<TriggerOutput> {
     // Extract event data from the trigger
     let event = match &action.data {
         bindings::wavs::types::events::TriggerData::EvmContractEvent(event) => {
@@ -198,28 +100,98 @@ async fn process_trigger(action: &TriggerAction) -> Result<Vec<MatchResult>> {
         }
         _ => {
             println!("⚠️ Unexpected trigger type");
-            return Ok(Vec::new());
+            return Ok(TriggerOutput::default());
         }
     };
 
-    // Load existing order book from file
-    const ORDER_BOOK_FILE: &str = "clob_order_book.json";
-    let mut order_book = OrderBook::load_from_file(ORDER_BOOK_FILE);
+    let config = ClobConfig::load()?;
+
+    let mut state = ClobState {
+        markets: MarketRegistry::new(),
+        // Load stop orders that have not triggered yet
+        stops: StopBook::load_from_file(STOP_BOOK_FILE),
+        // Load fills proposed by earlier runs that have not settled yet
+        fills: FillTracker::load_from_file(FILL_TRACKER_FILE),
+    };
+    let mut output = TriggerOutput::default();
+
+    // Load existing per-market order books from file, rebuilding them from
+    // chain state if there is nothing usable on disk
+    match MarketRegistry::load_from_file(ORDER_BOOK_FILE) {
+        Some(markets) => state.markets = markets,
+        None if config.bootstrap_from_chain => {
+            let (block, now) = (event.log.block_number, event.log.block_timestamp);
+            rebuild_from_chain(&config, block, now, &mut state).await?
+        }
+        None => println!("📁 Starting with an empty order book"),
+    }
+    let block = event.log.block_number;
+    let now = event.log.block_timestamp;
+
+    // Phases come from the contract, not from the saved books
+    load_phases(&config, block, &mut state).await?;
+
+    // Roll back or re-propose fills the chain never executed
+    output.matches.extend(state.fills.expire(block, &config, &mut state.markets));
+
+    // Release good-till-date orders whose expiry has passed
+    match now {
+        Some(now) => {
+            let mut expired = state.markets.expire_orders(now);
+            expired.extend(state.stops.expire_orders(now));
+            log_cancellations(&expired);
+            output.cancellations.extend(expired);
+        }
+        None => println!("⚠️ Trigger has no block timestamp, skipping order expiry"),
+    }
 
     // Determine which event we're processing based on event topics
-    let matches = if !event.log.data.topics.is_empty() {
-        let event_signature = hex::encode(&event.log.data.topics[0]);
+    if !event.log.data.topics.is_empty() {
+        let event_signature = B256::from_slice(&event.log.data.topics[0]);
 
-        if event_signature.starts_with("7fc72616") {
-            // CLOBTrigger event signature
+        if event_signature == solidity::CLOBTrigger::SIGNATURE_HASH {
             println!("🎯 Processing CLOBTrigger event");
-            let order_id = parse_clob_trigger(event.log.data.clone())?;
+            let order_id = parse_clob_trigger(event.log.data.clone())?.to::<u64>();
             println!("🔄 CLOBTrigger received for order ID: {}", order_id);
-            // For CLOBTrigger events, we might want to process all pending orders
-            // For now, just return empty matches
-            Vec::new()
-        } else if event_signature.starts_with("f860599f") {
-            // OrderPlaced event signature
+
+            // The OrderPlaced workflow may already have added the order
+            let market = match state
+                .markets
+                .market_of(order_id)
+                .or_else(|| state.stops.market_of(order_id))
+            {
+                Some(market) => Some(market),
+                None => {
+                    // Read as of the trigger block so every operator loads
+                    // the same order state
+                    let client = ClobClient::from_config(&config)?;
+                    match client.get_order_at(order_id, Some(block)).await? {
+                        Some(order) if order.status.is_live() => {
+                            println!(
+                                "✅ Loaded order from chain: ID={}, Type={:?}, Price={}, Amount={}, Filled={}",
+                                order.id, order.order_type, order.price, order.amount, order.filled_amount
+                            );
+                            Some(ingest_order(order, &mut state, &config, &mut output))
+                        }
+                        Some(order) => {
+                            println!(
+                                "ℹ️ Order {} is {:?}, nothing to match",
+                                order_id, order.status
+                            );
+                            None
+                        }
+                        None => {
+                            println!("⚠️ Order {} does not exist on chain", order_id);
+                            None
+                        }
+                    }
+                }
+            };
+
+            if let Some(market) = market {
+                run_matching(market, &mut state, &config, block, now, &mut output).await?;
+            }
+        } else if event_signature == solidity::OrderPlaced::SIGNATURE_HASH {
             println!("📋 Processing OrderPlaced event");
             let order = parse_order_placed_event(event.log.data.clone())?;
             println!(
@@ -227,36 +199,805 @@ async fn process_trigger(action: &TriggerAction) -> Result<Vec<MatchResult>> {
                 order.id, order.order_type, order.price, order.amount
             );
 
-            // Add the new order to the book
-            order_book.add_order(order.clone());
+            // Add the new order to the book for its pair
+            let market = ingest_order(order, &mut state, &config, &mut output);
+            run_matching(market, &mut state, &config, block, now, &mut output).await?;
+        } else if event_signature == solidity::OrderCancelled::SIGNATURE_HASH {
+            println!("🚫 Processing OrderCancelled event");
+            let order_id = parse_order_cancelled_event(event.log.data.clone())?;
+            cancel_order(order_id, &mut state, &config);
+        } else if event_signature == solidity::OrderModified::SIGNATURE_HASH {
+            println!("✏️ Processing OrderModified event");
+            let modification = parse_order_modified_event(event.log.data.clone())?;
+            let order_id = modification.order_id;
+
+            // Orders our fills consumed locally are only known on chain
+            let order = match state.markets.order(order_id) {
+                Some(order) => Some(order.clone()),
+                None => {
+                    println!("ℹ️ Modified order {} was not in the order book", order_id);
+                    ClobClient::from_config(&config)?.get_order_at(order_id, Some(block)).await?
+                }
+            };
+            match order {
+                Some(order) if order.status.is_open() => {
+                    let order = modification.apply(order);
+                    let market = amend_order(order, &mut state, &config, &mut output);
+                    run_matching(market, &mut state, &config, block, now, &mut output).await?;
+                }
+                Some(order) => {
+                    println!("ℹ️ Modified order {} is {:?}, ignoring", order_id, order.status)
+                }
+                None => println!("⚠️ Modified order {} does not exist on chain", order_id),
+            }
+        } else if event_signature == solidity::OrderMatched::SIGNATURE_HASH {
+            println!("🤝 Processing OrderMatched event");
+            let (settled, market, at) = parse_order_matched_event(event.log.data.clone())?;
+            state.fills.settle(&settled, market, at, &mut state.markets, block, &config);
+        } else if event_signature == solidity::OrderPartiallyFilled::SIGNATURE_HASH {
+            println!("🧩 Processing OrderPartiallyFilled event");
+            let (order_id, filled_amount, remaining_amount) =
+                parse_order_partially_filled_event(event.log.data.clone())?;
+            state.fills.reconcile_partial_fill(
+                order_id,
+                filled_amount,
+                remaining_amount,
+                &mut state.markets,
+                config.price_decimals,
+            );
+        } else if event_signature == solidity::MarketPhaseChanged::SIGNATURE_HASH {
+            println!("🚦 Processing MarketPhaseChanged event");
+            let (market, phase) = parse_market_phase_changed_event(event.log.data.clone())?;
+            println!("🚦 Market {} moves to {:?}", market, phase);
+            state.markets.set_phase(market, phase);
+            run_matching(market, &mut state, &config, block, now, &mut output).await?;
+        } else {
+            println!("⚠️ Unknown event signature: {}", event_signature);
+        }
+    } else {
+        println!("⚠️ Event has no topics");
+    }
+
+    if config.simulate_settlement && !output.matches.is_empty() {
+        check_settlement(&config, &mut state, &mut output, block).await?;
+    }
+
+    log_self_trade_report(block, &output.self_trades);
+    state.fills.propose_cancellations(&output.cancellations, block);
 
-            // Run matching algorithm to find any matches
-            let matches = order_book.match_orders();
+    // Save the updated order books back to file
+    if let Err(e) = state.markets.save_to_file(ORDER_BOOK_FILE) {
+        println!("⚠️ Failed to save order book: {}", e);
+    }
+    if let Err(e) = state.stops.save_to_file(STOP_BOOK_FILE) {
+        println!("⚠️ Failed to save stop orders: {}", e);
+    }
+    if let Err(e) = state.fills.save_to_file(FILL_TRACKER_FILE) {
+        println!("⚠️ Failed to save fill tracker: {}", e);
+    }
+
+    // Operators that processed the same events must agree on this root
+    let state_root = state.markets.state_root(&state.stops, &config);
+    println!("🌳 Order book state root after block {}: {}", block, state_root);
+
+    if config.include_state_root {
+        output.state_root = state_root;
+    }
+
+    Ok(output)
+}
+
+/// Reconstructs every market from the orders the contract still has open, so
+/// an operator without a usable book file converges on the same book as the
+/// rest of the set. The contract is read as of the trigger `block`. Orders
+/// go in as the contract holds them: they already passed the market's rules
+/// and post-only check when placed, so nothing is rejected or repriced.
+/// Each market's price history, which the price band, the circuit breaker
+/// and stop orders work from, is replayed from the trades the contract
+/// executed; if those cannot be read, a market with an oracle starts from
+/// its price at block time `now`. Phases are read from the contract every
+/// run, see [`load_phases`]. Amendments that cost an order its queue
+/// priority are replayed from the contract's logs, sending the order behind
+/// every order placed before the amendment; if the logs cannot be read,
+/// amended orders queue by id again and the book's state root differs from
+/// that of operators that saw the amendment.
+async fn rebuild_from_chain(
+    config: &ClobConfig,
+    block: u64,
+    now: Option<u64>,
+    state: &mut ClobState,
+) -> Result<()> {
+    println!("🏗️ Rebuilding order books from chain state");
+    let client = ClobClient::from_config(config)?;
+
+    let orders = client.open_orders(block).await?;
+    let order_count = orders.len();
+    restore_orders(orders, state, config);
+    match client.requeued_orders(block).await {
+        Ok(requeued) => {
+            for (order_id, newest_id) in requeued {
+                state.markets.requeue(order_id, newest_id);
+            }
+        }
+        Err(e) => println!("⚠️ Could not read past amendments, amended orders queue by id: {}", e),
+    }
 
-            if !matches.is_empty() {
-                println!("🎯 Found {} matches!", matches.len());
-                for m in &matches {
-                    println!(
-                        "   💹 Match: Buy Order {} <-> Sell Order {}, Amount: {}, Price: {}",
-                        m.buy_order_id, m.sell_order_id, m.match_amount, m.match_price
+    match client.trades(block).await {
+        Ok(trades) => {
+            println!("🏗️ Replaying {} past trades into the price history", trades.len());
+            for (market, at, price) in trades {
+                state.markets.book_mut(market).prices.record(
+                    at,
+                    price,
+                    config.price_history_secs(),
+                );
+            }
+        }
+        Err(e) => {
+            println!("⚠️ Could not read past trades: {}", e);
+            if let (Some(oracle), Some(now)) = (config.price_oracle_address, now) {
+                for market in client.markets(block).await? {
+                    let price = client.oracle_price(oracle, market, block).await?;
+                    println!("🔮 Seeding market {} with oracle price {}", market, price);
+                    state.markets.book_mut(market).prices.record(
+                        now,
+                        price,
+                        config.price_history_secs(),
                     );
                 }
             }
+        }
+    }
 
-            matches
-        } else {
-            println!("⚠️ Unknown event signature: {}", event_signature);
-            Vec::new()
+    println!("🏗️ Rebuilt {} markets from {} open orders", state.markets.markets.len(), order_count);
+    Ok(())
+}
+
+/// Puts the orders the contract still has open into the books, and stop
+/// orders that have not triggered into the stop book, minus what our
+/// unsettled fills and pending cancellations take from them.
+fn restore_orders(orders: Vec<Order>, state: &mut ClobState, config: &ClobConfig) {
+    for order in orders {
+        if order.status == OrderStatus::PendingTrigger {
+            state.stops.add_order(order);
+            continue;
+        }
+        let taken = state.fills.in_flight(order.id, config.price_decimals)
+            + state.fills.cancelling(order.id);
+        let remaining = (order.amount - order.filled_amount).saturating_sub(taken);
+        if !order.is_exhausted(remaining, config.price_decimals) {
+            state.markets.restore(&order, remaining);
+        }
+    }
+}
+
+/// Takes every market's phase from the contract as of `block`, rather than
+/// from the saved books, so a halt or phase change another operator's
+/// payload applied is never missed.
+async fn load_phases(config: &ClobConfig, block: u64, state: &mut ClobState) -> Result<()> {
+    let client = ClobClient::from_config(config)?;
+    for market in client.markets(block).await? {
+        let phase = client.market_phase(market, block).await?;
+        state.markets.load_phase(market, phase);
+    }
+    Ok(())
+}
+
+/// Adds an order to the book for its pair, unless the book already holds it.
+/// Whatever our unsettled fills took from the order and our pending
+/// cancellations released is not added back, so an order seen through both
+/// `OrderPlaced` and `CLOBTrigger` is only ever matched once. New orders
+/// that break the market's rules are rejected; those rejections and
+/// post-only ones and reprices go to `output`. Stop orders that have not
+/// triggered are held in the stop book instead.
+fn ingest_order(
+    order: Order,
+    state: &mut ClobState,
+    config: &ClobConfig,
+    output: &mut TriggerOutput,
+) -> MarketKey {
+    // The chain still shows an order we already matched or cancelled as it
+    // was, and a stop we already triggered as pending
+    let taken =
+        state.fills.in_flight(order.id, config.price_decimals) + state.fills.cancelling(order.id);
+    let seen = state.markets.market_of(order.id).is_some() || taken > U256::ZERO;
+    let market = MarketKey::of(&order);
+    let spec = config.spec_for(market);
+    if !seen {
+        if let Err(reason) = spec.check(&order) {
+            println!(
+                "🚫 Rejected order {} breaking the rules of market {}: {:?}",
+                order.id, market, reason
+            );
+            let amount = order.amount - order.filled_amount;
+            output.cancellations.push(OrderCancellation { order_id: order.id, amount, reason });
+            return market;
+        }
+    }
+
+    if order.status == OrderStatus::PendingTrigger && !seen {
+        println!("⏸️ Holding stop order {} until price {}", order.id, order.stop_price);
+        return state.stops.add_order(order);
+    }
+
+    let (_, notice) = state.markets.add_order(order, taken, &spec);
+    if let Some(notice) = notice {
+        record_post_only(notice, output);
+    }
+    market
+}
+
+/// Applies an amendment to an order, given with its new price and amount.
+/// The book decides whether it keeps its queue priority; see
+/// [`order_book::OrderBook::modify_order`]. New terms that break the
+/// market's rules release whatever our unsettled fills leave of the order.
+fn amend_order(
+    order: Order,
+    state: &mut ClobState,
+    config: &ClobConfig,
+    output: &mut TriggerOutput,
+) -> MarketKey {
+    let market = MarketKey::of(&order);
+    let spec = config.spec_for(market);
+    let in_flight = state.fills.in_flight(order.id, config.price_decimals);
+    if let Err(reason) = spec.check(&order) {
+        println!(
+            "🚫 Rejected amendment of order {} breaking the rules of market {}: {:?}",
+            order.id, market, reason
+        );
+        state.markets.remove_order(order.id);
+        let amount = (order.amount - order.filled_amount).saturating_sub(in_flight);
+        if !amount.is_zero() {
+            output.cancellations.push(OrderCancellation { order_id: order.id, amount, reason });
         }
+        return market;
+    }
+
+    println!("✏️ Amending order {}: price {}, amount {}", order.id, order.price, order.amount);
+    let (_, notice) = state.markets.modify_order(order, in_flight, &spec);
+    if let Some(notice) = notice {
+        record_post_only(notice, output);
+    }
+    market
+}
+
+/// Drops an order the contract cancelled from its book, or from the stop
+/// book if it had not triggered yet.
+fn cancel_order(order_id: u64, state: &mut ClobState, config: &ClobConfig) {
+    state.fills.on_order_cancelled(order_id, &mut state.markets, config.price_decimals);
+    match state.markets.remove_order(order_id) {
+        Some((market, entry)) => println!(
+            "🗑️ Removed cancelled order {} from market {} (remaining {})",
+            order_id, market, entry.remaining_amount
+        ),
+        None => match state.stops.remove_order(order_id) {
+            Some(_) => println!("🗑️ Removed cancelled stop order {}", order_id),
+            None => println!("ℹ️ Cancelled order {} was not in the order book", order_id),
+        },
+    }
+}
+
+/// Logs the outcome of a post-only check and adds it to `output`.
+fn record_post_only(notice: PostOnlyNotice, output: &mut TriggerOutput) {
+    match &notice {
+        PostOnlyNotice::Rejected(cancellation) => println!(
+            "🙅 Rejected post-only order {} that would take liquidity",
+            cancellation.order_id
+        ),
+        PostOnlyNotice::Repriced(reprice) => println!(
+            "↔️ Slid post-only order {} from price {} to {}",
+            reprice.order_id, reprice.old_price, reprice.new_price
+        ),
+    }
+    output.record(notice);
+}
+
+/// Matches a market until nothing crosses or the batch is full, records the
+/// fills as proposed and adds everything produced to `output`. After each
+/// round the last trade price is checked against the market's stop orders,
+/// and any that trigger enter the book for another round. A round that
+/// trips the circuit breaker halts the market.
+async fn run_matching(
+    market: MarketKey,
+    state: &mut ClobState,
+    config: &ClobConfig,
+    block: u64,
+    now: Option<u64>,
+    output: &mut TriggerOutput,
+) -> Result<()> {
+    println!("🏦 Matching in market {}", market);
+
+    // Read at the trigger block so every operator bands against the same price
+    let oracle_price = match config.price_oracle_address {
+        Some(oracle)
+            if config.price_band_reference == PriceReference::Oracle
+                && config.price_band_bps > 0 =>
+        {
+            let price =
+                ClobClient::from_config(config)?.oracle_price(oracle, market, block).await?;
+            println!("🔮 Oracle price for market {} at block {}: {}", market, block, price);
+            Some(price)
+        }
+        _ => None,
+    };
+
+    let mut escrow = if config.check_escrow {
+        EscrowLedger::new(config.price_decimals)
     } else {
-        println!("⚠️ Event has no topics");
-        Vec::new()
+        EscrowLedger::unchecked()
     };
 
-    // Save the updated order book back to file
-    if let Err(e) = order_book.save_to_file(ORDER_BOOK_FILE) {
-        println!("⚠️ Failed to save order book: {}", e);
+    // Fills proposed again this run take their share of the batch
+    let mut budget = config.max_matches_per_batch.saturating_sub(output.matches.len());
+    loop {
+        activate_stops(market, state, config, output);
+        load_escrow(&mut escrow, market, state, config, block).await?;
+
+        let guard = state.markets.price_guard(market, config, oracle_price, now);
+        let phase = state.markets.phase(market, config);
+        let mut outcome = match_round(market, state, config, budget, now, guard, &mut escrow);
+        let next = state.markets.phase(market, config);
+        if next != phase {
+            // A call auction finished; the contract records where it left
+            // the market
+            output.phase_updates.push(MarketPhaseUpdate { market, phase: next });
+        }
+        for fill in &mut outcome.fills {
+            config.fees.charge(fill, config.price_decimals);
+        }
+        state.fills.propose(market, &outcome.fills, block);
+        budget -= outcome.fills.len();
+        let matches: Vec<MatchResult> = outcome.fills.into_iter().map(|f| f.result).collect();
+
+        if !matches.is_empty() {
+            println!("🎯 Found {} matches!", matches.len());
+            for m in &matches {
+                println!(
+                    "   💹 Match: Buy Order {} <-> Sell Order {}, Amount: {}, Price: {}, Taker: {:?}, Fees: {} / {}",
+                    m.buy_order_id, m.sell_order_id, m.match_amount, m.match_price, m.taker_side, m.buyer_fee, m.seller_fee
+                );
+            }
+        }
+
+        if outcome.carried_over {
+            println!(
+                "⏭️ Batch limit of {} matches reached, remaining crossing orders carried to the next run",
+                config.max_matches_per_batch
+            );
+        }
+
+        log_cancellations(&outcome.cancellations);
+
+        output.matches.extend(matches);
+        output.cancellations.extend(outcome.cancellations);
+        output.self_trades.extend(outcome.self_trades);
+        output.band_rejections.extend(outcome.band_rejections);
+
+        if let Some(breaker) = outcome.breaker {
+            halt_market(market, breaker, state, output);
+            break;
+        }
+
+        if budget == 0
+            || !state.markets.phase(market, config).is_trading()
+            || !state.stops.has_triggered(market, state.markets.last_price(market))
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Moves the stop orders of `market` that its last trade price triggered
+/// into the book. Stops wait while the market is not trading, so a stop
+/// market order is not cancelled for want of a market to fill it in.
+fn activate_stops(
+    market: MarketKey,
+    state: &mut ClobState,
+    config: &ClobConfig,
+    output: &mut TriggerOutput,
+) {
+    if !state.markets.phase(market, config).is_trading() {
+        return;
+    }
+    for order in state.stops.activate(market, state.markets.last_price(market)) {
+        println!("🚨 Stop order {} triggered at last price, entering the book", order.id);
+        output.triggered_stops.push(order.id);
+        ingest_order(order, state, config, output);
+    }
+}
+
+/// Replays the payload's matches against the contract's orders and escrow
+/// at `block` and keeps out whatever `executeMatch` would revert on. Fills
+/// and stop triggers of our earlier payloads that have not landed by
+/// `block` are replayed first, as the chain executes those payloads before
+/// this one; fills that landed are already in the contract's state, whether
+/// or not their OrderMatched event has reached us yet.
+/// What is left out is given back to the book and reported in `output`.
+async fn check_settlement(
+    config: &ClobConfig,
+    state: &mut ClobState,
+    output: &mut TriggerOutput,
+    block: u64,
+) -> Result<()> {
+    let client = ClobClient::from_config(config)?;
+    let landed = match state.fills.oldest_proposal() {
+        Some(from) => client.settled_matches(from, block).await?,
+        None => Vec::new(),
+    };
+    let earlier = state.fills.proposed_elsewhere(&output.matches, &landed);
+    let order_ids: BTreeSet<u64> = output
+        .matches
+        .iter()
+        .chain(&earlier)
+        .flat_map(|m| [m.buy_order_id, m.sell_order_id])
+        .map(|id| id.to::<u64>())
+        .collect();
+    let mut orders = Vec::new();
+    for order_id in order_ids {
+        orders.extend(client.get_order_at(order_id, Some(block)).await?);
+    }
+    let accounts: BTreeSet<(Address, Address)> = orders
+        .iter()
+        .flat_map(|order| [(order.trader, order.base_token), (order.trader, order.quote_token)])
+        .collect();
+    let mut escrow = BTreeMap::new();
+    for (trader, token) in accounts {
+        escrow.insert((trader, token), client.escrow_balance(trader, token, block).await?);
+    }
+
+    // Stops the chain still holds that are no longer in our stop book were
+    // triggered by an earlier payload
+    let earlier_stops: Vec<u64> = orders
+        .iter()
+        .filter(|order| order.status == OrderStatus::PendingTrigger)
+        .map(|order| order.id)
+        .filter(|id| state.stops.market_of(*id).is_none() && !output.triggered_stops.contains(id))
+        .collect();
+
+    let mut settlement = Settlement::new(orders, escrow, config.price_decimals);
+    settlement.run(&earlier_stops, earlier);
+    let matches = std::mem::take(&mut output.matches);
+    let (settled, excluded) = settlement.run(&output.triggered_stops, matches);
+    for exclusion in &excluded {
+        println!(
+            "🧮 Left {} of Buy Order {} <-> Sell Order {} out of the payload: {:?}",
+            exclusion.amount,
+            exclusion.proposed.buy_order_id,
+            exclusion.proposed.sell_order_id,
+            exclusion.reason
+        );
+        state.fills.exclude(exclusion, &mut state.markets, config.price_decimals);
+    }
+    output.matches = settled;
+    output.excluded.extend(excluded);
+    Ok(())
+}
+
+/// Reads the escrow of traders resting in `market` that `escrow` has not
+/// seen yet, as of the trigger block, less what our fills awaiting
+/// settlement will take out of it.
+async fn load_escrow(
+    escrow: &mut EscrowLedger,
+    market: MarketKey,
+    state: &ClobState,
+    config: &ClobConfig,
+    block: u64,
+) -> Result<()> {
+    let missing = escrow.missing(state.markets.escrow_accounts(market));
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let client = ClobClient::from_config(config)?;
+    for (trader, token) in missing {
+        let balance = client.escrow_balance(trader, token, block).await?;
+        let in_flight = state.fills.escrow_in_flight(trader, token, config.price_decimals);
+        escrow.insert(trader, token, balance.saturating_sub(in_flight));
+    }
+    println!("🔐 Loaded escrow for market {} at block {}", market, block);
+    Ok(())
+}
+
+/// One round of matching in `market`, as its phase and matching mode call
+/// for. A call auction that completes moves the market on to its next phase.
+/// A market that is not trading only cancels the orders that cannot wait.
+fn match_round(
+    market: MarketKey,
+    state: &mut ClobState,
+    config: &ClobConfig,
+    budget: usize,
+    now: Option<u64>,
+    guard: PriceGuard,
+    escrow: &mut EscrowLedger,
+) -> MatchOutcome {
+    let phase = state.markets.phase(market, config);
+    match phase {
+        MarketPhase::Continuous => match (config.matching_mode_for(market), now) {
+            (MatchingMode::Continuous, _) => {
+                state.markets.match_market(market, budget, now, config, guard, escrow)
+            }
+            (MatchingMode::Auction, Some(now)) => {
+                state.markets.auction_market(market, budget, now, config, guard, escrow)
+            }
+            (MatchingMode::Auction, None) => {
+                println!("⚠️ No block time for auction market {}, collecting orders", market);
+                MatchOutcome::default()
+            }
+        },
+        MarketPhase::OpeningAuction | MarketPhase::ClosingAuction => {
+            let outcome =
+                state.markets.call_auction_market(market, budget, now, config, guard, escrow);
+            if !outcome.carried_over && outcome.breaker.is_none() {
+                let next = match phase {
+                    MarketPhase::OpeningAuction => MarketPhase::Continuous,
+                    _ => MarketPhase::Closed,
+                };
+                println!("🔔 {:?} in market {} complete, moving to {:?}", phase, market, next);
+                state.markets.set_phase(market, next);
+            }
+            outcome
+        }
+        MarketPhase::PreOpen | MarketPhase::Halted | MarketPhase::Closed => {
+            // Orders that must execute now cannot wait for the market to
+            // reopen; limit orders placed before the open join its auction
+            let cancellations =
+                state.markets.cancel_immediate(market, phase == MarketPhase::PreOpen);
+            println!(
+                "⏸️ Market {} is {:?}, orders rest without matching; cancelled {} immediate orders",
+                market,
+                phase,
+                cancellations.len()
+            );
+            MatchOutcome { cancellations, ..MatchOutcome::default() }
+        }
+    }
+}
+
+/// Halts a market whose circuit breaker tripped, until the contract
+/// reopens it, and reports the halt for the contract to apply.
+fn halt_market(
+    market: MarketKey,
+    breaker: CircuitBreak,
+    state: &mut ClobState,
+    output: &mut TriggerOutput,
+) {
+    println!(
+        "🛑 Circuit breaker halted market {}: price {} moved too far from {}",
+        market, breaker.price, breaker.reference
+    );
+    state.markets.set_phase(market, MarketPhase::Halted);
+    output.halts.push(MarketHalt { market, price: breaker.price, reference: breaker.reference });
+}
+
+/// Lists every self-trade prevention decision taken in this run.
+fn log_self_trade_report(block: u64, self_trades: &[SelfTrade]) {
+    if self_trades.is_empty() {
+        return;
+    }
+    println!("🧾 Self-trade prevention report for block {}:", block);
+    for self_trade in self_trades {
+        println!(
+            "   🪞 Trader {}: resting order {} / incoming order {}, amount {}, action {:?}",
+            self_trade.trader,
+            self_trade.resting_order_id,
+            self_trade.incoming_order_id,
+            self_trade.amount,
+            self_trade.action
+        );
+    }
+}
+
+fn log_cancellations(cancellations: &[OrderCancellation]) {
+    for cancellation in cancellations {
+        println!(
+            "   ✂️ Releasing {} of order {}: {:?}",
+            cancellation.amount, cancellation.order_id, cancellation.reason
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config::config;
+    use crate::trigger::test_orders::order;
+    use crate::trigger::{OrderKind, OrderType, TimeInForce};
+
+    const BUYER: Address = Address::repeat_byte(1);
+    const SELLER: Address = Address::repeat_byte(2);
+
+    fn state() -> ClobState {
+        ClobState {
+            markets: MarketRegistry::new(),
+            stops: StopBook::new(),
+            fills: FillTracker::new(),
+        }
     }
 
-    Ok(matches)
+    fn run(market: MarketKey, state: &mut ClobState, config: &ClobConfig) -> MatchOutcome {
+        let budget = config.max_matches_per_batch;
+        let mut escrow = EscrowLedger::unchecked();
+        match_round(market, state, config, budget, None, PriceGuard::default(), &mut escrow)
+    }
+
+    #[test]
+    fn ioc_seen_through_both_events_is_matched_once() {
+        let config = config();
+        let mut state = state();
+        let mut output = TriggerOutput::default();
+        let ioc =
+            Order { time_in_force: TimeInForce::Ioc, ..order(2, BUYER, OrderType::Buy, 100, 3) };
+
+        // OrderPlaced: the IOC takes the one unit for sale and the rest of
+        // it is cancelled
+        let sell = order(1, SELLER, OrderType::Sell, 100, 1);
+        let market = ingest_order(sell, &mut state, &config, &mut output);
+        ingest_order(ioc.clone(), &mut state, &config, &mut output);
+        let outcome = run(market, &mut state, &config);
+        assert_eq!(outcome.fills.len(), 1);
+        assert_eq!(outcome.cancellations[0].amount, U256::from(2));
+        state.fills.propose(market, &outcome.fills, 1);
+        state.fills.propose_cancellations(&outcome.cancellations, 1);
+
+        // CLOBTrigger reads it at the same block, still open and unfilled;
+        // none of it may take the liquidity that arrived since
+        ingest_order(ioc, &mut state, &config, &mut output);
+        ingest_order(order(3, SELLER, OrderType::Sell, 100, 2), &mut state, &config, &mut output);
+        let outcome = run(market, &mut state, &config);
+        assert!(outcome.fills.is_empty() && outcome.cancellations.is_empty());
+        assert!(output.cancellations.is_empty());
+    }
+
+    #[test]
+    fn stops_wait_for_the_market_to_trade() {
+        let config = config();
+        let mut state = state();
+        let mut output = TriggerOutput::default();
+        let stop = Order {
+            kind: OrderKind::Market,
+            status: OrderStatus::PendingTrigger,
+            stop_price: U256::from(100),
+            ..order(1, SELLER, OrderType::Sell, 90, 1)
+        };
+        let market = ingest_order(stop, &mut state, &config, &mut output);
+        state.markets.book_mut(market).prices.record(1, U256::from(99), 60);
+
+        state.markets.set_phase(market, MarketPhase::Halted);
+        activate_stops(market, &mut state, &config, &mut output);
+        assert!(output.triggered_stops.is_empty());
+        assert_eq!(state.stops.market_of(1), Some(market));
+
+        state.markets.set_phase(market, MarketPhase::Continuous);
+        activate_stops(market, &mut state, &config, &mut output);
+        assert_eq!(output.triggered_stops, vec![1]);
+        assert_eq!(state.markets.market_of(1), Some(market));
+    }
+
+    #[test]
+    fn cancelled_orders_leave_the_book_and_the_stop_book() {
+        let config = config();
+        let mut state = state();
+        let mut output = TriggerOutput::default();
+        let market =
+            ingest_order(order(1, BUYER, OrderType::Buy, 90, 1), &mut state, &config, &mut output);
+        let stop = Order {
+            status: OrderStatus::PendingTrigger,
+            stop_price: U256::from(100),
+            ..order(2, BUYER, OrderType::Buy, 110, 1)
+        };
+        ingest_order(stop, &mut state, &config, &mut output);
+
+        cancel_order(1, &mut state, &config);
+        let book = state.markets.book_mut(market);
+        assert!(!book.contains(1));
+        assert!(book.bids.is_empty());
+        assert_eq!(state.markets.market_of(1), None);
+        assert_eq!(state.stops.market_of(2), Some(market));
+
+        cancel_order(2, &mut state, &config);
+        assert_eq!(state.stops.market_of(2), None);
+        assert_eq!(state.stops.order_count(), 0);
+    }
+
+    #[test]
+    fn tripped_breaker_halts_the_market() {
+        let mut config = config();
+        config.circuit_breaker_bps = 1_000;
+        let mut state = state();
+        let mut output = TriggerOutput::default();
+        let market = MarketKey::of(&order(1, SELLER, OrderType::Sell, 100, 1));
+        state.markets.book_mut(market).prices.record(0, U256::from(100), 300);
+        for order in [
+            order(1, SELLER, OrderType::Sell, 100, 1),
+            order(2, SELLER, OrderType::Sell, 120, 1),
+            order(3, BUYER, OrderType::Buy, 120, 2),
+        ] {
+            ingest_order(order, &mut state, &config, &mut output);
+        }
+
+        let guard = state.markets.price_guard(market, &config, None, Some(10));
+        let mut escrow = EscrowLedger::unchecked();
+        let outcome = match_round(market, &mut state, &config, 10, Some(10), guard, &mut escrow);
+        assert_eq!(outcome.fills.len(), 1);
+        halt_market(market, outcome.breaker.unwrap(), &mut state, &mut output);
+        assert_eq!(state.markets.phase(market, &config), MarketPhase::Halted);
+        assert_eq!(output.halts.len(), 1);
+        assert_eq!(output.halts[0].reference, U256::from(100));
+
+        // The crossing orders rest until the contract reopens the market
+        assert!(run(market, &mut state, &config).fills.is_empty());
+        assert!(state.markets.market_of(2).is_some() && state.markets.market_of(3).is_some());
+    }
+
+    #[test]
+    fn rebuilt_books_match_the_live_state_root() {
+        let config = config();
+        let (mut live, mut rebuilt) = (state(), state());
+        let mut output = TriggerOutput::default();
+        let sell = order(1, SELLER, OrderType::Sell, 100, 10);
+        let stop = Order {
+            status: OrderStatus::PendingTrigger,
+            stop_price: U256::from(120),
+            ..order(3, BUYER, OrderType::Buy, 130, 1)
+        };
+        let market = ingest_order(sell.clone(), &mut live, &config, &mut output);
+        ingest_order(stop.clone(), &mut live, &config, &mut output);
+        ingest_order(order(2, BUYER, OrderType::Buy, 100, 4), &mut live, &config, &mut output);
+        let outcome = run(market, &mut live, &config);
+        assert_eq!(outcome.fills.len(), 1);
+        let settled = outcome.fills[0].result.clone();
+        live.fills.settle(&settled, market, 0, &mut live.markets, 1, &config);
+        live.markets.set_phase(market, MarketPhase::Halted);
+
+        // The chain shows the sell partly filled, the buy filled and the
+        // stop pending, and stores the halt
+        let chain_sell = Order { filled_amount: U256::from(4), ..sell };
+        restore_orders(vec![chain_sell, stop], &mut rebuilt, &config);
+        rebuilt.markets.load_phase(market, Some(MarketPhase::Halted));
+
+        assert_eq!(
+            rebuilt.markets.state_root(&rebuilt.stops, &config),
+            live.markets.state_root(&live.stops, &config)
+        );
+    }
+
+    #[test]
+    fn amended_orders_keep_priority_only_at_the_same_price_and_less() {
+        let config = config();
+        let (mut live, mut rebuilt) = (state(), state());
+        let mut output = TriggerOutput::default();
+        let sell = |id, price, amount| order(id, SELLER, OrderType::Sell, price, amount);
+        for placed in [sell(1, 100, 2), sell(2, 100, 2), sell(3, 100, 2), sell(4, 101, 2)] {
+            ingest_order(placed, &mut live, &config, &mut output);
+        }
+        // Larger, smaller, then repriced onto the level, before order 5
+        let market = amend_order(sell(1, 100, 3), &mut live, &config, &mut output);
+        amend_order(sell(2, 100, 1), &mut live, &config, &mut output);
+        amend_order(sell(4, 100, 2), &mut live, &config, &mut output);
+        ingest_order(sell(5, 100, 2), &mut live, &config, &mut output);
+
+        // The chain holds the amended terms; its logs show orders 1 and 4
+        // lost their priority after order 4 was placed
+        let chain = vec![
+            sell(1, 100, 3),
+            sell(2, 100, 1),
+            sell(3, 100, 2),
+            sell(4, 100, 2),
+            sell(5, 100, 2),
+        ];
+        restore_orders(chain, &mut rebuilt, &config);
+        for (order_id, newest_id) in [(1, 4), (4, 4)] {
+            rebuilt.markets.requeue(order_id, newest_id);
+        }
+
+        for state in [&mut live, &mut rebuilt] {
+            let (_, asks) = state.markets.book_mut(market).snapshot();
+            let queue: Vec<u64> = asks[0].orders.iter().map(|o| o.orderId.to::<u64>()).collect();
+            assert_eq!(queue, [2, 3, 1, 4, 5]);
+        }
+        assert_eq!(
+            rebuilt.markets.state_root(&rebuilt.stops, &config),
+            live.markets.state_root(&live.stops, &config)
+        );
+    }
 }
@@ -0,0 +1,636 @@
+use crate::config::ClobConfig;
+use crate::escrow::EscrowLedger;
+use crate::order_book::OrderBook;
+use crate::price_guard::{PriceGuard, PriceHistory};
+use crate::solidity::MarketSnapshot;
+use crate::spec::MarketSpec;
+use crate::stops::StopBook;
+use crate::trigger::{MatchOutcome, Order, OrderBookEntry, OrderCancellation, PostOnlyNotice};
+use alloy_sol_types::SolValue;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use wavs_wasi_utils::evm::alloy_primitives::{keccak256, Address, B256, U256};
+
+/// Identifies a trading pair, like the `(baseToken, quoteToken)` pair behind
+/// `CLOB.sol`'s `getOrderBookKey`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct MarketKey {
+    pub base_token: Address,
+    pub quote_token: Address,
+}
+
+impl MarketKey {
+    pub fn new(base_token: Address, quote_token: Address) -> Self {
+        Self { base_token, quote_token }
+    }
+
+    pub fn of(order: &Order) -> Self {
+        Self::new(order.base_token, order.quote_token)
+    }
+}
+
+impl fmt::Display for MarketKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.base_token, self.quote_token)
+    }
+}
+
+/// Trading phase of a market. Orders are taken into the book in every
+/// phase; only matching depends on it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MarketPhase {
+    /// Orders collect before the open without matching.
+    PreOpen,
+    /// The book is uncrossed at a single price, then trading goes
+    /// continuous.
+    OpeningAuction,
+    /// Regular trading in the configured matching mode.
+    #[default]
+    Continuous,
+    /// Matching is paused until the market is reopened.
+    Halted,
+    /// The book is uncrossed at a single price, then the market closes.
+    ClosingAuction,
+    /// Trading has ended for the session.
+    Closed,
+}
+
+impl MarketPhase {
+    /// Encoding of the phase in CLOB.sol's `MarketPhase` enum
+    pub fn to_sol(self) -> u8 {
+        match self {
+            MarketPhase::PreOpen => 0,
+            MarketPhase::OpeningAuction => 1,
+            MarketPhase::Continuous => 2,
+            MarketPhase::Halted => 3,
+            MarketPhase::ClosingAuction => 4,
+            MarketPhase::Closed => 5,
+        }
+    }
+
+    /// Decode the `MarketPhase` enum as emitted by CLOB.sol
+    pub fn from_sol(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(MarketPhase::PreOpen),
+            1 => Ok(MarketPhase::OpeningAuction),
+            2 => Ok(MarketPhase::Continuous),
+            3 => Ok(MarketPhase::Halted),
+            4 => Ok(MarketPhase::ClosingAuction),
+            5 => Ok(MarketPhase::Closed),
+            _ => Err(anyhow::anyhow!("Invalid market phase: {}", value)),
+        }
+    }
+
+    /// Whether fills may be emitted for the market in this phase.
+    pub fn is_trading(self) -> bool {
+        matches!(self, Self::OpeningAuction | Self::Continuous | Self::ClosingAuction)
+    }
+}
+
+impl FromStr for MarketPhase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pre_open" => Ok(Self::PreOpen),
+            "opening_auction" => Ok(Self::OpeningAuction),
+            "continuous" => Ok(Self::Continuous),
+            "halted" => Ok(Self::Halted),
+            "closing_auction" => Ok(Self::ClosingAuction),
+            "closed" => Ok(Self::Closed),
+            _ => Err(format!(
+                "unknown market phase {s:?}, expected pre_open, opening_auction, continuous, halted, closing_auction or closed"
+            )),
+        }
+    }
+}
+
+/// One order book per trading pair.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Markets")]
+pub struct MarketRegistry {
+    #[serde(with = "market_list")]
+    pub markets: BTreeMap<MarketKey, OrderBook>,
+    /// Order id -> market, so an order's book can be found without asking
+    /// every market. Rebuilt from the books on load. Orders matched out of
+    /// their book may linger until then; lookups check the book.
+    #[serde(skip)]
+    orders: BTreeMap<u64, MarketKey>,
+}
+
+/// The persisted part of a [`MarketRegistry`].
+#[derive(Deserialize)]
+struct Markets {
+    #[serde(with = "market_list")]
+    markets: BTreeMap<MarketKey, OrderBook>,
+}
+
+impl From<Markets> for MarketRegistry {
+    fn from(persisted: Markets) -> Self {
+        let orders = persisted
+            .markets
+            .iter()
+            .flat_map(|(key, book)| book.order_ids().map(move |id| (id, *key)))
+            .collect();
+        Self { markets: persisted.markets, orders }
+    }
+}
+
+impl MarketRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the books written by a previous run, or `None` if there is no
+    /// usable file and the books have to be rebuilt.
+    pub fn load_from_file(file_path: &str) -> Option<Self> {
+        if Path::new(file_path).exists() {
+            match fs::read_to_string(file_path) {
+                Ok(contents) => match serde_json::from_str::<StateFile>(&contents) {
+                    Ok(StateFile::Markets(registry)) => {
+                        println!(
+                            "📂 Loaded order books from file for {} markets",
+                            registry.markets.len()
+                        );
+                        return Some(registry);
+                    }
+                    Ok(StateFile::SingleBook(order_book)) => {
+                        let registry = Self::from_single_book(order_book);
+                        println!(
+                            "📂 Migrated single order book file into {} markets",
+                            registry.markets.len()
+                        );
+                        return Some(registry);
+                    }
+                    Err(e) => println!("⚠️ Failed to parse order book file: {}", e),
+                },
+                Err(e) => println!("⚠️ Failed to read order book file: {}", e),
+            }
+        } else {
+            println!("📁 Order book file not found");
+        }
+        None
+    }
+
+    pub fn save_to_file(&self, file_path: &str) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(file_path, contents)?;
+        println!("💾 Saved order books to file for {} markets", self.markets.len());
+        Ok(())
+    }
+
+    /// Splits a book that mixed every pair together, as written before
+    /// markets were tracked separately, into one book per pair. Entries keep
+    /// their relative time priority within each level.
+    fn from_single_book(order_book: OrderBook) -> Self {
+        let mut registry = Self::new();
+        for level in order_book.bids.into_values().chain(order_book.asks.into_values()) {
+            for entry in level {
+                let key = MarketKey::of(&entry.order);
+                registry.orders.insert(entry.order.id, key);
+                registry.book_mut(key).insert_entry(entry);
+            }
+        }
+        registry
+    }
+
+    pub fn book_mut(&mut self, key: MarketKey) -> &mut OrderBook {
+        self.markets.entry(key).or_default()
+    }
+
+    /// Routes an order to the book for its pair. See
+    /// [`OrderBook::add_order`] for `in_flight` and the post-only check.
+    pub fn add_order(
+        &mut self,
+        order: Order,
+        in_flight: U256,
+        spec: &MarketSpec,
+    ) -> (MarketKey, Option<PostOnlyNotice>) {
+        let key = MarketKey::of(&order);
+        self.orders.insert(order.id, key);
+        let notice = self.book_mut(key).add_order(order, in_flight, spec);
+        (key, notice)
+    }
+
+    /// Routes an amendment to the book for the order's pair. See
+    /// [`OrderBook::modify_order`].
+    pub fn modify_order(
+        &mut self,
+        order: Order,
+        in_flight: U256,
+        spec: &MarketSpec,
+    ) -> (MarketKey, Option<PostOnlyNotice>) {
+        let key = MarketKey::of(&order);
+        self.orders.insert(order.id, key);
+        let notice = self.book_mut(key).modify_order(order, in_flight, spec);
+        (key, notice)
+    }
+
+    /// Gives `amount` back to an order in the book for its pair. See
+    /// [`OrderBook::restore`].
+    pub fn restore(&mut self, order: &Order, amount: U256) {
+        let key = MarketKey::of(order);
+        self.orders.insert(order.id, key);
+        self.book_mut(key).restore(order, amount);
+    }
+
+    /// Sends an order to the back of its level in whichever market holds
+    /// it. See [`OrderBook::requeue`].
+    pub fn requeue(&mut self, order_id: u64, newest_id: u64) {
+        if let Some(book) = self.book_for_order(order_id) {
+            book.requeue(order_id, newest_id);
+        }
+    }
+
+    /// The order as it rests in whichever market holds it.
+    pub fn order(&self, order_id: u64) -> Option<&Order> {
+        let key = self.market_of(order_id)?;
+        self.markets.get(&key)?.entry(order_id).map(|entry| &entry.order)
+    }
+
+    /// The market an order rests in, if any.
+    pub fn market_of(&self, order_id: u64) -> Option<MarketKey> {
+        let key = *self.orders.get(&order_id)?;
+        self.markets.get(&key)?.contains(order_id).then_some(key)
+    }
+
+    /// Removes an order from whichever market it rests in.
+    pub fn remove_order(&mut self, order_id: u64) -> Option<(MarketKey, OrderBookEntry)> {
+        let key = self.orders.remove(&order_id)?;
+        Some((key, self.markets.get_mut(&key)?.remove_order(order_id)?))
+    }
+
+    /// The book holding `order_id`, if it rests in any market.
+    pub fn book_for_order(&mut self, order_id: u64) -> Option<&mut OrderBook> {
+        let key = self.market_of(order_id)?;
+        self.markets.get_mut(&key)
+    }
+
+    /// Runs the matching loop for a single market, with the
+    /// [`crate::config::MatchPolicy`] configured for it. Orders in other markets
+    /// are never considered.
+    pub fn match_market(
+        &mut self,
+        key: MarketKey,
+        max_matches: usize,
+        now: Option<u64>,
+        config: &ClobConfig,
+        guard: PriceGuard,
+        escrow: &mut EscrowLedger,
+    ) -> MatchOutcome {
+        let policy = config.match_policy(key);
+        match self.markets.get_mut(&key) {
+            Some(book) => book.match_orders(max_matches, now, policy, guard, escrow),
+            None => MatchOutcome::default(),
+        }
+    }
+
+    /// Runs the periodic auction for a single market. See
+    /// [`OrderBook::run_auction`].
+    pub fn auction_market(
+        &mut self,
+        key: MarketKey,
+        max_matches: usize,
+        now: u64,
+        config: &ClobConfig,
+        guard: PriceGuard,
+        escrow: &mut EscrowLedger,
+    ) -> MatchOutcome {
+        let interval = config.auction_interval_secs;
+        let policy = config.match_policy(key);
+        match self.markets.get_mut(&key) {
+            Some(book) => book.run_auction(max_matches, now, interval, policy, guard, escrow),
+            None => MatchOutcome::default(),
+        }
+    }
+
+    /// Uncrosses a single market in an opening or closing call auction. See
+    /// [`OrderBook::run_call_auction`].
+    pub fn call_auction_market(
+        &mut self,
+        key: MarketKey,
+        max_matches: usize,
+        now: Option<u64>,
+        config: &ClobConfig,
+        guard: PriceGuard,
+        escrow: &mut EscrowLedger,
+    ) -> MatchOutcome {
+        let policy = config.match_policy(key);
+        match self.markets.get_mut(&key) {
+            Some(book) => book.run_call_auction(max_matches, now, policy, guard, escrow),
+            None => MatchOutcome::default(),
+        }
+    }
+
+    /// The `(trader, token)` escrow accounts backing the orders resting in
+    /// `key`: the quote token for bids, the base token for asks.
+    pub fn escrow_accounts(&self, key: MarketKey) -> BTreeSet<(Address, Address)> {
+        self.markets.get(&key).map(OrderBook::escrow_accounts).unwrap_or_default()
+    }
+
+    /// Price band and circuit breaker for the next matching run in `key`.
+    /// See [`PriceGuard::new`].
+    pub fn price_guard(
+        &self,
+        key: MarketKey,
+        config: &ClobConfig,
+        oracle_price: Option<U256>,
+        now: Option<u64>,
+    ) -> PriceGuard {
+        let empty = PriceHistory::default();
+        let history = self.markets.get(&key).map_or(&empty, |book| &book.prices);
+        PriceGuard::new(config, history, oracle_price, now)
+    }
+
+    /// Price of the latest fill in `key`, matched by this operator or
+    /// settled on-chain by another. Stop orders trigger on it.
+    pub fn last_price(&self, key: MarketKey) -> Option<U256> {
+        self.markets.get(&key).and_then(|book| book.prices.last())
+    }
+
+    /// The phase `key` is in: the one the contract stores for it, or the
+    /// one a call auction finished in this run moved it to, otherwise the
+    /// one configured for it.
+    pub fn phase(&self, key: MarketKey, config: &ClobConfig) -> MarketPhase {
+        self.markets.get(&key).and_then(|book| book.phase).unwrap_or_else(|| config.phase_for(key))
+    }
+
+    pub fn set_phase(&mut self, key: MarketKey, phase: MarketPhase) {
+        self.book_mut(key).phase = Some(phase);
+    }
+
+    /// Takes the phase the contract stores for `key`; `None` if it never
+    /// moved the market, leaving it to the config.
+    pub fn load_phase(&mut self, key: MarketKey, phase: Option<MarketPhase>) {
+        match phase {
+            Some(phase) => self.set_phase(key, phase),
+            None => {
+                if let Some(book) = self.markets.get_mut(&key) {
+                    book.phase = None;
+                }
+            }
+        }
+    }
+
+    /// Removes good-till-date orders that have expired at block time `now`
+    /// from every market.
+    pub fn expire_orders(&mut self, now: u64) -> Vec<OrderCancellation> {
+        self.markets.values_mut().flat_map(|book| book.expire_orders(now)).collect()
+    }
+
+    /// Cancels the orders in `key` that cannot wait for it to trade again.
+    /// See [`OrderBook::cancel_immediate`].
+    pub fn cancel_immediate(
+        &mut self,
+        key: MarketKey,
+        keep_limit_orders: bool,
+    ) -> Vec<OrderCancellation> {
+        self.markets
+            .get_mut(&key)
+            .map(|book| book.cancel_immediate(keep_limit_orders))
+            .unwrap_or_default()
+    }
+
+    /// Canonical snapshot of every market with resting orders, pending
+    /// stops, a phase set on it or an auction under way, ordered by market
+    /// key. Other markets are left out so the snapshot only depends on that
+    /// state, not on which markets this operator has happened to see.
+    pub fn snapshot(&self, stops: &StopBook, config: &ClobConfig) -> Vec<MarketSnapshot> {
+        let keys: BTreeSet<MarketKey> =
+            self.markets.keys().chain(stops.markets.keys()).copied().collect();
+        keys.into_iter()
+            .filter_map(|key| {
+                let book = self.markets.get(&key);
+                let pending = stops.snapshot(key);
+                let tracked = book.is_some_and(|book| {
+                    !book.is_empty() || book.phase.is_some() || !book.auction.is_idle()
+                });
+                if !tracked && pending.is_empty() {
+                    return None;
+                }
+                let (bids, asks) = book.map(OrderBook::snapshot).unwrap_or_default();
+                let auction = book.map(|book| book.auction).unwrap_or_default();
+                Some(MarketSnapshot {
+                    baseToken: key.base_token,
+                    quoteToken: key.quote_token,
+                    phase: self.phase(key, config).to_sol(),
+                    auctionCutoff: auction.cutoff,
+                    unfinishedAuctionPrice: auction.unfinished_price.unwrap_or_default(),
+                    bids,
+                    asks,
+                    stops: pending,
+                })
+            })
+            .collect()
+    }
+
+    /// `keccak256(abi.encode(snapshot))`. Operators that processed the same
+    /// events hold the same root.
+    pub fn state_root(&self, stops: &StopBook, config: &ClobConfig) -> B256 {
+        keccak256(self.snapshot(stops, config).abi_encode())
+    }
+}
+
+/// Either the per-market registry or a single book from before markets were
+/// split out.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StateFile {
+    Markets(MarketRegistry),
+    SingleBook(OrderBook),
+}
+
+/// Serializes per-market state as a list of markets, since JSON object keys
+/// cannot hold the `(base_token, quote_token)` pair.
+pub(crate) mod market_list {
+    use super::MarketKey;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize)]
+    struct MarketRef<'a, T> {
+        #[serde(flatten)]
+        key: &'a MarketKey,
+        #[serde(flatten)]
+        book: &'a T,
+    }
+
+    #[derive(Deserialize)]
+    struct Market<T> {
+        #[serde(flatten)]
+        key: MarketKey,
+        #[serde(flatten)]
+        book: T,
+    }
+
+    pub fn serialize<S: Serializer, T: Serialize>(
+        markets: &BTreeMap<MarketKey, T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(markets.iter().map(|(key, book)| MarketRef { key, book }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<MarketKey, T>, D::Error> {
+        let markets = Vec::<Market<T>>::deserialize(deserializer)?;
+        Ok(markets.into_iter().map(|market| (market.key, market.book)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config::config;
+    use crate::trigger::test_orders::order;
+    use crate::trigger::{OrderStatus, OrderType};
+
+    const BUYER: Address = Address::repeat_byte(1);
+    const SELLER: Address = Address::repeat_byte(2);
+    const WETH: Address = Address::repeat_byte(0xee);
+    const WBTC: Address = Address::repeat_byte(0xbb);
+    const USDC: Address = Address::repeat_byte(0xcc);
+
+    #[test]
+    fn state_root_covers_stops_phase_auction_and_hidden_size() {
+        let config = config();
+        let root = |markets: &MarketRegistry, stops: &StopBook| markets.state_root(stops, &config);
+
+        // Two icebergs showing the same slice differ in what they hide
+        let iceberg = |amount| Order {
+            display_amount: U256::from(2),
+            ..order(2, BUYER, OrderType::Buy, 80, amount)
+        };
+        let spec = config.spec_for(MarketKey::of(&iceberg(10)));
+        let (mut deep, mut shallow) = (MarketRegistry::new(), MarketRegistry::new());
+        deep.add_order(iceberg(10), U256::ZERO, &spec);
+        shallow.add_order(iceberg(6), U256::ZERO, &spec);
+        assert_ne!(root(&deep, &StopBook::new()), root(&shallow, &StopBook::new()));
+
+        let mut markets = MarketRegistry::new();
+        let mut stops = StopBook::new();
+        let empty = root(&markets, &stops);
+        let stop = Order {
+            status: OrderStatus::PendingTrigger,
+            stop_price: U256::from(100),
+            ..order(1, SELLER, OrderType::Sell, 90, 1)
+        };
+        let market = stops.add_order(stop);
+        let with_stop = root(&markets, &stops);
+        assert_ne!(with_stop, empty);
+
+        markets.set_phase(market, MarketPhase::Halted);
+        let halted = root(&markets, &stops);
+        assert_ne!(halted, with_stop);
+
+        markets.book_mut(market).auction.cutoff = 60;
+        assert_ne!(root(&markets, &stops), halted);
+    }
+
+    #[test]
+    fn phase_comes_from_chain_not_from_the_saved_books() {
+        let config = config();
+        let mut markets = MarketRegistry::new();
+        let resting = order(1, BUYER, OrderType::Buy, 100, 5);
+        let market = MarketKey::of(&resting);
+        markets.restore(&resting, U256::from(5));
+        markets.set_phase(market, MarketPhase::Halted);
+
+        let mut loaded: MarketRegistry =
+            serde_json::from_value(serde_json::to_value(&markets).unwrap()).unwrap();
+        assert_eq!(loaded.phase(market, &config), config.phase_for(market));
+
+        loaded.load_phase(market, Some(MarketPhase::ClosingAuction));
+        assert_eq!(loaded.phase(market, &config), MarketPhase::ClosingAuction);
+        // A market the contract never moved trades as configured
+        loaded.load_phase(market, None);
+        assert_eq!(loaded.phase(market, &config), config.phase_for(market));
+    }
+
+    fn in_market(base_token: Address, order: Order) -> Order {
+        Order { base_token, quote_token: USDC, ..order }
+    }
+
+    #[test]
+    fn markets_never_match_across_pairs() {
+        let config = config();
+        let mut markets = MarketRegistry::new();
+        let buy = in_market(WETH, order(1, BUYER, OrderType::Buy, 100, 5));
+        let sell = in_market(WBTC, order(2, SELLER, OrderType::Sell, 90, 5));
+        let spec = config.spec_for(MarketKey::of(&buy));
+        let (weth, _) = markets.add_order(buy, U256::ZERO, &spec);
+        let (wbtc, _) = markets.add_order(sell, U256::ZERO, &spec);
+        assert_ne!(weth, wbtc);
+
+        for key in [weth, wbtc] {
+            let mut escrow = EscrowLedger::unchecked();
+            let outcome =
+                markets.match_market(key, 10, None, &config, PriceGuard::default(), &mut escrow);
+            assert!(outcome.fills.is_empty());
+        }
+        assert_eq!(markets.market_of(1), Some(weth));
+        assert_eq!(markets.market_of(2), Some(wbtc));
+    }
+
+    #[test]
+    fn markets_save_as_a_flat_list() {
+        let mut markets = MarketRegistry::new();
+        markets.restore(&in_market(WETH, order(1, BUYER, OrderType::Buy, 100, 5)), U256::from(5));
+        markets.restore(&in_market(WBTC, order(2, SELLER, OrderType::Sell, 90, 3)), U256::from(3));
+
+        let saved = serde_json::to_value(&markets).unwrap();
+        let list = saved["markets"].as_array().unwrap();
+        assert_eq!(list.len(), 2);
+        assert!(list.iter().all(|market| market["quote_token"] == serde_json::json!(USDC)));
+
+        let loaded: MarketRegistry = serde_json::from_value(saved).unwrap();
+        assert_eq!(loaded.market_of(1), Some(MarketKey::new(WETH, USDC)));
+        assert_eq!(loaded.market_of(2), Some(MarketKey::new(WBTC, USDC)));
+        assert_eq!(loaded.order(2).map(|order| order.amount), Some(U256::from(3)));
+    }
+
+    #[test]
+    fn single_book_file_splits_into_markets() {
+        // Before markets were split out the file held one book for every pair
+        let mut book = OrderBook::default();
+        for order in [
+            in_market(WETH, order(1, BUYER, OrderType::Buy, 100, 5)),
+            in_market(WETH, order(3, BUYER, OrderType::Buy, 100, 5)),
+            in_market(WBTC, order(2, SELLER, OrderType::Sell, 90, 5)),
+        ] {
+            book.insert_entry(OrderBookEntry::new(order, U256::from(5)));
+        }
+        let path = std::env::temp_dir().join(format!("clob-single-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, serde_json::to_string(&book).unwrap()).unwrap();
+        let markets = MarketRegistry::load_from_file(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let weth = MarketKey::new(WETH, USDC);
+        assert_eq!(markets.markets.len(), 2);
+        assert_eq!(markets.market_of(2), Some(MarketKey::new(WBTC, USDC)));
+        let queue: Vec<u64> = markets.markets[&weth].bids[&U256::from(100)]
+            .iter()
+            .map(|entry| entry.order.id)
+            .collect();
+        assert_eq!(queue, vec![1, 3]);
+    }
+
+    #[test]
+    fn removed_orders_leave_the_order_index() {
+        let mut markets = MarketRegistry::new();
+        markets.restore(&order(1, BUYER, OrderType::Buy, 100, 5), U256::from(5));
+        assert!(markets.orders.contains_key(&1));
+
+        let (key, entry) = markets.remove_order(1).unwrap();
+        assert_eq!(entry.remaining_amount, U256::from(5));
+        assert!(markets.markets[&key].is_empty());
+        assert!(!markets.orders.contains_key(&1));
+        assert!(markets.remove_order(1).is_none());
+    }
+}
@@ -0,0 +1,1756 @@
+use crate::allocation::Allocation;
+use crate::auction::{clearing_price, AuctionRound};
+use crate::config::{MatchPolicy, SelfTradePrevention};
+use crate::escrow::EscrowLedger;
+use crate::market::MarketPhase;
+use crate::price_guard::{PriceBand, PriceCheck, PriceGuard, PriceHistory};
+use crate::solidity::{PriceLevelSnapshot, RestingOrder};
+use crate::spec::MarketSpec;
+use crate::trade_price::TradePrice;
+use crate::trigger::{
+    CancelReason, CircuitBreak, Fill, MatchOutcome, MatchResult, Order, OrderBookEntry,
+    OrderCancellation, OrderKind, OrderReprice, OrderType, PostOnly, PostOnlyNotice,
+    PriceBandRejection, SelfTrade, TimeInForce,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use wavs_wasi_utils::evm::alloy_primitives::{Address, U256};
+
+/// Orders resting at a single price, in time priority (ascending
+/// [`OrderBookEntry::queue_key`], i.e. order id unless an iceberg requeued).
+pub type PriceLevel = Vec<OrderBookEntry>;
+
+/// Price levels ordered numerically by price.
+pub type PriceLadder = BTreeMap<U256, PriceLevel>;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "PriceLadders")]
+pub struct OrderBook {
+    #[serde(with = "price_ladder")]
+    pub bids: PriceLadder,
+    #[serde(with = "price_ladder")]
+    pub asks: PriceLadder,
+    /// Order id -> (side, price level), so an order can be found without
+    /// scanning every level. Rebuilt from the ladders on load.
+    #[serde(skip)]
+    index: BTreeMap<u64, (OrderType, U256)>,
+    /// Orders that may not rest: market, IOC and FOK orders. They only stay
+    /// while the book is crossed, i.e. when a batch limit cut matching short.
+    #[serde(skip)]
+    immediate_orders: BTreeSet<u64>,
+    #[serde(default, skip_serializing_if = "AuctionRound::is_idle")]
+    pub auction: AuctionRound,
+    /// Phase the contract stores for the market, read from chain every
+    /// run, or the one a call auction finished in this run moved it to.
+    /// `None` leaves the phase to the component config.
+    #[serde(skip)]
+    pub phase: Option<MarketPhase>,
+    /// Recent fill prices, for the price band and circuit breaker.
+    #[serde(default, skip_serializing_if = "PriceHistory::is_empty")]
+    pub prices: PriceHistory,
+}
+
+/// The persisted part of an [`OrderBook`].
+#[derive(Deserialize)]
+struct PriceLadders {
+    // `buy_orders`/`sell_orders` are the field names used by the original
+    // string-keyed file format, kept as aliases so old files still load.
+    #[serde(alias = "buy_orders", with = "price_ladder")]
+    bids: PriceLadder,
+    #[serde(alias = "sell_orders", with = "price_ladder")]
+    asks: PriceLadder,
+    #[serde(default)]
+    auction: AuctionRound,
+    #[serde(default)]
+    prices: PriceHistory,
+}
+
+impl From<PriceLadders> for OrderBook {
+    fn from(ladders: PriceLadders) -> Self {
+        let mut book = Self { auction: ladders.auction, prices: ladders.prices, ..Self::default() };
+        for entry in ladders.bids.into_values().chain(ladders.asks.into_values()).flatten() {
+            book.insert_entry(entry);
+        }
+        book
+    }
+}
+
+impl OrderBook {
+    /// Highest resting buy price.
+    pub fn best_bid(&self) -> Option<U256> {
+        self.bids.keys().next_back().copied()
+    }
+
+    /// Lowest resting sell price.
+    pub fn best_ask(&self) -> Option<U256> {
+        self.asks.keys().next().copied()
+    }
+
+    /// Adds an order to its price level. `in_flight` is the
+    /// amount already taken by fills proposed but not yet settled on-chain,
+    /// which `filled_amount` does not reflect yet. Orders already in the book
+    /// are left untouched, and so are orders with nothing left to fill.
+    ///
+    /// A post-only order that would cross is rejected or slid to one tick of
+    /// `spec` behind the best opposite price, as its flag asks, before it
+    /// can take part in matching. A triggered stop order arrives behind
+    /// every order already in the book.
+    pub fn add_order(
+        &mut self,
+        order: Order,
+        in_flight: U256,
+        spec: &MarketSpec,
+    ) -> Option<PostOnlyNotice> {
+        self.admit(order, in_flight, spec, false)
+    }
+
+    /// Applies an amendment to an order, given as the order with its new
+    /// price and amount. A smaller amount at the same price keeps the
+    /// order's place in the queue. A new price or a larger amount sends it
+    /// to the back of its level, and makes it the newest order in the book
+    /// for taker purposes, after the same post-only check a new order gets.
+    /// `in_flight` is as for [`Self::add_order`]. The lost priority is only
+    /// kept here, not on chain.
+    pub fn modify_order(
+        &mut self,
+        order: Order,
+        in_flight: U256,
+        spec: &MarketSpec,
+    ) -> Option<PostOnlyNotice> {
+        let remaining = (order.amount - order.filled_amount).saturating_sub(in_flight);
+        let exhausted = order.is_exhausted(remaining, spec.price_decimals);
+        if let Some(entry) = self.entry_mut(order.id) {
+            if order.price == entry.order.price && remaining <= entry.remaining_amount {
+                let order_id = order.id;
+                entry.order = order;
+                if exhausted {
+                    self.remove_order(order_id);
+                } else {
+                    self.set_remaining(order_id, remaining);
+                }
+                return None;
+            }
+        }
+        self.remove_order(order.id);
+        self.admit(order, in_flight, spec, true)
+    }
+
+    /// Adds an order that is not in the book yet. A `requeued` order takes
+    /// its place in the queue and its arrival behind every order in the
+    /// book.
+    fn admit(
+        &mut self,
+        mut order: Order,
+        in_flight: U256,
+        spec: &MarketSpec,
+        requeued: bool,
+    ) -> Option<PostOnlyNotice> {
+        if self.contains(order.id) {
+            return None;
+        }
+
+        let remaining = (order.amount - order.filled_amount).saturating_sub(in_flight);
+        if order.is_exhausted(remaining, spec.price_decimals) {
+            return None;
+        }
+
+        let mut notice = None;
+        if order.post_only != PostOnly::Off {
+            match self.passive_price(&order, spec.tick_size) {
+                Some(price) if price == order.price => {}
+                Some(price) if order.post_only == PostOnly::Slide => {
+                    notice = Some(PostOnlyNotice::Repriced(OrderReprice {
+                        order_id: order.id,
+                        old_price: order.price,
+                        new_price: price,
+                    }));
+                    order.price = price;
+                }
+                _ => {
+                    return Some(PostOnlyNotice::Rejected(OrderCancellation {
+                        order_id: order.id,
+                        amount: remaining,
+                        reason: CancelReason::PostOnlyRejected,
+                    }))
+                }
+            }
+        }
+
+        let mut entry = OrderBookEntry::new(order, remaining);
+        if requeued {
+            let arrival = self.next_arrival(entry.order.id);
+            entry.arrived_at = Some(arrival);
+            entry.requeued_at = Some(arrival);
+        } else if !entry.order.stop_price.is_zero() {
+            entry.arrived_at = Some(self.next_arrival(entry.order.id));
+        }
+        self.insert_entry(entry);
+        notice
+    }
+
+    /// Sends a resting order to the back of its level, as an amendment that
+    /// cost it its priority does, when `newest_id` was the newest order
+    /// placed. Replayed in the order the amendments happened, this puts
+    /// orders rebuilt from the chain back where the amendments left them.
+    pub fn requeue(&mut self, order_id: u64, newest_id: u64) {
+        let Some(mut entry) = self.remove_order(order_id) else {
+            return;
+        };
+        let arrival = self.arrival_after(newest_id.max(order_id));
+        entry.arrived_at = Some(arrival);
+        entry.requeued_at = Some(arrival);
+        self.insert_entry(entry);
+    }
+
+    /// Arrival for an order entering the book now rather than when it was
+    /// placed: behind the newest order in the book and anything that
+    /// arrived or was requeued late after it. Orders placed from now on have
+    /// higher ids and still arrive later.
+    fn next_arrival(&self, order_id: u64) -> (u64, u32) {
+        let newest_id = self.index.keys().next_back().copied().unwrap_or_default();
+        self.arrival_after(newest_id.max(order_id))
+    }
+
+    /// Arrival behind order `watermark` and anything that arrived or was
+    /// requeued late after it.
+    fn arrival_after(&self, watermark: u64) -> (u64, u32) {
+        let sequence = self
+            .bids
+            .values()
+            .chain(self.asks.values())
+            .flatten()
+            .flat_map(|entry| [entry.arrival(), entry.queue_key()])
+            .filter(|(id, _)| *id == watermark)
+            .map(|(_, sequence)| sequence + 1)
+            .max()
+            .unwrap_or(1);
+        (watermark, sequence)
+    }
+
+    /// The price at which `order` rests without crossing: its own price if
+    /// that is already passive, otherwise one tick behind the best opposite
+    /// price. `None` if no positive price is passive.
+    fn passive_price(&self, order: &Order, tick_size: U256) -> Option<U256> {
+        match order.order_type {
+            OrderType::Buy => match self.best_ask() {
+                Some(ask) if order.price >= ask => {
+                    ask.checked_sub(tick_size).filter(|price| !price.is_zero())
+                }
+                _ => Some(order.price),
+            },
+            OrderType::Sell => match self.best_bid() {
+                Some(bid) if order.price <= bid => bid.checked_add(tick_size),
+                _ => Some(order.price),
+            },
+        }
+    }
+
+    /// Inserts an entry into its price level by queue key. Order ids follow
+    /// the contract's placement order, so every operator ends up with the
+    /// same queue no matter which event delivered an order first.
+    pub fn insert_entry(&mut self, entry: OrderBookEntry) {
+        let side = entry.order.order_type;
+        let price = entry.order.price;
+        self.index.insert(entry.order.id, (side, price));
+        if entry.order.is_immediate() {
+            self.immediate_orders.insert(entry.order.id);
+        }
+        let level = self.ladder_mut(side).entry(price).or_default();
+        let position = level.partition_point(|e| e.queue_key() < entry.queue_key());
+        level.insert(position, entry);
+    }
+
+    pub fn contains(&self, order_id: u64) -> bool {
+        self.index.contains_key(&order_id)
+    }
+
+    /// Ids of every resting order, ascending.
+    pub fn order_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.index.keys().copied()
+    }
+
+    /// Removes a resting order, returning its entry if it was in the book.
+    pub fn remove_order(&mut self, order_id: u64) -> Option<OrderBookEntry> {
+        let (side, price) = self.forget(order_id)?;
+        let ladder = self.ladder_mut(side);
+        let level = ladder.get_mut(&price)?;
+        let position = level.iter().position(|entry| entry.order.id == order_id)?;
+        let entry = level.remove(position);
+        if level.is_empty() {
+            ladder.remove(&price);
+        }
+        Some(entry)
+    }
+
+    pub fn entry(&self, order_id: u64) -> Option<&OrderBookEntry> {
+        let (side, price) = self.index.get(&order_id)?;
+        self.ladder(*side).get(price)?.iter().find(|entry| entry.order.id == order_id)
+    }
+
+    pub fn entry_mut(&mut self, order_id: u64) -> Option<&mut OrderBookEntry> {
+        let (side, price) = *self.index.get(&order_id)?;
+        self.ladder_mut(side).get_mut(&price)?.iter_mut().find(|entry| entry.order.id == order_id)
+    }
+
+    /// Sets the amount still open for a resting order, removing it once
+    /// nothing is left. An iceberg never displays more than it has left.
+    pub fn set_remaining(&mut self, order_id: u64, remaining: U256) {
+        if remaining == U256::ZERO {
+            self.remove_order(order_id);
+        } else if let Some(entry) = self.entry_mut(order_id) {
+            entry.remaining_amount = remaining;
+            if let Some(visible) = &mut entry.visible_amount {
+                *visible = (*visible).min(remaining);
+            }
+        }
+    }
+
+    /// Takes a fill of `amount` base units at `price`, at most what the
+    /// entry has available, out of a resting order. Returns what it took in
+    /// the order's own units. A filled order, or a quote-denominated one
+    /// whose quote has run out, leaves the book; an iceberg whose displayed
+    /// slice ran out shows a new slice from its hidden remainder at the back
+    /// of its price level.
+    fn consume(&mut self, order_id: u64, amount: U256, price: U256, price_decimals: u8) -> U256 {
+        let Some(entry) = self.entry_mut(order_id) else {
+            return U256::ZERO;
+        };
+        let units = entry.order.fill_units(amount, price, price_decimals);
+        entry.remaining_amount -= units;
+        if let Some(visible) = &mut entry.visible_amount {
+            *visible -= units;
+        }
+        let filled = entry.order.is_exhausted(entry.remaining_amount, price_decimals);
+        let slice_done = entry.visible_amount == Some(U256::ZERO);
+
+        if filled {
+            self.remove_order(order_id);
+        } else if slice_done {
+            self.replenish(order_id);
+        }
+        units
+    }
+
+    /// Moves an iceberg to the back of its level with a fresh displayed
+    /// slice. The queue key sorts after every entry in the level and before
+    /// any order placed later, whose id exceeds every id in the book now.
+    fn replenish(&mut self, order_id: u64) {
+        let Some(mut entry) = self.remove_order(order_id) else {
+            return;
+        };
+        let level = self.ladder(entry.order.order_type).get(&entry.order.price);
+        let newest_id = self.index.keys().next_back().copied().unwrap_or_default().max(order_id);
+        let watermark = level
+            .into_iter()
+            .flatten()
+            .map(|e| e.queue_key().0)
+            .max()
+            .unwrap_or_default()
+            .max(newest_id);
+        let sequence = level
+            .into_iter()
+            .flatten()
+            .map(OrderBookEntry::queue_key)
+            .filter(|(id, _)| *id == watermark)
+            .map(|(_, sequence)| sequence + 1)
+            .max()
+            .unwrap_or(1);
+
+        entry.visible_amount = Some(entry.order.display_amount.min(entry.remaining_amount));
+        entry.requeued_at = Some((watermark, sequence));
+        println!(
+            "🧊 Iceberg order {} replenished {} at the back of price level {}",
+            order_id,
+            entry.available(),
+            entry.order.price
+        );
+        self.insert_entry(entry);
+    }
+
+    /// Gives `amount` back to an order, e.g. when a fill it took part in is
+    /// rolled back. An order no longer in the book is re-inserted at its
+    /// original queue position.
+    pub fn restore(&mut self, order: &Order, amount: U256) {
+        match self.entry_mut(order.id) {
+            Some(entry) => entry.remaining_amount += amount,
+            None => self.insert_entry(OrderBookEntry::new(order.clone(), amount)),
+        }
+    }
+
+    /// Canonical form of the book: levels best price first, orders in queue
+    /// order, with only the fields that affect matching.
+    pub fn snapshot(&self) -> (Vec<PriceLevelSnapshot>, Vec<PriceLevelSnapshot>) {
+        let level_snapshot = |(price, level): (&U256, &PriceLevel)| PriceLevelSnapshot {
+            price: *price,
+            orders: level
+                .iter()
+                .map(|entry| RestingOrder {
+                    orderId: U256::from(entry.order.id),
+                    trader: entry.order.trader,
+                    remainingAmount: entry.available(),
+                    hiddenAmount: entry.remaining_amount - entry.available(),
+                })
+                .collect(),
+        };
+        (
+            self.bids.iter().rev().map(level_snapshot).collect(),
+            self.asks.iter().map(level_snapshot).collect(),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+
+    /// Drops an order from the lookup indexes, returning where it rested.
+    fn forget(&mut self, order_id: u64) -> Option<(OrderType, U256)> {
+        self.immediate_orders.remove(&order_id);
+        self.index.remove(&order_id)
+    }
+
+    fn ladder(&self, side: OrderType) -> &PriceLadder {
+        match side {
+            OrderType::Buy => &self.bids,
+            OrderType::Sell => &self.asks,
+        }
+    }
+
+    fn ladder_mut(&mut self, side: OrderType) -> &mut PriceLadder {
+        match side {
+            OrderType::Buy => &mut self.bids,
+            OrderType::Sell => &mut self.asks,
+        }
+    }
+
+    /// Removes good-till-date orders that have expired at block time `now`.
+    pub fn expire_orders(&mut self, now: u64) -> Vec<OrderCancellation> {
+        let expired: Vec<u64> = self
+            .bids
+            .values()
+            .chain(self.asks.values())
+            .flatten()
+            .filter(|entry| entry.order.is_expired(now))
+            .map(|entry| entry.order.id)
+            .collect();
+        self.cancel(expired, |_| CancelReason::Expired)
+    }
+
+    /// Cancels market, IOC and FOK orders without matching them, for a
+    /// market that is not trading. With `keep_limit_orders`, as before an
+    /// opening call auction, those with a limit price wait to take part in
+    /// it instead.
+    pub fn cancel_immediate(&mut self, keep_limit_orders: bool) -> Vec<OrderCancellation> {
+        let cancelled: Vec<u64> = self
+            .immediate_orders
+            .iter()
+            .filter_map(|id| self.entry(*id))
+            .filter(|entry| !keep_limit_orders || entry.order.kind == OrderKind::Market)
+            .map(|entry| entry.order.id)
+            .collect();
+        self.cancel(cancelled, unfilled_reason)
+    }
+
+    /// Runs a matching pass: expired orders are dropped first (when the block
+    /// time is known), then fill-or-kill orders that cannot complete within
+    /// this batch are killed, then the book is matched. Anything left to
+    /// match once `max_matches` is hit stays in the book for the next run.
+    /// Otherwise market and IOC orders still in the book have run out of
+    /// liquidity and are cancelled. Pairs are handled and priced as `policy`
+    /// says. Every match price is checked by `guard` and every fill must be
+    /// backed by `escrow`.
+    pub fn match_orders(
+        &mut self,
+        max_matches: usize,
+        now: Option<u64>,
+        policy: MatchPolicy,
+        guard: PriceGuard,
+        escrow: &mut EscrowLedger,
+    ) -> MatchOutcome {
+        let mut cancellations = match now {
+            Some(now) => self.expire_orders(now),
+            None => Vec::new(),
+        };
+        cancellations.extend(self.kill_unfillable(max_matches, policy, guard, escrow));
+
+        let mut outcome = self.match_loop(max_matches, policy, guard, escrow);
+        self.record_prints(now, &outcome.fills, guard.history_secs);
+        cancellations.append(&mut outcome.cancellations);
+        outcome.cancellations = cancellations;
+
+        if !outcome.carried_over {
+            let unfilled: Vec<u64> = self.immediate_orders.iter().copied().collect();
+            outcome.cancellations.extend(self.cancel(unfilled, unfilled_reason));
+        }
+
+        outcome
+    }
+
+    /// Runs the periodic auction once block time `now` has passed the next
+    /// multiple of `interval`. Orders placed before that cutoff take part;
+    /// see [`Self::clear_at`]. An auction the batch limit cut short is
+    /// finished first, at its original price.
+    pub fn run_auction(
+        &mut self,
+        max_matches: usize,
+        now: u64,
+        interval: u64,
+        policy: MatchPolicy,
+        guard: PriceGuard,
+        escrow: &mut EscrowLedger,
+    ) -> MatchOutcome {
+        let mut cancellations = self.expire_orders(now);
+
+        let (cutoff, price) = match self.auction.unfinished_price {
+            Some(price) => (self.auction.cutoff, Some(price)),
+            None => {
+                let cutoff = now - now % interval;
+                if cutoff <= self.auction.cutoff {
+                    return MatchOutcome { cancellations, ..MatchOutcome::default() };
+                }
+                (cutoff, self.clearing_price(cutoff, policy.price_decimals))
+            }
+        };
+
+        let mut outcome = self.clear_at(cutoff, price, max_matches, policy, &guard, escrow);
+        self.auction.cutoff = cutoff;
+        self.record_prints(Some(now), &outcome.fills, guard.history_secs);
+        cancellations.append(&mut outcome.cancellations);
+        outcome.cancellations = cancellations;
+        outcome
+    }
+
+    /// Uncrosses the whole book at a single price, as the opening and
+    /// closing call auctions do. Every order in the book takes part; see
+    /// [`Self::clear_at`].
+    pub fn run_call_auction(
+        &mut self,
+        max_matches: usize,
+        now: Option<u64>,
+        policy: MatchPolicy,
+        guard: PriceGuard,
+        escrow: &mut EscrowLedger,
+    ) -> MatchOutcome {
+        let mut cancellations = match now {
+            Some(now) => self.expire_orders(now),
+            None => Vec::new(),
+        };
+
+        let price = self
+            .auction
+            .unfinished_price
+            .or_else(|| self.clearing_price(u64::MAX, policy.price_decimals));
+        let mut outcome = self.clear_at(u64::MAX, price, max_matches, policy, &guard, escrow);
+        self.record_prints(now, &outcome.fills, guard.history_secs);
+        cancellations.append(&mut outcome.cancellations);
+        outcome.cancellations = cancellations;
+        outcome
+    }
+
+    /// Fills the orders placed before `cutoff` whose limit reaches `price`
+    /// at that one price, in price-time priority; every other order sits
+    /// the auction out. If the batch limit cuts the auction short the price
+    /// is kept for the next run, otherwise market, IOC and FOK orders that
+    /// took part and did not fill are cancelled. A price `guard` refuses
+    /// clears nothing and trips the circuit breaker, leaving every order in
+    /// the book.
+    fn clear_at(
+        &mut self,
+        cutoff: u64,
+        price: Option<U256>,
+        max_matches: usize,
+        policy: MatchPolicy,
+        guard: &PriceGuard,
+        escrow: &mut EscrowLedger,
+    ) -> MatchOutcome {
+        if let Some(price) = price {
+            let reference = match guard.check(price) {
+                PriceCheck::Allowed => None,
+                PriceCheck::OutsideBand(band) => Some(band.reference),
+                PriceCheck::Breaker { reference } => Some(reference),
+            };
+            if let Some(reference) = reference {
+                println!("🚧 Auction price {} is too far from {}, not clearing", price, reference);
+                self.auction.unfinished_price = None;
+                return MatchOutcome {
+                    breaker: Some(CircuitBreak { price, reference }),
+                    carried_over: true,
+                    ..MatchOutcome::default()
+                };
+            }
+        }
+
+        let mut outcome = match price {
+            Some(price) => {
+                println!("🔨 Auction closing at {} clears at price {}", cutoff, price);
+                // Orders that sit this auction out are set aside so matching
+                // only sees orders that all cross each other
+                let sitting_out: Vec<u64> = self
+                    .bids
+                    .values()
+                    .chain(self.asks.values())
+                    .flatten()
+                    .filter(|entry| {
+                        entry.order.timestamp >= cutoff
+                            || match entry.order.order_type {
+                                OrderType::Buy => entry.order.price < price,
+                                OrderType::Sell => entry.order.price > price,
+                            }
+                    })
+                    .map(|entry| entry.order.id)
+                    .collect();
+                let parked: Vec<OrderBookEntry> =
+                    sitting_out.into_iter().filter_map(|id| self.remove_order(id)).collect();
+
+                // The clearing price was checked above, the pairs' own
+                // prices do not matter
+                let unguarded = PriceGuard::default();
+                let fifo = MatchPolicy {
+                    allocation: Allocation::Fifo,
+                    trade_price: TradePrice::Fixed(price),
+                    ..policy
+                };
+                let killed = self.kill_unfillable(max_matches, fifo, unguarded, escrow);
+                let mut outcome = self.match_loop(max_matches, fifo, unguarded, escrow);
+                outcome.cancellations.splice(0..0, killed);
+                for entry in parked {
+                    self.insert_entry(entry);
+                }
+                outcome
+            }
+            None => MatchOutcome::default(),
+        };
+
+        self.auction.unfinished_price = price.filter(|_| outcome.carried_over);
+        if !outcome.carried_over {
+            let unfilled: Vec<u64> = self
+                .immediate_orders
+                .iter()
+                .filter(|id| self.entry(**id).is_some_and(|entry| entry.order.timestamp < cutoff))
+                .copied()
+                .collect();
+            outcome.cancellations.extend(self.cancel(unfilled, unfilled_reason));
+        }
+
+        outcome
+    }
+
+    /// Adds the prices of `fills` matched at block time `now` to the price
+    /// history. Without a block time they are dated with the latest print,
+    /// so the last trade price, which stop orders trigger on, still follows
+    /// them.
+    fn record_prints(&mut self, now: Option<u64>, fills: &[Fill], keep_secs: u64) {
+        for fill in fills {
+            let at = now.unwrap_or_else(|| self.prices.latest_at());
+            self.prices.record(at, fill.result.match_price, keep_secs);
+        }
+    }
+
+    /// Clearing price for the orders placed before `cutoff`, if any cross.
+    /// A quote-denominated buy counts what it pays for at its limit price.
+    fn clearing_price(&self, cutoff: u64, price_decimals: u8) -> Option<U256> {
+        let participants = |ladder: &PriceLadder| -> Vec<(U256, U256)> {
+            ladder
+                .values()
+                .flatten()
+                .filter(|entry| entry.order.timestamp < cutoff)
+                .map(|entry| {
+                    (entry.order.price, entry.available_at(entry.order.price, price_decimals))
+                })
+                .collect()
+        };
+        clearing_price(&participants(&self.bids), &participants(&self.asks))
+            .map(|clearing| clearing.price)
+    }
+
+    /// Kills every fill-or-kill order that a dry run of this batch would not
+    /// fill completely. Killing one order can change what the others get,
+    /// so the check repeats until a pass kills nothing; every surviving FOK
+    /// order is then filled in full by the real run on the same book. The
+    /// dry run only copies the crossed levels, and is skipped once no FOK
+    /// order is left.
+    fn kill_unfillable(
+        &mut self,
+        max_matches: usize,
+        policy: MatchPolicy,
+        guard: PriceGuard,
+        escrow: &EscrowLedger,
+    ) -> Vec<OrderCancellation> {
+        let mut killed = Vec::new();
+        loop {
+            let fok_orders: Vec<(u64, U256)> = self
+                .immediate_orders
+                .iter()
+                .filter_map(|id| self.entry(*id))
+                .filter(|entry| entry.order.time_in_force == TimeInForce::Fok)
+                .map(|entry| (entry.order.id, entry.remaining_amount))
+                .collect();
+            if fok_orders.is_empty() {
+                return killed;
+            }
+            let dry_run =
+                self.crossed_levels().match_loop(max_matches, policy, guard, &mut escrow.clone());
+            let unfillable: Vec<u64> = fok_orders
+                .into_iter()
+                .filter(|(id, remaining)| {
+                    let filled: U256 = dry_run
+                        .fills
+                        .iter()
+                        .map(|fill| fill.units_of(*id, policy.price_decimals))
+                        .sum();
+                    filled < *remaining
+                })
+                .map(|(id, _)| id)
+                .collect();
+            if unfillable.is_empty() {
+                return killed;
+            }
+            killed.extend(self.cancel(unfillable, |_| CancelReason::FokKilled));
+        }
+    }
+
+    /// A copy of the levels matching can reach: bids at or above the best
+    /// ask and asks at or below the best bid. Matching only takes orders
+    /// out, so no other level ever crosses, and orders that cross nothing
+    /// do not move the clearing price either.
+    fn crossed_levels(&self) -> OrderBook {
+        let mut crossed = OrderBook::default();
+        if let (Some(bid), Some(ask)) = (self.best_bid(), self.best_ask()) {
+            let levels = self.bids.range(ask..).chain(self.asks.range(..=bid));
+            for entry in levels.flat_map(|(_, level)| level) {
+                crossed.insert_entry(entry.clone());
+            }
+        }
+        crossed
+    }
+
+    /// Takes orders out of the book, reporting their remaining amounts for
+    /// the contract to refund.
+    fn cancel(
+        &mut self,
+        order_ids: Vec<u64>,
+        reason: impl Fn(&Order) -> CancelReason,
+    ) -> Vec<OrderCancellation> {
+        order_ids
+            .into_iter()
+            .filter_map(|id| self.remove_order(id))
+            .map(|entry| OrderCancellation {
+                order_id: entry.order.id,
+                amount: entry.remaining_amount,
+                reason: reason(&entry.order),
+            })
+            .collect()
+    }
+
+    /// Every bid/ask pair that crosses, in matching priority: bids best
+    /// first, and for each bid the asks it crosses best first.
+    fn crossing_pairs(&self) -> impl Iterator<Item = (&OrderBookEntry, &OrderBookEntry)> {
+        self.bids.iter().rev().flat_map(|(_, level)| level).flat_map(move |bid| {
+            self.asks
+                .range(..=bid.order.price)
+                .flat_map(|(_, level)| level)
+                .map(move |ask| (bid, ask))
+        })
+    }
+
+    /// The next pair to match or to apply self-trade prevention to. Under
+    /// [`SelfTradePrevention::Skip`] pairs from the same trader are passed
+    /// over, and recorded in `self_trades` the first time.
+    fn next_pair(
+        &self,
+        policy: MatchPolicy,
+        self_trades: &mut Vec<SelfTrade>,
+    ) -> Option<(OrderBookEntry, OrderBookEntry)> {
+        let MatchPolicy { stp, price_decimals, .. } = policy;
+        let mut pairs = self.crossing_pairs();
+        let (bid, ask) = match stp {
+            SelfTradePrevention::Skip => pairs.find(|(bid, ask)| {
+                if bid.order.trader != ask.order.trader {
+                    return true;
+                }
+                let self_trade = SelfTrade::between(bid, ask, stp, price_decimals);
+                let seen = self_trades.iter().any(|t| {
+                    t.resting_order_id == self_trade.resting_order_id
+                        && t.incoming_order_id == self_trade.incoming_order_id
+                });
+                if !seen {
+                    self_trades.push(self_trade);
+                }
+                false
+            })?,
+            _ => pairs.next()?,
+        };
+        Some((bid.clone(), ask.clone()))
+    }
+
+    /// Repeatedly matches the best pair of crossing orders until none is
+    /// left or `max_matches` fills have been produced. Pairs from a single
+    /// trader are handled by the policy's `stp` instead of being matched.
+    /// Under FIFO the pair trades directly; other allocations share the
+    /// order that arrived last across the other one's price level. Each pair
+    /// trades at the price the policy's `trade_price` picks. A pair priced
+    /// outside the band is refused, and a price that trips the circuit
+    /// breaker stops matching altogether. Fills are cut to what `escrow`
+    /// backs; orders it no longer backs sit out the rest of the run and keep
+    /// their place in the queue, as do orders too small to cost a quote buy
+    /// anything.
+    fn match_loop(
+        &mut self,
+        max_matches: usize,
+        policy: MatchPolicy,
+        mut guard: PriceGuard,
+        escrow: &mut EscrowLedger,
+    ) -> MatchOutcome {
+        let MatchPolicy { allocation, trade_price, price_decimals, .. } = policy;
+        let mut outcome = MatchOutcome::default();
+        let mut unbacked = Vec::new();
+        let uniform = match trade_price {
+            TradePrice::Auction => self.clearing_price(u64::MAX, price_decimals),
+            _ => None,
+        };
+
+        while outcome.fills.len() < max_matches {
+            let Some((buy, sell)) = self.next_pair(policy, &mut outcome.self_trades) else {
+                break;
+            };
+
+            if buy.order.trader == sell.order.trader {
+                self.prevent_self_trade(&buy, &sell, policy, &mut outcome);
+                continue;
+            }
+
+            // The order that arrived last took the liquidity
+            let taker_side =
+                if buy.arrival() > sell.arrival() { OrderType::Buy } else { OrderType::Sell };
+            let price = trade_price.price(&buy.order, &sell.order, taker_side, uniform);
+            match guard.check(price) {
+                PriceCheck::Allowed => guard.record(price),
+                PriceCheck::OutsideBand(band) => {
+                    self.reject_outside_band(&buy, &sell, price, band, &mut outcome);
+                    continue;
+                }
+                PriceCheck::Breaker { reference } => {
+                    outcome.breaker = Some(CircuitBreak { price, reference });
+                    break;
+                }
+            }
+
+            if allocation != Allocation::Fifo {
+                let (incoming, resting) =
+                    if taker_side == OrderType::Buy { (&buy, &sell) } else { (&sell, &buy) };
+                let budget = max_matches - outcome.fills.len();
+                let (fills, short) =
+                    self.allocate_level(incoming, resting, price, policy, budget, escrow);
+                let progressed = !fills.is_empty() || !short.is_empty();
+                outcome.fills.extend(fills);
+                self.set_aside(short, &mut unbacked);
+                if progressed {
+                    continue;
+                }
+                // Every share would have cost a quote buy nothing; the pair
+                // trades on its own instead
+            }
+
+            let buy_amount = buy.available_at(price, price_decimals);
+            let sell_amount = sell.available_at(price, price_decimals);
+            let match_amount = buy_amount.min(sell_amount);
+            if buy.order.is_free_fill(match_amount, price, price_decimals) {
+                // The smaller side is too small to be paid for at this price
+                let small = if buy_amount <= sell_amount { &buy } else { &sell };
+                if let Some(entry) = self.remove_order(small.order.id) {
+                    println!(
+                        "🪙 Order {} is too small to trade at {} this run",
+                        small.order.id, price
+                    );
+                    unbacked.push(entry);
+                }
+                continue;
+            }
+            let (match_amount, short) =
+                escrow.reserve(&buy.order, &sell.order, match_amount, price);
+            if match_amount.is_zero() {
+                self.set_aside(short, &mut unbacked);
+                continue;
+            }
+
+            outcome.fills.push(Fill {
+                result: MatchResult {
+                    buy_order_id: U256::from(buy.order.id),
+                    sell_order_id: U256::from(sell.order.id),
+                    match_amount,
+                    match_price: price,
+                    taker_side,
+                    buyer_fee: U256::ZERO,
+                    seller_fee: U256::ZERO,
+                },
+                buy_order: buy.order.clone(),
+                sell_order: sell.order.clone(),
+            });
+
+            // Filled orders drop out of the book along with empty levels,
+            // exhausted iceberg slices replenish
+            self.consume(buy.order.id, match_amount, price, price_decimals);
+            self.consume(sell.order.id, match_amount, price, price_decimals);
+            self.set_aside(short, &mut unbacked);
+        }
+
+        outcome.carried_over = self.next_pair(policy, &mut Vec::new()).is_some();
+        for entry in unbacked {
+            self.insert_entry(entry);
+        }
+        outcome
+    }
+
+    /// Matches the order of a crossing pair that arrived last against the
+    /// resting order's price level at `price`, split by the policy's
+    /// allocation. Only orders that arrived before the incoming one and are
+    /// owned by other traders take part, so the resting order of the pair
+    /// always gets a share. Every order in the level has the resting order's
+    /// limit, so `price` suits them all. At most `budget` fills are produced,
+    /// in queue order; the rest of the level stays crossed for later.
+    /// Returns the fills and the orders `escrow` no longer backs; the
+    /// allocation ends early if the incoming one is among them.
+    fn allocate_level(
+        &mut self,
+        incoming: &OrderBookEntry,
+        resting: &OrderBookEntry,
+        price: U256,
+        policy: MatchPolicy,
+        budget: usize,
+        escrow: &mut EscrowLedger,
+    ) -> (Vec<Fill>, Vec<u64>) {
+        let price_decimals = policy.price_decimals;
+        let level: Vec<(u64, U256)> = self
+            .ladder(resting.order.order_type)
+            .get(&resting.order.price)
+            .into_iter()
+            .flatten()
+            .filter(|entry| {
+                entry.arrival() < incoming.arrival() && entry.order.trader != incoming.order.trader
+            })
+            .map(|entry| (entry.order.id, entry.available_at(price, price_decimals)))
+            .collect();
+
+        let quantity = incoming.available_at(price, price_decimals);
+        let mut allocations = policy.allocation.allocate(quantity, &level);
+        allocations.truncate(budget);
+        let mut fills = Vec::new();
+        let mut unbacked = Vec::new();
+        for (order_id, match_amount) in allocations {
+            let Some(counterparty) = self.entry(order_id).map(|entry| entry.order.clone()) else {
+                continue;
+            };
+            let (buy_order, sell_order) = match incoming.order.order_type {
+                OrderType::Buy => (incoming.order.clone(), counterparty),
+                OrderType::Sell => (counterparty, incoming.order.clone()),
+            };
+            let (match_amount, short) =
+                escrow.reserve(&buy_order, &sell_order, match_amount, price);
+            if !match_amount.is_zero() {
+                fills.push(Fill {
+                    result: MatchResult {
+                        buy_order_id: U256::from(buy_order.id),
+                        sell_order_id: U256::from(sell_order.id),
+                        match_amount,
+                        match_price: price,
+                        taker_side: incoming.order.order_type,
+                        buyer_fee: U256::ZERO,
+                        seller_fee: U256::ZERO,
+                    },
+                    buy_order,
+                    sell_order,
+                });
+                self.consume(order_id, match_amount, price, price_decimals);
+                self.consume(incoming.order.id, match_amount, price, price_decimals);
+            }
+            unbacked.extend(short);
+            if unbacked.contains(&incoming.order.id) {
+                break;
+            }
+        }
+        (fills, unbacked)
+    }
+
+    /// Takes orders whose trader does not have the escrow to back them out
+    /// of matching, so a fill they cannot pay for does not fail the whole
+    /// batch on-chain. The entries are put back once matching is done.
+    fn set_aside(&mut self, order_ids: Vec<u64>, unbacked: &mut Vec<OrderBookEntry>) {
+        for id in order_ids {
+            if let Some(entry) = self.remove_order(id) {
+                println!("💸 Escrow does not back order {}, skipping it this run", id);
+                unbacked.push(entry);
+            }
+        }
+    }
+
+    /// The `(trader, token)` escrow accounts backing the resting orders.
+    pub fn escrow_accounts(&self) -> BTreeSet<(Address, Address)> {
+        let bids = self.bids.values().flatten().map(|e| (e.order.trader, e.order.quote_token));
+        let asks = self.asks.values().flatten().map(|e| (e.order.trader, e.order.base_token));
+        bids.chain(asks).collect()
+    }
+
+    /// Refuses a pair whose `price` is outside `band` by cancelling the
+    /// newer order of the two, the one whose arrival made the pair cross.
+    fn reject_outside_band(
+        &mut self,
+        buy: &OrderBookEntry,
+        sell: &OrderBookEntry,
+        price: U256,
+        band: PriceBand,
+        outcome: &mut MatchOutcome,
+    ) {
+        let newer = if buy.arrival() > sell.arrival() { buy.order.id } else { sell.order.id };
+        println!(
+            "🚧 Refusing match of buy {} and sell {} at {}, outside band {}..={}; cancelling order {}",
+            buy.order.id, sell.order.id, price, band.lower, band.upper, newer
+        );
+        outcome.cancellations.extend(self.cancel(vec![newer], |_| CancelReason::PriceBand));
+        outcome.band_rejections.push(PriceBandRejection {
+            buy_order_id: buy.order.id,
+            sell_order_id: sell.order.id,
+            price,
+            lower: band.lower,
+            upper: band.upper,
+        });
+    }
+
+    /// Applies `stp` to a crossing pair from the same trader. Every policy
+    /// here takes at least one of the two orders out of the book.
+    fn prevent_self_trade(
+        &mut self,
+        buy: &OrderBookEntry,
+        sell: &OrderBookEntry,
+        policy: MatchPolicy,
+        outcome: &mut MatchOutcome,
+    ) {
+        let MatchPolicy { stp, price_decimals, .. } = policy;
+        let self_trade = SelfTrade::between(buy, sell, stp, price_decimals);
+        match stp {
+            SelfTradePrevention::CancelResting => outcome.cancellations.extend(
+                self.cancel(vec![self_trade.resting_order_id], |_| CancelReason::SelfTrade),
+            ),
+            SelfTradePrevention::CancelIncoming => outcome.cancellations.extend(
+                self.cancel(vec![self_trade.incoming_order_id], |_| CancelReason::SelfTrade),
+            ),
+            SelfTradePrevention::DecrementBoth => {
+                // Both sides give up the same base amount, valued at the
+                // buy's limit
+                for entry in [buy, sell] {
+                    let amount = self.consume(
+                        entry.order.id,
+                        self_trade.amount,
+                        buy.order.price,
+                        price_decimals,
+                    );
+                    outcome.cancellations.push(OrderCancellation {
+                        order_id: entry.order.id,
+                        amount,
+                        reason: CancelReason::SelfTrade,
+                    });
+                }
+            }
+            SelfTradePrevention::Skip => unreachable!("skipped pairs are never selected"),
+        }
+        outcome.self_trades.push(self_trade);
+    }
+}
+
+/// Why an immediate order still in the book after matching is cancelled.
+fn unfilled_reason(order: &Order) -> CancelReason {
+    match (order.kind, order.time_in_force) {
+        (OrderKind::Market, _) => CancelReason::MarketUnfilled,
+        (_, TimeInForce::Fok) => CancelReason::FokKilled,
+        _ => CancelReason::IocUnfilled,
+    }
+}
+
+/// Serializes a [`PriceLadder`] as a JSON object keyed by the decimal price.
+///
+/// This matches the original `price.to_string()` keys, so files written
+/// before the ladder was keyed by `U256` deserialize unchanged.
+mod price_ladder {
+    use super::{PriceLadder, PriceLevel};
+    use serde::de::Error;
+    use serde::ser::SerializeMap;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::BTreeMap;
+    use wavs_wasi_utils::evm::alloy_primitives::U256;
+
+    pub fn serialize<S: Serializer>(
+        ladder: &PriceLadder,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(ladder.len()))?;
+        for (price, level) in ladder {
+            map.serialize_entry(&price.to_string(), level)?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<PriceLadder, D::Error> {
+        let raw = BTreeMap::<String, PriceLevel>::deserialize(deserializer)?;
+        let mut ladder = PriceLadder::new();
+        for (key, level) in raw {
+            let price = parse_price(&key).map_err(D::Error::custom)?;
+            ladder.entry(price).or_default().extend(level);
+        }
+        Ok(ladder)
+    }
+
+    fn parse_price(key: &str) -> Result<U256, String> {
+        let parsed = match key.strip_prefix("0x") {
+            Some(hex) => U256::from_str_radix(hex, 16),
+            None => U256::from_str_radix(key, 10),
+        };
+        parsed.map_err(|e| format!("invalid price level key {key:?}: {e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{test_config, ClobConfig};
+    use crate::market::MarketRegistry;
+    use crate::trigger::test_orders::order;
+    use crate::trigger::Denomination;
+
+    const BUYER: Address = Address::repeat_byte(1);
+    const SELLER: Address = Address::repeat_byte(2);
+
+    /// A market without size rules, with prices in hundredths.
+    fn spec() -> MarketSpec {
+        MarketSpec {
+            tick_size: U256::from(1),
+            lot_size: U256::from(1),
+            min_amount: U256::ZERO,
+            max_amount: None,
+            min_notional: U256::ZERO,
+            price_decimals: 2,
+        }
+    }
+
+    fn policy() -> MatchPolicy {
+        MatchPolicy {
+            stp: SelfTradePrevention::CancelResting,
+            allocation: Allocation::Fifo,
+            trade_price: TradePrice::Maker,
+            price_decimals: spec().price_decimals,
+        }
+    }
+
+    fn quote_buy(id: u64, price: u64, quote: u64) -> Order {
+        Order {
+            denomination: Denomination::Quote,
+            ..order(id, BUYER, OrderType::Buy, price, quote)
+        }
+    }
+
+    fn run(book: &mut OrderBook) -> MatchOutcome {
+        book.match_orders(
+            100,
+            None,
+            policy(),
+            PriceGuard::default(),
+            &mut EscrowLedger::unchecked(),
+        )
+    }
+
+    fn fill_ids(outcome: &MatchOutcome) -> Vec<(u64, u64)> {
+        outcome.fills.iter().map(|f| (f.buy_order.id, f.sell_order.id)).collect()
+    }
+
+    #[test]
+    fn levels_match_in_price_then_time_priority() {
+        // As strings "1000" sorts before "999"; the ladder orders by value
+        let mut book = OrderBook::default();
+        for bid in [
+            order(1, BUYER, OrderType::Buy, 999, 1),
+            order(2, BUYER, OrderType::Buy, 1_000, 1),
+            order(3, BUYER, OrderType::Buy, 1_000, 1),
+        ] {
+            book.add_order(bid, U256::ZERO, &spec());
+        }
+        assert_eq!(book.best_bid(), Some(U256::from(1_000)));
+
+        book.add_order(order(4, SELLER, OrderType::Sell, 900, 3), U256::ZERO, &spec());
+        assert_eq!(fill_ids(&run(&mut book)), vec![(2, 4), (3, 4), (1, 4)]);
+    }
+
+    #[test]
+    fn legacy_string_keyed_book_loads_by_price() {
+        let entry =
+            |order| serde_json::to_value(OrderBookEntry::new(order, U256::from(1))).unwrap();
+        let legacy = serde_json::json!({
+            "buy_orders": {
+                "999": [entry(order(1, BUYER, OrderType::Buy, 999, 1))],
+                "1000": [entry(order(2, BUYER, OrderType::Buy, 1_000, 1))],
+            },
+            "sell_orders": {
+                "1001": [entry(order(3, SELLER, OrderType::Sell, 1_001, 1))],
+            },
+        });
+        let book: OrderBook = serde_json::from_value(legacy).unwrap();
+        assert_eq!(book.best_bid(), Some(U256::from(1_000)));
+        assert_eq!(book.best_ask(), Some(U256::from(1_001)));
+        // The order index is rebuilt, so orders can be found by id again
+        assert!(book.contains(1) && book.contains(2) && book.contains(3));
+    }
+
+    #[test]
+    fn saved_books_load_with_every_order_field() {
+        let resting = Order {
+            kind: OrderKind::Market,
+            denomination: Denomination::Quote,
+            time_in_force: TimeInForce::Gtd,
+            expires_at: 1_700,
+            post_only: PostOnly::Slide,
+            stop_price: U256::from(95),
+            display_amount: U256::from(2),
+            ..order(1, BUYER, OrderType::Buy, 100, 10)
+        };
+        let mut markets = MarketRegistry::new();
+        markets.restore(&resting, U256::from(10));
+
+        let path = std::env::temp_dir().join(format!("clob-books-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        markets.save_to_file(path).unwrap();
+        let loaded = MarketRegistry::load_from_file(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let key = loaded.market_of(1).unwrap();
+        let entry = loaded.markets[&key].entry(1).unwrap();
+        assert_eq!(entry.remaining_amount, U256::from(10));
+        assert_eq!(entry.visible_amount, Some(U256::from(2)));
+        let order = &entry.order;
+        assert_eq!(order.kind, OrderKind::Market);
+        assert_eq!(order.denomination, Denomination::Quote);
+        assert_eq!(order.time_in_force, TimeInForce::Gtd);
+        assert_eq!(order.expires_at, 1_700);
+        assert_eq!(order.post_only, PostOnly::Slide);
+        assert_eq!(order.stop_price, U256::from(95));
+        assert_eq!(order.display_amount, U256::from(2));
+    }
+
+    #[test]
+    fn batch_cap_carries_matching_over_to_the_next_run() {
+        let mut book = OrderBook::default();
+        for id in 1..=3 {
+            book.add_order(order(id, SELLER, OrderType::Sell, 100, 1), U256::ZERO, &spec());
+        }
+        // An IOC order cut short by the cap is not cancelled; it keeps
+        // matching in the next run
+        let ioc =
+            Order { time_in_force: TimeInForce::Ioc, ..order(4, BUYER, OrderType::Buy, 100, 3) };
+        book.add_order(ioc, U256::ZERO, &spec());
+
+        let mut escrow = EscrowLedger::unchecked();
+        let first = book.match_orders(2, None, policy(), PriceGuard::default(), &mut escrow);
+        assert_eq!(fill_ids(&first), vec![(4, 1), (4, 2)]);
+        assert!(first.carried_over);
+        assert!(first.cancellations.is_empty());
+        assert!(book.contains(4));
+
+        let second = book.match_orders(2, None, policy(), PriceGuard::default(), &mut escrow);
+        assert_eq!(fill_ids(&second), vec![(4, 3)]);
+        assert!(!second.carried_over);
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn batch_cap_cuts_a_level_allocation_in_queue_order() {
+        let mut book = OrderBook::default();
+        for id in 1..=3 {
+            book.add_order(order(id, SELLER, OrderType::Sell, 100, 2), U256::ZERO, &spec());
+        }
+        book.add_order(order(4, BUYER, OrderType::Buy, 100, 6), U256::ZERO, &spec());
+
+        // Pro-rata gives each sell its 2, but only the first two fit
+        let pro_rata = MatchPolicy { allocation: Allocation::ProRata, ..policy() };
+        let mut escrow = EscrowLedger::unchecked();
+        let first = book.match_orders(2, None, pro_rata, PriceGuard::default(), &mut escrow);
+        assert_eq!(fill_ids(&first), vec![(4, 1), (4, 2)]);
+        assert!(first.carried_over);
+
+        let second = book.match_orders(2, None, pro_rata, PriceGuard::default(), &mut escrow);
+        assert_eq!(fill_ids(&second), vec![(4, 3)]);
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn fills_without_block_time_still_set_the_last_price() {
+        let mut book = OrderBook::default();
+        book.add_order(order(1, SELLER, OrderType::Sell, 100, 1), U256::ZERO, &spec());
+        book.add_order(order(2, BUYER, OrderType::Buy, 100, 1), U256::ZERO, &spec());
+        run(&mut book);
+        assert_eq!(book.prices.last(), Some(U256::from(100)));
+    }
+
+    #[test]
+    fn immediate_orders_do_not_wait_for_a_closed_market() {
+        let mut book = OrderBook::default();
+        let market = Order { kind: OrderKind::Market, ..order(1, BUYER, OrderType::Buy, 50, 10) };
+        let ioc =
+            Order { time_in_force: TimeInForce::Ioc, ..order(2, BUYER, OrderType::Buy, 40, 10) };
+        for order in [market, ioc, order(3, SELLER, OrderType::Sell, 60, 10)] {
+            book.add_order(order, U256::ZERO, &spec());
+        }
+
+        // Before an opening auction the IOC limit order waits to join it
+        let cancelled = book.cancel_immediate(true);
+        assert_eq!(cancelled.len(), 1);
+        assert_eq!(cancelled[0].order_id, 1);
+        assert_eq!(cancelled[0].reason, CancelReason::MarketUnfilled);
+
+        let cancelled = book.cancel_immediate(false);
+        assert_eq!(cancelled.len(), 1);
+        assert_eq!(cancelled[0].order_id, 2);
+        assert_eq!(cancelled[0].reason, CancelReason::IocUnfilled);
+        assert!(book.contains(3));
+    }
+
+    /// Rests sells 1 and 2 of 2 units each at 100 and applies `amendment`.
+    fn amended(amendment: Order) -> OrderBook {
+        let mut book = OrderBook::default();
+        for id in [1, 2] {
+            book.add_order(order(id, SELLER, OrderType::Sell, 100, 2), U256::ZERO, &spec());
+        }
+        assert!(book.modify_order(amendment, U256::ZERO, &spec()).is_none());
+        book
+    }
+
+    /// Buys one unit at 100 and returns the sell order it matched.
+    fn first_in_queue(book: &mut OrderBook) -> u64 {
+        book.add_order(order(3, BUYER, OrderType::Buy, 100, 1), U256::ZERO, &spec());
+        run(book).fills[0].sell_order.id
+    }
+
+    #[test]
+    fn smaller_amount_keeps_queue_priority() {
+        let mut book = amended(order(1, SELLER, OrderType::Sell, 100, 1));
+        assert_eq!(first_in_queue(&mut book), 1);
+    }
+
+    #[test]
+    fn new_price_loses_queue_priority() {
+        // Moved away and back, order 1 queues behind order 2
+        let mut book = amended(order(1, SELLER, OrderType::Sell, 101, 2));
+        book.modify_order(order(1, SELLER, OrderType::Sell, 100, 2), U256::ZERO, &spec());
+        assert_eq!(first_in_queue(&mut book), 2);
+    }
+
+    #[test]
+    fn larger_amount_loses_queue_priority() {
+        let mut book = amended(order(1, SELLER, OrderType::Sell, 100, 3));
+        assert_eq!(first_in_queue(&mut book), 2);
+    }
+
+    #[test]
+    fn requeued_amendment_gets_the_post_only_check() {
+        let mut book = OrderBook::default();
+        book.add_order(order(1, BUYER, OrderType::Buy, 100, 1), U256::ZERO, &spec());
+        let sell = |price| Order {
+            post_only: PostOnly::Slide,
+            ..order(2, SELLER, OrderType::Sell, price, 1)
+        };
+        book.add_order(sell(105), U256::ZERO, &spec());
+
+        // Amended to cross the bid, it slides one tick behind it instead
+        let notice = book.modify_order(sell(100), U256::ZERO, &spec());
+        let Some(PostOnlyNotice::Repriced(reprice)) = notice else {
+            panic!("expected a reprice, got {notice:?}");
+        };
+        assert_eq!(reprice.new_price, U256::from(101));
+        assert_eq!(book.best_ask(), Some(U256::from(101)));
+        assert!(run(&mut book).fills.is_empty());
+    }
+
+    #[test]
+    fn quote_buy_spends_its_quote_and_leaves() {
+        let mut book = OrderBook::default();
+        book.add_order(order(1, SELLER, OrderType::Sell, 40, 1_000), U256::ZERO, &spec());
+        book.add_order(quote_buy(2, 40, 101), U256::ZERO, &spec());
+        let outcome = run(&mut book);
+        // 1.01 quote buys 252 units at 0.40 for 1.00; the 0.01 left cannot
+        // buy anything
+        let amounts: Vec<U256> = outcome.fills.iter().map(|f| f.result.match_amount).collect();
+        assert_eq!(amounts, vec![U256::from(252)]);
+        assert!(!book.contains(2));
+    }
+
+    #[test]
+    fn quote_buy_never_takes_base_for_free() {
+        // A single quote unit pays for 2 units at 0.40, which cost 0.80 and
+        // so round down to nothing
+        let mut book = OrderBook::default();
+        book.add_order(order(1, SELLER, OrderType::Sell, 40, 1_000), U256::ZERO, &spec());
+        book.add_order(quote_buy(2, 40, 1), U256::ZERO, &spec());
+        assert!(run(&mut book).fills.is_empty());
+        assert!(!book.contains(2));
+
+        // At its own limit of 1.00 it could buy a unit, but it trades at the
+        // resting 0.40 and sits the run out instead of taking 2 for free
+        book.add_order(quote_buy(3, 100, 1), U256::ZERO, &spec());
+        assert!(run(&mut book).fills.is_empty());
+        assert!(book.contains(3));
+    }
+
+    #[test]
+    fn market_order_sweeps_levels_up_to_its_slippage_cap() {
+        let mut book = OrderBook::default();
+        for (id, price) in [(1, 100), (2, 101), (3, 103)] {
+            book.add_order(order(id, SELLER, OrderType::Sell, price, 2), U256::ZERO, &spec());
+        }
+        // Its price of 1.02 is the worst it accepts
+        let market = Order { kind: OrderKind::Market, ..order(4, BUYER, OrderType::Buy, 102, 10) };
+        book.add_order(market, U256::ZERO, &spec());
+
+        let outcome = run(&mut book);
+        assert_eq!(fill_ids(&outcome), vec![(4, 1), (4, 2)]);
+        assert_eq!(outcome.cancellations.len(), 1);
+        assert_eq!(outcome.cancellations[0].order_id, 4);
+        assert_eq!(outcome.cancellations[0].amount, U256::from(6));
+        assert_eq!(outcome.cancellations[0].reason, CancelReason::MarketUnfilled);
+        assert!(!book.contains(4));
+        assert!(book.contains(3));
+    }
+
+    #[test]
+    fn quote_market_buy_runs_out_of_quote_mid_level() {
+        let mut book = OrderBook::default();
+        for (id, price) in [(1, 100), (2, 100), (3, 101)] {
+            book.add_order(order(id, SELLER, OrderType::Sell, price, 5), U256::ZERO, &spec());
+        }
+        // 0.07 quote pays for 7 units at 1.00, so the second sell is only
+        // partly taken and the next level is never reached
+        let market = Order { kind: OrderKind::Market, ..quote_buy(4, 101, 7) };
+        book.add_order(market, U256::ZERO, &spec());
+
+        let outcome = run(&mut book);
+        let fills: Vec<(u64, U256)> =
+            outcome.fills.iter().map(|f| (f.sell_order.id, f.result.match_amount)).collect();
+        assert_eq!(fills, vec![(1, U256::from(5)), (2, U256::from(2))]);
+        assert!(outcome.cancellations.is_empty());
+        assert!(!book.contains(4));
+        assert_eq!(book.entry(2).unwrap().remaining_amount, U256::from(3));
+        assert_eq!(book.entry(3).unwrap().remaining_amount, U256::from(5));
+    }
+
+    #[test]
+    fn fok_order_that_cannot_fill_in_full_is_killed() {
+        let mut book = OrderBook::default();
+        book.add_order(order(1, SELLER, OrderType::Sell, 100, 2), U256::ZERO, &spec());
+        book.add_order(order(2, SELLER, OrderType::Sell, 110, 5), U256::ZERO, &spec());
+        let fok = |id, amount| Order {
+            time_in_force: TimeInForce::Fok,
+            ..order(id, BUYER, OrderType::Buy, 100, amount)
+        };
+
+        // Only 2 are offered within its limit; nothing trades
+        book.add_order(fok(3, 3), U256::ZERO, &spec());
+        let outcome = run(&mut book);
+        assert!(outcome.fills.is_empty());
+        assert_eq!(outcome.cancellations.len(), 1);
+        assert_eq!(outcome.cancellations[0].order_id, 3);
+        assert_eq!(outcome.cancellations[0].amount, U256::from(3));
+        assert_eq!(outcome.cancellations[0].reason, CancelReason::FokKilled);
+        assert_eq!(book.entry(1).unwrap().remaining_amount, U256::from(2));
+
+        book.add_order(fok(4, 2), U256::ZERO, &spec());
+        let outcome = run(&mut book);
+        assert_eq!(fill_ids(&outcome), vec![(4, 1)]);
+        assert!(outcome.cancellations.is_empty());
+    }
+
+    #[test]
+    fn expired_gtd_orders_leave_before_matching() {
+        let mut book = OrderBook::default();
+        let gtd = Order {
+            time_in_force: TimeInForce::Gtd,
+            expires_at: 1_000,
+            ..order(1, SELLER, OrderType::Sell, 100, 1)
+        };
+        book.add_order(gtd, U256::ZERO, &spec());
+        book.add_order(order(2, SELLER, OrderType::Sell, 101, 1), U256::ZERO, &spec());
+        book.add_order(order(3, BUYER, OrderType::Buy, 101, 1), U256::ZERO, &spec());
+
+        let mut escrow = EscrowLedger::unchecked();
+        let outcome =
+            book.match_orders(100, Some(1_000), policy(), PriceGuard::default(), &mut escrow);
+        assert_eq!(fill_ids(&outcome), vec![(3, 2)]);
+        assert_eq!(outcome.cancellations.len(), 1);
+        assert_eq!(outcome.cancellations[0].order_id, 1);
+        assert_eq!(outcome.cancellations[0].reason, CancelReason::Expired);
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn crossing_post_only_reject_is_cancelled_without_matching() {
+        let mut book = OrderBook::default();
+        book.add_order(order(1, SELLER, OrderType::Sell, 100, 2), U256::ZERO, &spec());
+        let buy = Order { post_only: PostOnly::Reject, ..order(2, BUYER, OrderType::Buy, 100, 3) };
+
+        let notice = book.add_order(buy, U256::ZERO, &spec());
+        let Some(PostOnlyNotice::Rejected(cancellation)) = notice else {
+            panic!("expected a rejection, got {notice:?}");
+        };
+        assert_eq!(cancellation.order_id, 2);
+        assert_eq!(cancellation.amount, U256::from(3));
+        assert_eq!(cancellation.reason, CancelReason::PostOnlyRejected);
+        assert!(!book.contains(2));
+        assert!(run(&mut book).fills.is_empty());
+        assert_eq!(book.entry(1).unwrap().remaining_amount, U256::from(2));
+    }
+
+    /// The buyer's sell 1 rests ahead of the seller's sell 2, both 5 at 1.00,
+    /// when the buyer sends a buy of 3 at 1.00 under `stp`.
+    fn self_trade(stp: SelfTradePrevention) -> (OrderBook, MatchOutcome) {
+        let mut book = OrderBook::default();
+        book.add_order(order(1, BUYER, OrderType::Sell, 100, 5), U256::ZERO, &spec());
+        book.add_order(order(2, SELLER, OrderType::Sell, 100, 5), U256::ZERO, &spec());
+        book.add_order(order(3, BUYER, OrderType::Buy, 100, 3), U256::ZERO, &spec());
+        let policy = MatchPolicy { stp, ..policy() };
+        let outcome = book.match_orders(
+            100,
+            None,
+            policy,
+            PriceGuard::default(),
+            &mut EscrowLedger::unchecked(),
+        );
+        (book, outcome)
+    }
+
+    fn cancelled(outcome: &MatchOutcome) -> Vec<(u64, U256, CancelReason)> {
+        outcome.cancellations.iter().map(|c| (c.order_id, c.amount, c.reason)).collect()
+    }
+
+    /// The report entries as `(resting, incoming, amount, action)`.
+    fn reported(outcome: &MatchOutcome) -> Vec<(u64, u64, U256, SelfTradePrevention)> {
+        outcome
+            .self_trades
+            .iter()
+            .map(|t| (t.resting_order_id, t.incoming_order_id, t.amount, t.action))
+            .collect()
+    }
+
+    #[test]
+    fn cancel_resting_lets_the_incoming_order_trade_on() {
+        let stp = SelfTradePrevention::CancelResting;
+        let (book, outcome) = self_trade(stp);
+        assert_eq!(cancelled(&outcome), vec![(1, U256::from(5), CancelReason::SelfTrade)]);
+        assert_eq!(fill_ids(&outcome), vec![(3, 2)]);
+        assert_eq!(reported(&outcome), vec![(1, 3, U256::from(3), stp)]);
+        assert!(!book.contains(1));
+    }
+
+    #[test]
+    fn cancel_incoming_keeps_the_resting_order() {
+        let stp = SelfTradePrevention::CancelIncoming;
+        let (book, outcome) = self_trade(stp);
+        assert_eq!(cancelled(&outcome), vec![(3, U256::from(3), CancelReason::SelfTrade)]);
+        assert!(outcome.fills.is_empty());
+        assert_eq!(reported(&outcome), vec![(1, 3, U256::from(3), stp)]);
+        assert_eq!(book.entry(1).unwrap().remaining_amount, U256::from(5));
+    }
+
+    #[test]
+    fn decrement_both_takes_the_crossing_amount_off_each() {
+        let stp = SelfTradePrevention::DecrementBoth;
+        let (book, outcome) = self_trade(stp);
+        assert_eq!(
+            cancelled(&outcome),
+            vec![
+                (3, U256::from(3), CancelReason::SelfTrade),
+                (1, U256::from(3), CancelReason::SelfTrade)
+            ]
+        );
+        assert!(outcome.fills.is_empty());
+        assert_eq!(reported(&outcome), vec![(1, 3, U256::from(3), stp)]);
+        assert!(!book.contains(3));
+        assert_eq!(book.entry(1).unwrap().remaining_amount, U256::from(2));
+    }
+
+    #[test]
+    fn skip_passes_over_own_orders_on_every_level() {
+        let stp = SelfTradePrevention::Skip;
+        let mut book = OrderBook::default();
+        book.add_order(order(1, BUYER, OrderType::Sell, 100, 5), U256::ZERO, &spec());
+        book.add_order(order(2, BUYER, OrderType::Sell, 101, 5), U256::ZERO, &spec());
+        book.add_order(order(3, SELLER, OrderType::Sell, 102, 1), U256::ZERO, &spec());
+        book.add_order(order(4, SELLER, OrderType::Sell, 102, 5), U256::ZERO, &spec());
+        book.add_order(order(5, BUYER, OrderType::Buy, 102, 3), U256::ZERO, &spec());
+
+        let policy = MatchPolicy { stp, ..policy() };
+        let outcome = book.match_orders(
+            100,
+            None,
+            policy,
+            PriceGuard::default(),
+            &mut EscrowLedger::unchecked(),
+        );
+        assert_eq!(fill_ids(&outcome), vec![(5, 3), (5, 4)]);
+        assert!(outcome.cancellations.is_empty());
+        // Each skipped pair is reported once, however often it is passed
+        assert_eq!(
+            reported(&outcome),
+            vec![(1, 5, U256::from(3), stp), (2, 5, U256::from(3), stp)]
+        );
+        assert_eq!(book.entry(1).unwrap().remaining_amount, U256::from(5));
+        assert_eq!(book.entry(2).unwrap().remaining_amount, U256::from(5));
+        assert_eq!(book.entry(4).unwrap().remaining_amount, U256::from(3));
+    }
+
+    #[test]
+    fn replenished_iceberg_slice_queues_at_the_back() {
+        let mut book = OrderBook::default();
+        let iceberg =
+            Order { display_amount: U256::from(2), ..order(1, SELLER, OrderType::Sell, 100, 5) };
+        book.add_order(iceberg, U256::ZERO, &spec());
+        book.add_order(order(2, SELLER, OrderType::Sell, 100, 5), U256::ZERO, &spec());
+
+        // Taking the displayed 2 sends order 1 behind order 2
+        book.add_order(order(3, BUYER, OrderType::Buy, 100, 2), U256::ZERO, &spec());
+        assert_eq!(fill_ids(&run(&mut book)), vec![(3, 1)]);
+        assert_eq!(book.entry(1).unwrap().visible_amount, Some(U256::from(2)));
+        book.add_order(order(4, BUYER, OrderType::Buy, 100, 5), U256::ZERO, &spec());
+        assert_eq!(fill_ids(&run(&mut book)), vec![(4, 2)]);
+
+        // Its last slice only shows what is left
+        book.add_order(order(5, BUYER, OrderType::Buy, 100, 2), U256::ZERO, &spec());
+        run(&mut book);
+        let entry = book.entry(1).unwrap();
+        assert_eq!(entry.remaining_amount, U256::from(1));
+        assert_eq!(entry.visible_amount, Some(U256::from(1)));
+    }
+
+    fn placed(at: u64, order: Order) -> Order {
+        Order { timestamp: at, ..order }
+    }
+
+    fn auction(book: &mut OrderBook, now: u64) -> MatchOutcome {
+        let mut escrow = EscrowLedger::unchecked();
+        book.run_auction(100, now, 60, policy(), PriceGuard::default(), &mut escrow)
+    }
+
+    #[test]
+    fn auction_takes_orders_placed_before_the_last_interval_boundary() {
+        let mut book = OrderBook::default();
+        for order in [
+            placed(100, order(1, BUYER, OrderType::Buy, 100, 1)),
+            placed(110, order(2, SELLER, OrderType::Sell, 100, 1)),
+            placed(125, order(3, SELLER, OrderType::Sell, 100, 1)),
+            placed(125, order(4, BUYER, OrderType::Buy, 100, 1)),
+        ] {
+            book.add_order(order, U256::ZERO, &spec());
+        }
+
+        // At 130 the auction closing at 120 runs, without the later orders
+        assert_eq!(fill_ids(&auction(&mut book, 130)), vec![(1, 2)]);
+        assert_eq!(book.auction.cutoff, 120);
+        assert!(book.contains(3) && book.contains(4));
+
+        // Nothing runs until the next boundary has passed
+        assert!(auction(&mut book, 179).fills.is_empty());
+        assert_eq!(fill_ids(&auction(&mut book, 185)), vec![(4, 3)]);
+        assert_eq!(book.auction.cutoff, 180);
+    }
+
+    #[test]
+    fn orders_sitting_out_an_auction_keep_resting() {
+        let mut book = OrderBook::default();
+        for order in [
+            order(1, BUYER, OrderType::Buy, 105, 5),
+            order(2, BUYER, OrderType::Buy, 90, 1),
+            order(3, SELLER, OrderType::Sell, 100, 3),
+            // Crosses, but arrives after the auction closed
+            placed(125, order(4, SELLER, OrderType::Sell, 95, 2)),
+        ] {
+            book.add_order(order, U256::ZERO, &spec());
+        }
+
+        let outcome = auction(&mut book, 130);
+        assert_eq!(fill_ids(&outcome), vec![(1, 3)]);
+        assert_eq!(outcome.fills[0].result.match_amount, U256::from(3));
+        assert!(outcome.cancellations.is_empty());
+        assert_eq!(book.entry(1).unwrap().remaining_amount, U256::from(2));
+        assert!(book.contains(2) && book.contains(4));
+    }
+
+    #[test]
+    fn call_auction_cut_short_finishes_at_its_price() {
+        let mut book = OrderBook::default();
+        for order in [
+            order(1, BUYER, OrderType::Buy, 100, 1),
+            order(2, BUYER, OrderType::Buy, 100, 1),
+            order(3, SELLER, OrderType::Sell, 100, 2),
+        ] {
+            book.add_order(order, U256::ZERO, &spec());
+        }
+        let mut escrow = EscrowLedger::unchecked();
+
+        let first = book.run_call_auction(1, None, policy(), PriceGuard::default(), &mut escrow);
+        assert_eq!(fill_ids(&first), vec![(1, 3)]);
+        assert!(first.carried_over);
+        assert_eq!(book.auction.unfinished_price, Some(U256::from(100)));
+
+        // A new auction would now clear at 0.90; the cut one still clears
+        // at 1.00
+        book.add_order(order(4, SELLER, OrderType::Sell, 90, 5), U256::ZERO, &spec());
+        let second = book.run_call_auction(1, None, policy(), PriceGuard::default(), &mut escrow);
+        assert_eq!(fill_ids(&second), vec![(2, 4)]);
+        assert_eq!(second.fills[0].result.match_price, U256::from(100));
+        assert!(!second.carried_over);
+        assert_eq!(book.auction.unfinished_price, None);
+    }
+
+    /// A guard for a market that last traded at 1.00.
+    fn guard(config: ClobConfig) -> PriceGuard {
+        let mut history = PriceHistory::default();
+        history.record(0, U256::from(100), config.price_history_secs());
+        PriceGuard::new(&config, &history, None, Some(10))
+    }
+
+    #[test]
+    fn pair_outside_the_band_is_refused_and_reported() {
+        let mut config = test_config::config();
+        config.price_band_bps = 500;
+        let mut book = OrderBook::default();
+        book.add_order(order(1, SELLER, OrderType::Sell, 110, 1), U256::ZERO, &spec());
+        book.add_order(order(2, BUYER, OrderType::Buy, 110, 1), U256::ZERO, &spec());
+
+        let mut escrow = EscrowLedger::unchecked();
+        let outcome = book.match_orders(100, None, policy(), guard(config), &mut escrow);
+        assert!(outcome.fills.is_empty());
+        // The newer order made the pair cross, so it goes
+        assert_eq!(outcome.cancellations.len(), 1);
+        assert_eq!(outcome.cancellations[0].order_id, 2);
+        assert_eq!(outcome.cancellations[0].reason, CancelReason::PriceBand);
+        let rejection = &outcome.band_rejections[0];
+        assert_eq!((rejection.buy_order_id, rejection.sell_order_id), (2, 1));
+        assert_eq!(rejection.price, U256::from(110));
+        assert_eq!((rejection.lower, rejection.upper), (U256::from(95), U256::from(105)));
+        assert!(book.contains(1));
+    }
+
+    #[test]
+    fn breaker_stops_matching_at_the_first_price_that_trips_it() {
+        let mut config = test_config::config();
+        config.circuit_breaker_bps = 1_000;
+        let mut book = OrderBook::default();
+        book.add_order(order(1, SELLER, OrderType::Sell, 105, 1), U256::ZERO, &spec());
+        book.add_order(order(2, SELLER, OrderType::Sell, 111, 1), U256::ZERO, &spec());
+        book.add_order(order(3, SELLER, OrderType::Sell, 111, 1), U256::ZERO, &spec());
+        book.add_order(order(4, BUYER, OrderType::Buy, 111, 3), U256::ZERO, &spec());
+
+        let mut escrow = EscrowLedger::unchecked();
+        let outcome = book.match_orders(100, None, policy(), guard(config), &mut escrow);
+        assert_eq!(fill_ids(&outcome), vec![(4, 1)]);
+        let breaker = outcome.breaker.unwrap();
+        assert_eq!((breaker.price, breaker.reference), (U256::from(111), U256::from(100)));
+        // Nothing is cancelled; the rest waits for the market to reopen
+        assert!(outcome.cancellations.is_empty());
+        assert_eq!(book.entry(4).unwrap().remaining_amount, U256::from(2));
+        assert!(book.contains(2) && book.contains(3));
+    }
+}
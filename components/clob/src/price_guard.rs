@@ -0,0 +1,270 @@
+use crate::config::{ClobConfig, PriceReference};
+use serde::{Deserialize, Serialize};
+use wavs_wasi_utils::evm::alloy_primitives::U256;
+
+const BPS: u64 = 10_000;
+
+/// A fill price and the block time it was matched at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TradePrint {
+    pub at: u64,
+    pub price: U256,
+}
+
+/// Recent fill prices of a market, oldest first. Prints older than the
+/// longest window in use are dropped, except the last one before it, which
+/// still sets the price at the start of the window.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PriceHistory {
+    prints: Vec<TradePrint>,
+}
+
+impl PriceHistory {
+    pub fn is_empty(&self) -> bool {
+        self.prints.is_empty()
+    }
+
+    /// Adds a print at block time `at`. One older than the latest print,
+    /// as a fill settled by another operator can be, is dated with it so
+    /// prints stay in order.
+    pub fn record(&mut self, at: u64, price: U256, keep_secs: u64) {
+        let at = at.max(self.latest_at());
+        self.prints.push(TradePrint { at, price });
+        let since = at.saturating_sub(keep_secs);
+        let older = self.prints.iter().take_while(|print| print.at < since).count();
+        self.prints.drain(..older.saturating_sub(1));
+    }
+
+    pub fn last(&self) -> Option<U256> {
+        self.prints.last().map(|print| print.price)
+    }
+
+    /// Block time of the latest print, zero if there is none.
+    pub fn latest_at(&self) -> u64 {
+        self.prints.last().map_or(0, |print| print.at)
+    }
+
+    /// Time-weighted average over `[now - window, now]`, each price counting
+    /// for as long as it was the last trade.
+    pub fn twap(&self, now: u64, window: u64) -> Option<U256> {
+        let since = now.saturating_sub(window);
+        let mut weighted = U256::ZERO;
+        let mut total = 0u64;
+        for (i, print) in self.prints.iter().enumerate() {
+            let until = self.prints.get(i + 1).map_or(now, |next| next.at).min(now);
+            let duration = until.saturating_sub(print.at.max(since));
+            weighted += print.price * U256::from(duration);
+            total += duration;
+        }
+        match total {
+            0 => self.last(),
+            total => Some(weighted / U256::from(total)),
+        }
+    }
+
+    /// Lowest and highest price traded since `since`, counting the price
+    /// that stood at that moment.
+    fn range_since(&self, since: u64) -> Option<(U256, U256)> {
+        let first = self.prints.iter().rposition(|print| print.at <= since).unwrap_or(0);
+        let prices = self.prints.get(first..)?.iter().map(|print| print.price);
+        Some((prices.clone().min()?, prices.max()?))
+    }
+}
+
+/// Prices a market may trade at around `reference`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PriceBand {
+    pub reference: U256,
+    pub lower: U256,
+    pub upper: U256,
+}
+
+/// The outcome of checking a match price against a [`PriceGuard`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriceCheck {
+    Allowed,
+    /// The price is outside the band; the match is refused.
+    OutsideBand(PriceBand),
+    /// The price moved more than the breaker allows from `reference`, a
+    /// price traded within the breaker window; the market halts.
+    Breaker {
+        reference: U256,
+    },
+}
+
+/// Price band and volatility circuit breaker for one matching run. The
+/// breaker window is widened by every fill the run produces.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PriceGuard {
+    band: Option<PriceBand>,
+    breaker_bps: u64,
+    window: Option<(U256, U256)>,
+    /// How long [`PriceHistory`] has to keep prints for.
+    pub history_secs: u64,
+}
+
+impl PriceGuard {
+    /// Sets up the band around the configured reference price and the
+    /// breaker from `history`. `oracle_price` is used when the reference is
+    /// the oracle. Without a reference price the band is not applied.
+    pub fn new(
+        config: &ClobConfig,
+        history: &PriceHistory,
+        oracle_price: Option<U256>,
+        now: Option<u64>,
+    ) -> Self {
+        let reference = match config.price_band_reference {
+            PriceReference::LastTrade => history.last(),
+            PriceReference::Twap => match now {
+                Some(now) => history.twap(now, config.twap_window_secs),
+                None => history.last(),
+            },
+            PriceReference::Oracle => oracle_price,
+        };
+        let band = reference.filter(|_| config.price_band_bps > 0).map(|reference| {
+            let width = reference * U256::from(config.price_band_bps) / U256::from(BPS);
+            PriceBand {
+                reference,
+                lower: reference.saturating_sub(width),
+                upper: reference + width,
+            }
+        });
+        let window = match now {
+            Some(now) if config.circuit_breaker_bps > 0 => {
+                history.range_since(now.saturating_sub(config.circuit_breaker_window_secs))
+            }
+            _ => None,
+        };
+        Self {
+            band,
+            breaker_bps: config.circuit_breaker_bps,
+            window,
+            history_secs: config.price_history_secs(),
+        }
+    }
+
+    pub fn check(&self, price: U256) -> PriceCheck {
+        if let Some(band) = self.band {
+            if price < band.lower || price > band.upper {
+                return PriceCheck::OutsideBand(band);
+            }
+        }
+        if let Some((low, high)) = self.window.filter(|_| self.breaker_bps > 0) {
+            let bps = U256::from(self.breaker_bps);
+            if price * U256::from(BPS) > low * (U256::from(BPS) + bps) {
+                return PriceCheck::Breaker { reference: low };
+            }
+            if price * U256::from(BPS) < high * U256::from(BPS).saturating_sub(bps) {
+                return PriceCheck::Breaker { reference: high };
+            }
+        }
+        PriceCheck::Allowed
+    }
+
+    /// Counts a fill towards the breaker window.
+    pub fn record(&mut self, price: U256) {
+        if self.breaker_bps > 0 {
+            self.window = Some(match self.window {
+                Some((low, high)) => (low.min(price), high.max(price)),
+                None => (price, price),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config::config;
+
+    fn history(prints: &[(u64, u64)]) -> PriceHistory {
+        let mut history = PriceHistory::default();
+        for (at, price) in prints {
+            history.record(*at, U256::from(*price), u64::MAX);
+        }
+        history
+    }
+
+    fn prints(history: &PriceHistory) -> Vec<(u64, u64)> {
+        history.prints.iter().map(|print| (print.at, print.price.to::<u64>())).collect()
+    }
+
+    #[test]
+    fn record_keeps_the_last_print_before_the_window() {
+        let mut history = PriceHistory::default();
+        for (at, price) in [(0, 10), (50, 11), (200, 12)] {
+            history.record(at, U256::from(price), 100);
+        }
+        // The print at 50 still sets the price at the start of [100, 200]
+        assert_eq!(prints(&history), vec![(50, 11), (200, 12)]);
+
+        // A late print is dated with the latest one
+        history.record(150, U256::from(13), 100);
+        assert_eq!(prints(&history), vec![(50, 11), (200, 12), (200, 13)]);
+    }
+
+    #[test]
+    fn twap_weighs_each_price_by_how_long_it_stood() {
+        let history = history(&[(0, 100), (60, 200)]);
+        assert_eq!(history.twap(120, 120), Some(U256::from(150)));
+        assert_eq!(history.twap(120, 30), Some(U256::from(200)));
+        // 100 for 30s, then 200 for 10s
+        assert_eq!(history.twap(70, 40), Some(U256::from(125)));
+        // An empty window falls back to the last trade
+        assert_eq!(history.twap(60, 0), Some(U256::from(200)));
+        assert_eq!(PriceHistory::default().twap(60, 60), None);
+    }
+
+    #[test]
+    fn range_counts_the_price_standing_at_the_start() {
+        let history = history(&[(0, 100), (50, 120), (80, 90)]);
+        assert_eq!(history.range_since(60), Some((U256::from(90), U256::from(120))));
+        assert_eq!(history.range_since(100), Some((U256::from(90), U256::from(90))));
+        assert_eq!(history.range_since(0), Some((U256::from(90), U256::from(120))));
+        assert_eq!(PriceHistory::default().range_since(0), None);
+    }
+
+    #[test]
+    fn band_is_inclusive_around_the_reference() {
+        let mut config = config();
+        config.price_band_bps = 500;
+        let guard = PriceGuard::new(&config, &history(&[(0, 1_000)]), None, None);
+
+        let band = PriceBand {
+            reference: U256::from(1_000),
+            lower: U256::from(950),
+            upper: U256::from(1_050),
+        };
+        for price in [950, 1_000, 1_050] {
+            assert_eq!(guard.check(U256::from(price)), PriceCheck::Allowed);
+        }
+        assert_eq!(guard.check(U256::from(949)), PriceCheck::OutsideBand(band));
+        assert_eq!(guard.check(U256::from(1_051)), PriceCheck::OutsideBand(band));
+
+        // Without a price to band around, anything goes
+        let guard = PriceGuard::new(&config, &PriceHistory::default(), None, None);
+        assert_eq!(guard.check(U256::from(1)), PriceCheck::Allowed);
+    }
+
+    #[test]
+    fn breaker_trips_on_a_move_from_the_window_range() {
+        let mut config = config();
+        config.circuit_breaker_bps = 1_000;
+        let history = history(&[(0, 1_000), (100, 1_100)]);
+        let mut guard = PriceGuard::new(&config, &history, None, Some(200));
+
+        // 10% above the low and below the high are still allowed
+        assert_eq!(guard.check(U256::from(1_100)), PriceCheck::Allowed);
+        assert_eq!(guard.check(U256::from(990)), PriceCheck::Allowed);
+        let from_low = PriceCheck::Breaker { reference: U256::from(1_000) };
+        let from_high = PriceCheck::Breaker { reference: U256::from(1_100) };
+        assert_eq!(guard.check(U256::from(1_101)), from_low);
+        assert_eq!(guard.check(U256::from(989)), from_high);
+
+        // Fills of the run widen the window
+        guard.record(U256::from(900));
+        let from_new_low = PriceCheck::Breaker { reference: U256::from(900) };
+        assert_eq!(guard.check(U256::from(990)), PriceCheck::Allowed);
+        assert_eq!(guard.check(U256::from(1_000)), from_new_low);
+    }
+}
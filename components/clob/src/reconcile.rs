@@ -0,0 +1,683 @@
+use crate::config::{ClobConfig, StaleFillPolicy};
+use crate::market::{MarketKey, MarketRegistry};
+use crate::settlement::{ExcludedMatch, ExclusionReason};
+use crate::trigger::{price_scale, Fill, MatchResult, Order, OrderCancellation};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use wavs_wasi_utils::evm::alloy_primitives::{Address, U256};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FillStatus {
+    /// Emitted in our payload and already applied to the local book, but not
+    /// yet seen in an `OrderMatched` event.
+    Proposed,
+    /// Confirmed by an `OrderMatched` event.
+    Settled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackedFill {
+    pub market: MarketKey,
+    pub fill: Fill,
+    pub status: FillStatus,
+    /// Block of the trigger that last proposed this fill.
+    pub proposed_at_block: u64,
+    pub settled_at_block: Option<u64>,
+    pub reproposals: u32,
+}
+
+impl TrackedFill {
+    fn involves(&self, order_id: u64) -> bool {
+        self.fill.buy_order.id == order_id || self.fill.sell_order.id == order_id
+    }
+
+    /// Whether this is the fill `result` describes.
+    fn is(&self, result: &MatchResult) -> bool {
+        U256::from(self.fill.buy_order.id) == result.buy_order_id
+            && U256::from(self.fill.sell_order.id) == result.sell_order_id
+            && self.fill.result.match_amount == result.match_amount
+    }
+
+    fn is_proposed(&self) -> bool {
+        self.status == FillStatus::Proposed
+    }
+
+    fn order(&self, order_id: u64) -> Option<&Order> {
+        [&self.fill.buy_order, &self.fill.sell_order].into_iter().find(|order| order.id == order_id)
+    }
+}
+
+/// An amount of an order released in our payload, which the chain keeps
+/// open until it executes the payload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingCancellation {
+    pub order_id: u64,
+    pub amount: U256,
+    pub proposed_at_block: u64,
+}
+
+/// Fills this operator has proposed, kept until the chain confirms them or
+/// they time out. Settled fills are retained for one more timeout window so
+/// replayed `OrderMatched` events are not mistaken for foreign fills.
+/// Cancellations we proposed are kept the same way, until the chain cancels
+/// the order or they time out.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FillTracker {
+    pub fills: Vec<TrackedFill>,
+    #[serde(default)]
+    pub cancellations: Vec<PendingCancellation>,
+}
+
+impl FillTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load_from_file(file_path: &str) -> Self {
+        if Path::new(file_path).exists() {
+            match fs::read_to_string(file_path) {
+                Ok(contents) => match serde_json::from_str::<FillTracker>(&contents) {
+                    Ok(tracker) => {
+                        println!("📂 Loaded {} tracked fills from file", tracker.fills.len());
+                        return tracker;
+                    }
+                    Err(e) => println!("⚠️ Failed to parse fill tracker file: {}", e),
+                },
+                Err(e) => println!("⚠️ Failed to read fill tracker file: {}", e),
+            }
+        } else {
+            println!("📁 Fill tracker file not found, starting with no tracked fills");
+        }
+        Self::new()
+    }
+
+    pub fn save_to_file(&self, file_path: &str) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(file_path, contents)?;
+        println!("💾 Saved {} tracked fills to file", self.fills.len());
+        Ok(())
+    }
+
+    /// Records fills that are about to be emitted in the payload.
+    pub fn propose(&mut self, market: MarketKey, fills: &[Fill], block: u64) {
+        self.fills.extend(fills.iter().map(|fill| TrackedFill {
+            market,
+            fill: fill.clone(),
+            status: FillStatus::Proposed,
+            proposed_at_block: block,
+            settled_at_block: None,
+            reproposals: 0,
+        }));
+    }
+
+    /// Total amount of an order taken by fills still awaiting settlement,
+    /// in the order's own units.
+    pub fn in_flight(&self, order_id: u64, price_decimals: u8) -> U256 {
+        self.fills
+            .iter()
+            .filter(|t| t.is_proposed() && t.involves(order_id))
+            .map(|t| t.fill.units_of(order_id, price_decimals))
+            .sum()
+    }
+
+    /// Block of the oldest proposal still awaiting settlement; the earliest
+    /// any of our fills can have landed at.
+    pub fn oldest_proposal(&self) -> Option<u64> {
+        self.fills.iter().filter(|t| t.is_proposed()).map(|t| t.proposed_at_block).min()
+    }
+
+    /// Fills awaiting settlement other than `matches`, the ones in the
+    /// payload being built, and other than `landed`, the ones that settled
+    /// on-chain before we saw their event: what payloads proposed earlier
+    /// still carry.
+    pub fn proposed_elsewhere(
+        &self,
+        matches: &[MatchResult],
+        landed: &[MatchResult],
+    ) -> Vec<MatchResult> {
+        let mut current: Vec<&MatchResult> = matches.iter().chain(landed).collect();
+        self.fills
+            .iter()
+            .filter(|t| t.is_proposed())
+            .filter_map(|t| match current.iter().position(|m| t.is(m)) {
+                Some(i) => {
+                    current.swap_remove(i);
+                    None
+                }
+                None => Some(t.fill.result.clone()),
+            })
+            .collect()
+    }
+
+    /// Records cancellations that are about to be emitted in the payload.
+    pub fn propose_cancellations(&mut self, cancellations: &[OrderCancellation], block: u64) {
+        self.cancellations.extend(cancellations.iter().map(|cancellation| PendingCancellation {
+            order_id: cancellation.order_id,
+            amount: cancellation.amount,
+            proposed_at_block: block,
+        }));
+    }
+
+    /// Total amount of an order released by cancellations the chain has not
+    /// executed yet, in the order's own units.
+    pub fn cancelling(&self, order_id: u64) -> U256 {
+        self.cancellations.iter().filter(|c| c.order_id == order_id).map(|c| c.amount).sum()
+    }
+
+    /// What fills awaiting settlement will take out of `trader`'s escrow in
+    /// `token`: quote tokens for its buys, base tokens for its sells.
+    pub fn escrow_in_flight(&self, trader: Address, token: Address, price_decimals: u8) -> U256 {
+        let scale = price_scale(price_decimals);
+        self.fills
+            .iter()
+            .filter(|t| t.is_proposed())
+            .map(|t| {
+                let (buy, sell, result) = (&t.fill.buy_order, &t.fill.sell_order, &t.fill.result);
+                let mut taken = U256::ZERO;
+                if buy.trader == trader && buy.quote_token == token {
+                    taken += result.match_amount * result.match_price / scale;
+                }
+                if sell.trader == trader && sell.base_token == token {
+                    taken += result.match_amount;
+                }
+                taken
+            })
+            .sum()
+    }
+
+    /// Handles an `OrderMatched` event. A fill we proposed is only marked
+    /// settled, since the book already reflects it. A fill we never proposed
+    /// means the chain moved without us, so fully consumed orders are taken
+    /// out of the book; partially filled ones are corrected by the
+    /// `OrderPartiallyFilled` event emitted alongside it. Its price, traded
+    /// in `market` at block time `at`, joins the market's price history.
+    pub fn settle(
+        &mut self,
+        settled: &MatchResult,
+        market: MarketKey,
+        at: u64,
+        markets: &mut MarketRegistry,
+        block: u64,
+        config: &ClobConfig,
+    ) {
+        let price_decimals = config.price_decimals;
+        let same_fill = |tracked: &TrackedFill| tracked.is(settled);
+
+        if let Some(tracked) = self.fills.iter_mut().find(|t| t.is_proposed() && same_fill(t)) {
+            tracked.status = FillStatus::Settled;
+            tracked.settled_at_block = Some(block);
+            println!(
+                "✅ Proposed fill settled: Buy Order {} <-> Sell Order {}, Amount: {}",
+                settled.buy_order_id, settled.sell_order_id, settled.match_amount
+            );
+            return;
+        }
+
+        if self.fills.iter().any(same_fill) {
+            println!(
+                "ℹ️ Fill Buy Order {} <-> Sell Order {} already settled, ignoring",
+                settled.buy_order_id, settled.sell_order_id
+            );
+            return;
+        }
+
+        println!(
+            "⚠️ Settled fill was not proposed by this operator: Buy Order {} <-> Sell Order {}, Amount: {}",
+            settled.buy_order_id, settled.sell_order_id, settled.match_amount
+        );
+        markets.book_mut(market).prices.record(
+            at,
+            settled.match_price,
+            config.price_history_secs(),
+        );
+        for order_id in [settled.buy_order_id, settled.sell_order_id] {
+            let order_id = order_id.to::<u64>();
+            if let Some(book) = markets.book_for_order(order_id) {
+                let exhausted = book.entry_mut(order_id).is_some_and(|entry| {
+                    let units = entry.order.fill_units(
+                        settled.match_amount,
+                        settled.match_price,
+                        price_decimals,
+                    );
+                    let left = entry.remaining_amount.saturating_sub(units);
+                    entry.order.is_exhausted(left, price_decimals)
+                });
+                if exhausted {
+                    book.remove_order(order_id);
+                }
+            }
+        }
+    }
+
+    /// Handles an `OrderPartiallyFilled` event, whose remaining amount is
+    /// authoritative. The book should hold that amount minus whatever we
+    /// have proposed for the order that the chain has not executed yet. The
+    /// fill this event reports is assumed to be the oldest proposed fill of
+    /// the same amount, if any. Amounts are in the order's own units.
+    pub fn reconcile_partial_fill(
+        &self,
+        order_id: u64,
+        filled_amount: U256,
+        chain_remaining: U256,
+        markets: &mut MarketRegistry,
+        price_decimals: u8,
+    ) {
+        let proposed: Vec<&TrackedFill> =
+            self.fills.iter().filter(|t| t.is_proposed() && t.involves(order_id)).collect();
+        let reported = proposed
+            .iter()
+            .position(|t| t.fill.units_of(order_id, price_decimals) == filled_amount);
+        let in_flight: U256 = proposed
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| Some(*i) != reported)
+            .map(|(_, t)| t.fill.units_of(order_id, price_decimals))
+            .sum();
+        let expected = chain_remaining.saturating_sub(in_flight);
+
+        if let Some(book) = markets.book_for_order(order_id) {
+            let current = book.entry_mut(order_id).map(|entry| entry.remaining_amount);
+            if current != Some(expected) {
+                println!(
+                    "🔧 Reconciled order {} remaining amount {:?} -> {}",
+                    order_id, current, expected
+                );
+                book.set_remaining(order_id, expected);
+            }
+        } else if expected > U256::ZERO {
+            // Our proposals consumed the order locally but the chain still
+            // has more open than they account for.
+            match self.fills.iter().find_map(|t| t.order(order_id)) {
+                Some(order) => {
+                    println!("🔧 Restored order {} with remaining amount {}", order_id, expected);
+                    markets.restore(order, expected);
+                }
+                None => println!(
+                    "⚠️ Order {} has {} open on-chain but is unknown locally",
+                    order_id, expected
+                ),
+            }
+        }
+    }
+
+    /// Drops proposed fills involving a cancelled order and gives their
+    /// amount back to the other side. If such a fill did settle before the
+    /// cancel, its `OrderMatched` event is handled as a foreign fill. Our
+    /// own cancellations of the order are done with.
+    pub fn on_order_cancelled(
+        &mut self,
+        order_id: u64,
+        markets: &mut MarketRegistry,
+        price_decimals: u8,
+    ) {
+        self.cancellations.retain(|cancellation| cancellation.order_id != order_id);
+        self.fills.retain(|tracked| {
+            if !tracked.is_proposed() || !tracked.involves(order_id) {
+                return true;
+            }
+            let counterparty = if tracked.fill.buy_order.id == order_id {
+                &tracked.fill.sell_order
+            } else {
+                &tracked.fill.buy_order
+            };
+            let amount = tracked.fill.units_of(counterparty.id, price_decimals);
+            println!(
+                "↩️ Dropped proposed fill for cancelled order {}, returned {} to order {}",
+                order_id, amount, counterparty.id
+            );
+            markets.restore(counterparty, amount);
+            false
+        });
+    }
+
+    /// Takes the part of a proposed fill the settlement check kept out of
+    /// the payload off the fill and gives it back to the book. The order
+    /// that ruled it out gets nothing back, and leaves the book if the chain
+    /// has closed it. An order that is not open yet but has other fills
+    /// awaiting settlement stays, since an earlier payload may still open
+    /// it.
+    pub fn exclude(
+        &mut self,
+        excluded: &ExcludedMatch,
+        markets: &mut MarketRegistry,
+        price_decimals: u8,
+    ) {
+        let Some(index) = self
+            .fills
+            .iter()
+            .position(|tracked| tracked.is_proposed() && tracked.is(&excluded.proposed))
+        else {
+            return;
+        };
+        let closed = match excluded.reason {
+            ExclusionReason::ExceedsRemaining => false,
+            ExclusionReason::NotOpen => !self.fills.iter().enumerate().any(|(i, tracked)| {
+                i != index
+                    && tracked.is_proposed()
+                    && excluded.stale_order.is_some_and(|id| tracked.involves(id))
+            }),
+            _ => true,
+        };
+
+        let tracked = &mut self.fills[index];
+        let result = &mut tracked.fill.result;
+        let left = result.match_amount - excluded.amount;
+        result.buyer_fee = result.buyer_fee * left / result.match_amount;
+        result.seller_fee = result.seller_fee * left / result.match_amount;
+        result.match_amount = left;
+
+        let price = tracked.fill.result.match_price;
+        for order in [&tracked.fill.buy_order, &tracked.fill.sell_order] {
+            if Some(order.id) != excluded.stale_order {
+                markets.restore(order, order.fill_units(excluded.amount, price, price_decimals));
+            } else if closed {
+                markets.remove_order(order.id);
+            }
+        }
+        if left.is_zero() {
+            self.fills.remove(index);
+        }
+    }
+
+    /// Applies `stale_fill_policy` to proposed fills older than
+    /// `settlement_timeout_blocks` and forgets settled fills once their
+    /// retention window has passed. Returns the fills to propose again.
+    /// Fills in a market that is not trading are rolled back rather than
+    /// proposed again. At most `max_matches_per_batch` fills are proposed
+    /// again per run; the rest wait for the next one. Cancellations still
+    /// pending after the timeout are forgotten.
+    pub fn expire(
+        &mut self,
+        block: u64,
+        config: &ClobConfig,
+        markets: &mut MarketRegistry,
+    ) -> Vec<MatchResult> {
+        let timeout = config.settlement_timeout_blocks;
+        let mut reproposed = Vec::new();
+        self.cancellations.retain(|c| block < c.proposed_at_block.saturating_add(timeout));
+
+        self.fills.retain_mut(|tracked| {
+            if let Some(settled_at) = tracked.settled_at_block {
+                return block < settled_at.saturating_add(timeout);
+            }
+            if block < tracked.proposed_at_block.saturating_add(timeout) {
+                return true;
+            }
+
+            let result = &tracked.fill.result;
+            if config.stale_fill_policy == StaleFillPolicy::Repropose
+                && tracked.reproposals < config.max_reproposals
+                && markets.phase(tracked.market, config).is_trading()
+            {
+                if reproposed.len() == config.max_matches_per_batch {
+                    return true;
+                }
+                tracked.reproposals += 1;
+                tracked.proposed_at_block = block;
+                println!(
+                    "🔁 Re-proposing unsettled fill (attempt {}): Buy Order {} <-> Sell Order {}, Amount: {}",
+                    tracked.reproposals, result.buy_order_id, result.sell_order_id, result.match_amount
+                );
+                reproposed.push(result.clone());
+                return true;
+            }
+
+            println!(
+                "↩️ Rolling back unsettled fill: Buy Order {} <-> Sell Order {}, Amount: {}",
+                result.buy_order_id, result.sell_order_id, result.match_amount
+            );
+            for order in [&tracked.fill.buy_order, &tracked.fill.sell_order] {
+                markets.restore(order, tracked.fill.units_of(order.id, config.price_decimals));
+            }
+            false
+        });
+
+        reproposed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::market::MarketPhase;
+    use crate::trigger::test_orders::{order, BASE, QUOTE};
+    use crate::trigger::{CancelReason, OrderType};
+
+    const BUYER: Address = Address::repeat_byte(1);
+    const SELLER: Address = Address::repeat_byte(2);
+    const MARKET: MarketKey = MarketKey { base_token: BASE, quote_token: QUOTE };
+
+    /// Prices with no decimals, so a fill costs `amount * price` quote.
+    fn config() -> ClobConfig {
+        let mut config = test_config::config();
+        config.price_decimals = 0;
+        config
+    }
+
+    fn fill(buy: &Order, sell: &Order, amount: u64) -> Fill {
+        Fill {
+            result: MatchResult {
+                buy_order_id: U256::from(buy.id),
+                sell_order_id: U256::from(sell.id),
+                match_amount: U256::from(amount),
+                match_price: sell.price,
+                taker_side: OrderType::Buy,
+                buyer_fee: U256::ZERO,
+                seller_fee: U256::ZERO,
+            },
+            buy_order: buy.clone(),
+            sell_order: sell.clone(),
+        }
+    }
+
+    /// A buy and a sell of 10 at 10, with fills of `amounts` between them
+    /// proposed at block 100 and already taken out of the book.
+    fn proposed(amounts: &[u64]) -> (FillTracker, MarketRegistry) {
+        let buy = order(1, BUYER, OrderType::Buy, 10, 10);
+        let sell = order(2, SELLER, OrderType::Sell, 10, 10);
+        let left = U256::from(10 - amounts.iter().sum::<u64>());
+        let mut markets = MarketRegistry::new();
+        markets.restore(&buy, left);
+        markets.restore(&sell, left);
+        let fills: Vec<Fill> = amounts.iter().map(|amount| fill(&buy, &sell, *amount)).collect();
+        let mut tracker = FillTracker::new();
+        tracker.propose(MARKET, &fills, 100);
+        (tracker, markets)
+    }
+
+    fn remaining(markets: &mut MarketRegistry, order_id: u64) -> Option<u64> {
+        let book = markets.book_for_order(order_id)?;
+        book.entry(order_id).map(|entry| entry.remaining_amount.to::<u64>())
+    }
+
+    #[test]
+    fn stale_fills_roll_back() {
+        let (mut tracker, mut markets) = proposed(&[4]);
+        let config = config();
+        assert!(tracker.expire(119, &config, &mut markets).is_empty());
+        assert_eq!(tracker.fills.len(), 1);
+
+        assert!(tracker.expire(120, &config, &mut markets).is_empty());
+        assert!(tracker.fills.is_empty());
+        assert_eq!(remaining(&mut markets, 1), Some(10));
+        assert_eq!(remaining(&mut markets, 2), Some(10));
+    }
+
+    #[test]
+    fn stale_fills_are_proposed_again_up_to_the_batch_cap() {
+        let (mut tracker, mut markets) = proposed(&[4, 3]);
+        let mut config = config();
+        config.stale_fill_policy = StaleFillPolicy::Repropose;
+        config.max_matches_per_batch = 1;
+
+        let again = tracker.expire(120, &config, &mut markets);
+        assert_eq!(again.len(), 1);
+        assert_eq!(again[0].match_amount, U256::from(4));
+        let blocks: Vec<_> =
+            tracker.fills.iter().map(|t| (t.proposed_at_block, t.reproposals)).collect();
+        assert_eq!(blocks, vec![(120, 1), (100, 0)]);
+        // Nothing went back to the book.
+        assert_eq!(remaining(&mut markets, 1), Some(3));
+
+        // The fill left over waits for the next run.
+        let again = tracker.expire(121, &config, &mut markets);
+        assert_eq!(again[0].match_amount, U256::from(3));
+    }
+
+    #[test]
+    fn stale_fills_roll_back_once_out_of_reproposals_or_halted() {
+        let mut config = config();
+        config.stale_fill_policy = StaleFillPolicy::Repropose;
+
+        let (mut tracker, mut markets) = proposed(&[4]);
+        tracker.fills[0].reproposals = config.max_reproposals;
+        assert!(tracker.expire(120, &config, &mut markets).is_empty());
+        assert!(tracker.fills.is_empty());
+        assert_eq!(remaining(&mut markets, 1), Some(10));
+
+        let (mut tracker, mut markets) = proposed(&[4]);
+        markets.set_phase(MARKET, MarketPhase::Halted);
+        assert!(tracker.expire(120, &config, &mut markets).is_empty());
+        assert!(tracker.fills.is_empty());
+        assert_eq!(remaining(&mut markets, 2), Some(10));
+    }
+
+    #[test]
+    fn own_fills_settle_without_touching_the_book() {
+        let (mut tracker, mut markets) = proposed(&[4]);
+        let settled = tracker.fills[0].fill.result.clone();
+        tracker.settle(&settled, MARKET, 1_000, &mut markets, 105, &config());
+
+        assert_eq!(tracker.fills[0].status, FillStatus::Settled);
+        assert_eq!(tracker.fills[0].settled_at_block, Some(105));
+        assert_eq!(remaining(&mut markets, 1), Some(6));
+        assert_eq!(remaining(&mut markets, 2), Some(6));
+
+        // A replayed event is ignored, and the fill is forgotten a timeout
+        // after it settled.
+        tracker.settle(&settled, MARKET, 1_000, &mut markets, 106, &config());
+        assert_eq!(remaining(&mut markets, 2), Some(6));
+        tracker.expire(124, &config(), &mut markets);
+        assert_eq!(tracker.fills.len(), 1);
+        tracker.expire(125, &config(), &mut markets);
+        assert!(tracker.fills.is_empty());
+    }
+
+    #[test]
+    fn foreign_fills_take_exhausted_orders_out() {
+        let (mut tracker, mut markets) = proposed(&[]);
+        let small = order(3, BUYER, OrderType::Buy, 12, 4);
+        markets.restore(&small, U256::from(4));
+        let sell = markets.order(2).cloned().unwrap();
+        tracker.settle(&fill(&small, &sell, 4).result, MARKET, 1_000, &mut markets, 105, &config());
+
+        assert!(tracker.fills.is_empty());
+        assert_eq!(remaining(&mut markets, 3), None);
+        // The sell is left for its `OrderPartiallyFilled` event to correct.
+        assert_eq!(remaining(&mut markets, 2), Some(10));
+        assert_eq!(markets.last_price(MARKET), Some(U256::from(10)));
+    }
+
+    #[test]
+    fn partial_fills_leave_what_is_still_in_flight_off_the_book() {
+        let (tracker, mut markets) = proposed(&[4, 3]);
+        markets.book_for_order(2).unwrap().set_remaining(2, U256::from(1));
+
+        // The chain executed the fill of 4; the one of 3 is still in flight.
+        tracker.reconcile_partial_fill(2, U256::from(4), U256::from(6), &mut markets, 0);
+        assert_eq!(remaining(&mut markets, 2), Some(3));
+
+        // An order our proposals consumed locally comes back if the chain
+        // has more open than they account for.
+        markets.remove_order(2);
+        tracker.reconcile_partial_fill(2, U256::from(2), U256::from(8), &mut markets, 0);
+        assert_eq!(remaining(&mut markets, 2), Some(1));
+    }
+
+    #[test]
+    fn cancelled_orders_give_their_fills_back_to_the_other_side() {
+        let (mut tracker, mut markets) = proposed(&[4]);
+        let cancellation = OrderCancellation {
+            order_id: 2,
+            amount: U256::from(6),
+            reason: CancelReason::IocUnfilled,
+        };
+        tracker.propose_cancellations(&[cancellation], 100);
+        markets.remove_order(2);
+
+        tracker.on_order_cancelled(2, &mut markets, 0);
+        assert!(tracker.fills.is_empty());
+        assert!(tracker.cancellations.is_empty());
+        assert_eq!(remaining(&mut markets, 1), Some(10));
+        assert_eq!(remaining(&mut markets, 2), None);
+    }
+
+    fn excluded(tracker: &FillTracker, amount: u64, reason: ExclusionReason) -> ExcludedMatch {
+        ExcludedMatch {
+            proposed: tracker.fills[0].fill.result.clone(),
+            amount: U256::from(amount),
+            reason,
+            stale_order: Some(2),
+        }
+    }
+
+    #[test]
+    fn excluded_parts_go_back_to_the_other_order() {
+        let (mut tracker, mut markets) = proposed(&[4]);
+        let exceeds = excluded(&tracker, 1, ExclusionReason::ExceedsRemaining);
+        tracker.exclude(&exceeds, &mut markets, 0);
+
+        assert_eq!(tracker.fills[0].fill.result.match_amount, U256::from(3));
+        assert_eq!(remaining(&mut markets, 1), Some(7));
+        assert_eq!(remaining(&mut markets, 2), Some(6));
+
+        // A closed order leaves the book along with the whole fill.
+        let closed = excluded(&tracker, 3, ExclusionReason::NotOpen);
+        tracker.exclude(&closed, &mut markets, 0);
+        assert!(tracker.fills.is_empty());
+        assert_eq!(remaining(&mut markets, 1), Some(10));
+        assert_eq!(remaining(&mut markets, 2), None);
+    }
+
+    #[test]
+    fn orders_not_open_yet_stay_while_other_fills_may_open_them() {
+        let (mut tracker, mut markets) = proposed(&[4, 3]);
+        let not_open = excluded(&tracker, 4, ExclusionReason::NotOpen);
+        tracker.exclude(&not_open, &mut markets, 0);
+
+        assert_eq!(tracker.fills.len(), 1);
+        assert_eq!(remaining(&mut markets, 1), Some(7));
+        assert_eq!(remaining(&mut markets, 2), Some(3));
+    }
+
+    #[test]
+    fn in_flight_escrow_is_quote_for_buys_and_base_for_sells() {
+        let (tracker, _) = proposed(&[4, 3]);
+        assert_eq!(tracker.escrow_in_flight(BUYER, QUOTE, 0), U256::from(70));
+        assert_eq!(tracker.escrow_in_flight(BUYER, BASE, 0), U256::ZERO);
+        assert_eq!(tracker.escrow_in_flight(SELLER, BASE, 0), U256::from(7));
+        assert_eq!(tracker.escrow_in_flight(SELLER, QUOTE, 0), U256::ZERO);
+    }
+
+    #[test]
+    fn fills_that_landed_unacknowledged_are_not_replayed() {
+        let (mut tracker, _) = proposed(&[4, 3]);
+        let buy = order(1, BUYER, OrderType::Buy, 10, 10);
+        let sell = order(2, SELLER, OrderType::Sell, 10, 10);
+        let current = [fill(&buy, &sell, 2)];
+        tracker.propose(MARKET, &current, 110);
+        assert_eq!(tracker.oldest_proposal(), Some(100));
+
+        // The payload carrying the fill of 4 landed; its event has not
+        // come in yet
+        let landed = [fill(&buy, &sell, 4).result];
+        let current = [current[0].result.clone()];
+        let earlier = tracker.proposed_elsewhere(&current, &landed);
+        let amounts: Vec<u64> = earlier.iter().map(|m| m.match_amount.to::<u64>()).collect();
+        assert_eq!(amounts, [3]);
+        assert_eq!(tracker.proposed_elsewhere(&current, &[]).len(), 2);
+    }
+}
@@ -0,0 +1,407 @@
+use crate::solidity;
+use crate::trigger::{price_scale, MatchResult, Order, OrderStatus, OrderType};
+use std::collections::BTreeMap;
+use wavs_wasi_utils::evm::alloy_primitives::{Address, U256};
+
+/// Why a match, or part of it, was left out of the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExclusionReason {
+    /// One of the orders does not exist on-chain.
+    MissingOrder,
+    /// The buy order is not a buy or the sell order not a sell.
+    WrongSide,
+    /// One of the orders is not open.
+    NotOpen,
+    /// The match is larger than what one of the orders has left.
+    ExceedsRemaining,
+    /// The buyer's quote or the seller's base escrow does not cover it.
+    InsufficientEscrow,
+    /// It would cost a quote-denominated buy nothing once rounded down.
+    FreeFill,
+}
+
+impl ExclusionReason {
+    /// Encoding of the reason in `ExcludedMatch.reason`
+    pub fn to_sol(self) -> u8 {
+        match self {
+            ExclusionReason::MissingOrder => 0,
+            ExclusionReason::WrongSide => 1,
+            ExclusionReason::NotOpen => 2,
+            ExclusionReason::ExceedsRemaining => 3,
+            ExclusionReason::InsufficientEscrow => 4,
+            ExclusionReason::FreeFill => 5,
+        }
+    }
+}
+
+/// The part of a proposed match that would have reverted the envelope.
+#[derive(Debug, Clone)]
+pub struct ExcludedMatch {
+    /// The match as proposed.
+    pub proposed: MatchResult,
+    /// How much of it was left out; all of it if the match was dropped.
+    pub amount: U256,
+    pub reason: ExclusionReason,
+    /// The order whose on-chain state ruled the match out, if it was not
+    /// escrow.
+    pub stale_order: Option<u64>,
+}
+
+impl ExcludedMatch {
+    pub fn to_solidity(&self) -> solidity::ExcludedMatch {
+        solidity::ExcludedMatch {
+            buyOrderId: self.proposed.buy_order_id,
+            sellOrderId: self.proposed.sell_order_id,
+            amount: self.amount,
+            reason: self.reason.to_sol(),
+        }
+    }
+}
+
+/// Amount left out of a match, why, and the order to blame, if any.
+type Cut = (U256, ExclusionReason, Option<u64>);
+
+/// Replays a payload against the checks `CLOB.sol::handleSignedEnvelope`
+/// makes, on a copy of the contract's orders and `escrowBalances`, so that
+/// no single match can revert the others.
+#[derive(Debug, Clone)]
+pub struct Settlement {
+    orders: BTreeMap<u64, Order>,
+    escrow: BTreeMap<(Address, Address), U256>,
+    price_decimals: u8,
+}
+
+impl Settlement {
+    /// `orders` and `escrow` as the contract holds them. Escrow accounts
+    /// left out are taken to be empty.
+    pub fn new(
+        orders: impl IntoIterator<Item = Order>,
+        escrow: BTreeMap<(Address, Address), U256>,
+        price_decimals: u8,
+    ) -> Self {
+        Self {
+            orders: orders.into_iter().map(|order| (order.id, order)).collect(),
+            escrow,
+            price_decimals,
+        }
+    }
+
+    /// Runs `matches` in payload order, after opening `triggered_stops` as
+    /// the contract does first. Returns the matches that settle, cut down to
+    /// what the contract accepts, and what was left out.
+    pub fn run(
+        &mut self,
+        triggered_stops: &[u64],
+        matches: Vec<MatchResult>,
+    ) -> (Vec<MatchResult>, Vec<ExcludedMatch>) {
+        for id in triggered_stops {
+            if let Some(order) = self.orders.get_mut(id) {
+                if order.status == OrderStatus::PendingTrigger {
+                    order.status = OrderStatus::Open;
+                }
+            }
+        }
+
+        let mut settled = Vec::new();
+        let mut excluded = Vec::new();
+        for proposed in matches {
+            let (settles, exclusion) = self.execute(&proposed);
+            if let Some((amount, reason, stale_order)) = exclusion {
+                excluded.push(ExcludedMatch { proposed, amount, reason, stale_order });
+            }
+            settled.extend(settles);
+        }
+        (settled, excluded)
+    }
+
+    /// `executeMatch` for one match: the largest part of it that passes
+    /// every `require`, applied to the simulated state, and what was cut.
+    fn execute(&mut self, proposed: &MatchResult) -> (Option<MatchResult>, Option<Cut>) {
+        let buy_id = proposed.buy_order_id.to::<u64>();
+        let sell_id = proposed.sell_order_id.to::<u64>();
+        let drop = |reason, stale_order| (None, Some((proposed.match_amount, reason, stale_order)));
+
+        let (Some(buy), Some(sell)) = (self.orders.get(&buy_id), self.orders.get(&sell_id)) else {
+            let missing = if self.orders.contains_key(&buy_id) { sell_id } else { buy_id };
+            return drop(ExclusionReason::MissingOrder, Some(missing));
+        };
+        if buy.order_type != OrderType::Buy {
+            return drop(ExclusionReason::WrongSide, Some(buy_id));
+        }
+        if sell.order_type != OrderType::Sell {
+            return drop(ExclusionReason::WrongSide, Some(sell_id));
+        }
+        if !buy.status.is_open() {
+            return drop(ExclusionReason::NotOpen, Some(buy_id));
+        }
+        if !sell.status.is_open() {
+            return drop(ExclusionReason::NotOpen, Some(sell_id));
+        }
+
+        let price = proposed.match_price;
+        let mut amount = proposed.match_amount;
+        let mut cut = None;
+        for order in [buy, sell] {
+            let remaining =
+                order.capacity(order.amount - order.filled_amount, price, self.price_decimals);
+            if amount > remaining {
+                amount = remaining;
+                cut = Some((ExclusionReason::ExceedsRemaining, Some(order.id)));
+            }
+        }
+
+        let buyer_quote = self.balance(buy.trader, buy.quote_token);
+        let seller_base = self.balance(sell.trader, sell.base_token);
+        // Largest amount whose quote, rounded down as the contract does,
+        // the buyer's escrow still covers
+        let quote_cap = ((buyer_quote + U256::from(1))
+            .saturating_mul(price_scale(self.price_decimals))
+            - U256::from(1))
+        .checked_div(price)
+        .unwrap_or(U256::MAX);
+        let escrow_cap = quote_cap.min(seller_base);
+        if amount > escrow_cap {
+            amount = escrow_cap;
+            cut = Some((ExclusionReason::InsufficientEscrow, None));
+        }
+        if buy.is_free_fill(amount, price, self.price_decimals) {
+            amount = U256::ZERO;
+            cut = Some((ExclusionReason::FreeFill, None));
+        }
+
+        let exclusion =
+            cut.map(|(reason, stale_order)| (proposed.match_amount - amount, reason, stale_order));
+        if amount.is_zero() {
+            return (None, exclusion);
+        }
+
+        let settles = MatchResult {
+            match_amount: amount,
+            buyer_fee: proposed.buyer_fee * amount / proposed.match_amount,
+            seller_fee: proposed.seller_fee * amount / proposed.match_amount,
+            ..proposed.clone()
+        };
+        self.apply(&settles);
+        (Some(settles), exclusion)
+    }
+
+    /// Moves fill amounts and escrow as `executeMatch` does. Fees go to a
+    /// fee recipient the simulation does not follow. A quote-denominated buy
+    /// that can no longer pay for a base unit is filled, and the quote it
+    /// has left refunded.
+    fn apply(&mut self, fill: &MatchResult) {
+        let amount = fill.match_amount;
+        let quote = amount * fill.match_price / price_scale(self.price_decimals);
+        let price_decimals = self.price_decimals;
+        let mut fill_order = |id: U256| {
+            let order = self.orders.get_mut(&id.to::<u64>()).expect("checked by execute");
+            order.filled_amount += order.fill_units(amount, fill.match_price, price_decimals);
+            let left = order.amount - order.filled_amount;
+            let exhausted = order.is_exhausted(left, price_decimals);
+            order.status =
+                if exhausted { OrderStatus::Filled } else { OrderStatus::PartiallyFilled };
+            let dust = if exhausted { left } else { U256::ZERO };
+            (order.trader, order.base_token, order.quote_token, dust)
+        };
+        let (buyer, base, quote_token, dust) = fill_order(fill.buy_order_id);
+        let (seller, _, _, _) = fill_order(fill.sell_order_id);
+
+        let buyer_quote = self.escrow.entry((buyer, quote_token)).or_default();
+        *buyer_quote -= quote;
+        if *buyer_quote >= dust {
+            *buyer_quote -= dust;
+        }
+        *self.escrow.entry((seller, base)).or_default() -= amount;
+        *self.escrow.entry((buyer, base)).or_default() += amount - fill.buyer_fee;
+        *self.escrow.entry((seller, quote_token)).or_default() += quote - fill.seller_fee;
+    }
+
+    fn balance(&self, trader: Address, token: Address) -> U256 {
+        self.escrow.get(&(trader, token)).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trigger::test_orders::{order, BASE, QUOTE};
+    use crate::trigger::Denomination;
+
+    const BUYER: Address = Address::repeat_byte(1);
+    const SELLER: Address = Address::repeat_byte(2);
+
+    fn fill(buy: u64, sell: u64, price: u64, amount: u64) -> MatchResult {
+        MatchResult {
+            buy_order_id: U256::from(buy),
+            sell_order_id: U256::from(sell),
+            match_amount: U256::from(amount),
+            match_price: U256::from(price),
+            taker_side: OrderType::Buy,
+            buyer_fee: U256::ZERO,
+            seller_fee: U256::ZERO,
+        }
+    }
+
+    /// Prices with no decimals, so the quote amount is `amount * price`.
+    fn settlement(orders: Vec<Order>, buyer_quote: u64, seller_base: u64) -> Settlement {
+        let escrow = BTreeMap::from([
+            ((BUYER, QUOTE), U256::from(buyer_quote)),
+            ((SELLER, BASE), U256::from(seller_base)),
+        ]);
+        Settlement::new(orders, escrow, 0)
+    }
+
+    fn amounts(matches: &[MatchResult]) -> Vec<u64> {
+        matches.iter().map(|m| m.match_amount.to::<u64>()).collect()
+    }
+
+    #[test]
+    fn backed_matches_settle_unchanged() {
+        let orders =
+            vec![order(1, BUYER, OrderType::Buy, 10, 5), order(2, SELLER, OrderType::Sell, 10, 5)];
+        let (settled, excluded) = settlement(orders, 50, 5).run(&[], vec![fill(1, 2, 10, 5)]);
+        assert_eq!(amounts(&settled), vec![5]);
+        assert!(excluded.is_empty());
+    }
+
+    #[test]
+    fn missing_and_closed_orders_are_dropped() {
+        let mut cancelled = order(3, SELLER, OrderType::Sell, 10, 5);
+        cancelled.status = OrderStatus::Cancelled;
+        let orders = vec![order(1, BUYER, OrderType::Buy, 10, 10), cancelled];
+        let (settled, excluded) =
+            settlement(orders, 100, 10).run(&[], vec![fill(1, 2, 10, 5), fill(1, 3, 10, 5)]);
+        assert!(settled.is_empty());
+        let reasons: Vec<_> = excluded.iter().map(|e| (e.reason, e.stale_order)).collect();
+        assert_eq!(
+            reasons,
+            vec![(ExclusionReason::MissingOrder, Some(2)), (ExclusionReason::NotOpen, Some(3))]
+        );
+    }
+
+    #[test]
+    fn sides_must_match() {
+        let orders =
+            vec![order(1, BUYER, OrderType::Sell, 10, 5), order(2, SELLER, OrderType::Sell, 10, 5)];
+        let (settled, excluded) = settlement(orders, 50, 5).run(&[], vec![fill(1, 2, 10, 5)]);
+        assert!(settled.is_empty());
+        assert_eq!(excluded[0].reason, ExclusionReason::WrongSide);
+    }
+
+    #[test]
+    fn later_matches_see_earlier_fills() {
+        // The sell has 6 left; the second match only gets the 2 the first
+        // one leaves
+        let orders = vec![
+            order(1, BUYER, OrderType::Buy, 10, 4),
+            order(2, BUYER, OrderType::Buy, 10, 4),
+            order(3, SELLER, OrderType::Sell, 10, 6),
+        ];
+        let (settled, excluded) =
+            settlement(orders, 80, 6).run(&[], vec![fill(1, 3, 10, 4), fill(2, 3, 10, 4)]);
+        assert_eq!(amounts(&settled), vec![4, 2]);
+        assert_eq!(excluded[0].amount, U256::from(2));
+        assert_eq!(excluded[0].reason, ExclusionReason::ExceedsRemaining);
+        assert_eq!(excluded[0].stale_order, Some(3));
+    }
+
+    #[test]
+    fn escrow_cuts_with_the_contracts_rounding() {
+        // With 2 decimals 7 units at 1.50 cost 10.5, rounded down to 10, so
+        // 10 quote tokens back 7 units, not the 6 exact division gives
+        let orders = vec![
+            order(1, BUYER, OrderType::Buy, 150, 10),
+            order(2, SELLER, OrderType::Sell, 150, 10),
+        ];
+        let escrow =
+            BTreeMap::from([((BUYER, QUOTE), U256::from(10)), ((SELLER, BASE), U256::from(10))]);
+        let (settled, excluded) =
+            Settlement::new(orders, escrow, 2).run(&[], vec![fill(1, 2, 150, 10)]);
+        assert_eq!(amounts(&settled), vec![7]);
+        assert_eq!(excluded[0].amount, U256::from(3));
+        assert_eq!(excluded[0].reason, ExclusionReason::InsufficientEscrow);
+        assert_eq!(excluded[0].stale_order, None);
+    }
+
+    #[test]
+    fn withdrawn_seller_escrow_drops_the_match() {
+        let orders =
+            vec![order(1, BUYER, OrderType::Buy, 10, 5), order(2, SELLER, OrderType::Sell, 10, 5)];
+        let (settled, excluded) = settlement(orders, 50, 0).run(&[], vec![fill(1, 2, 10, 5)]);
+        assert!(settled.is_empty());
+        assert_eq!(excluded[0].amount, U256::from(5));
+    }
+
+    #[test]
+    fn triggered_stops_open_before_matching() {
+        let mut stop = order(2, SELLER, OrderType::Sell, 10, 5);
+        stop.status = OrderStatus::PendingTrigger;
+        let orders = vec![order(1, BUYER, OrderType::Buy, 10, 5), stop];
+        let (settled, _) = settlement(orders.clone(), 50, 5).run(&[], vec![fill(1, 2, 10, 5)]);
+        assert!(settled.is_empty());
+        let (settled, _) = settlement(orders, 50, 5).run(&[2], vec![fill(1, 2, 10, 5)]);
+        assert_eq!(amounts(&settled), vec![5]);
+    }
+
+    #[test]
+    fn earlier_payloads_replay_first() {
+        // An earlier payload triggered the stop and sold 3 of its 5; this
+        // one only gets the 2 left
+        let mut stop = order(3, SELLER, OrderType::Sell, 10, 5);
+        stop.status = OrderStatus::PendingTrigger;
+        let orders = vec![
+            order(1, BUYER, OrderType::Buy, 10, 3),
+            order(2, BUYER, OrderType::Buy, 10, 3),
+            stop,
+        ];
+        let mut settlement = settlement(orders, 60, 5);
+        settlement.run(&[3], vec![fill(1, 3, 10, 3)]);
+        let (settled, excluded) = settlement.run(&[], vec![fill(2, 3, 10, 3)]);
+        assert_eq!(amounts(&settled), vec![2]);
+        assert_eq!(excluded[0].reason, ExclusionReason::ExceedsRemaining);
+    }
+
+    #[test]
+    fn quote_buys_fill_until_their_quote_runs_out() {
+        // 25 quote tokens pay for 2 units at 10; the 5 left over buy nothing
+        // more, so the order is filled and the rest refunded
+        let mut buy = order(1, BUYER, OrderType::Buy, 10, 25);
+        buy.denomination = Denomination::Quote;
+        let orders = vec![buy, order(2, SELLER, OrderType::Sell, 10, 5)];
+        let mut settlement = settlement(orders, 25, 5);
+        let (settled, excluded) = settlement.run(&[], vec![fill(1, 2, 10, 3)]);
+        assert_eq!(amounts(&settled), vec![2]);
+        assert_eq!(excluded[0].reason, ExclusionReason::ExceedsRemaining);
+        assert_eq!(excluded[0].stale_order, Some(1));
+        assert_eq!(settlement.orders[&1].filled_amount, U256::from(20));
+        assert_eq!(settlement.orders[&1].status, OrderStatus::Filled);
+        assert_eq!(settlement.balance(BUYER, QUOTE), U256::ZERO);
+    }
+
+    #[test]
+    fn free_fills_of_quote_buys_are_dropped() {
+        // With 2 decimals 2 units at 0.40 cost 0.80, rounded down to nothing
+        let mut buy = order(1, BUYER, OrderType::Buy, 40, 1);
+        buy.denomination = Denomination::Quote;
+        let orders = vec![buy, order(2, SELLER, OrderType::Sell, 40, 1_000)];
+        let escrow =
+            BTreeMap::from([((BUYER, QUOTE), U256::from(1)), ((SELLER, BASE), U256::from(1_000))]);
+        let (settled, excluded) =
+            Settlement::new(orders, escrow, 2).run(&[], vec![fill(1, 2, 40, 2)]);
+        assert!(settled.is_empty());
+        assert_eq!(excluded[0].amount, U256::from(2));
+        assert_eq!(excluded[0].reason, ExclusionReason::FreeFill);
+    }
+
+    #[test]
+    fn fees_shrink_with_the_match() {
+        let orders =
+            vec![order(1, BUYER, OrderType::Buy, 10, 10), order(2, SELLER, OrderType::Sell, 10, 4)];
+        let mut proposed = fill(1, 2, 10, 10);
+        proposed.buyer_fee = U256::from(5);
+        proposed.seller_fee = U256::from(20);
+        let (settled, _) = settlement(orders, 100, 4).run(&[], vec![proposed]);
+        assert_eq!(settled[0].buyer_fee, U256::from(2));
+        assert_eq!(settled[0].seller_fee, U256::from(8));
+    }
+}
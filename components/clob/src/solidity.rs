@@ -6,6 +6,112 @@ sol! {
         uint256 sellOrderId;
         uint256 matchAmount;
         uint256 matchPrice;
+        // Aggressor side; the other order was resting
+        bool buyerIsTaker;
+        // Paid out of the base tokens the buyer receives
+        uint256 buyerFee;
+        // Paid out of the quote tokens the seller receives
+        uint256 sellerFee;
+    }
+
+    // Releases part of an order's open amount without a match, cancelling
+    // the order once nothing is left
+    struct OrderCancellation {
+        uint256 orderId;
+        uint256 amount;
+        uint8 reason;
+    }
+
+    // Moves a post-only order to a price where it no longer crosses
+    struct OrderReprice {
+        uint256 orderId;
+        uint256 newPrice;
+    }
+
+    // A match refused for trading outside the price band
+    struct PriceBandRejection {
+        uint256 buyOrderId;
+        uint256 sellOrderId;
+        uint256 price;
+        uint256 lowerBound;
+        uint256 upperBound;
+    }
+
+    // A market the circuit breaker halted
+    struct MarketHalt {
+        address baseToken;
+        address quoteToken;
+        uint256 price;
+        uint256 referencePrice;
+    }
+
+    // Part of a match left out of the payload because it would have
+    // reverted executeMatch
+    struct ExcludedMatch {
+        uint256 buyOrderId;
+        uint256 sellOrderId;
+        uint256 amount;
+        uint8 reason;
+    }
+
+    // A call auction that finished, moving its market on to `phase`
+    struct MarketPhaseUpdate {
+        address baseToken;
+        address quoteToken;
+        uint8 phase;
+    }
+
+    // Payload submitted to CLOB.sol. stateRoot is zero unless
+    // include_state_root is set in the component config. Triggered stops are
+    // opened before the matches, which may fill them; cancellations and
+    // reprices are applied after all matches. Halts and phase updates set
+    // the phase the contract stores for their market. Band rejections and
+    // excluded matches are only reported.
+    struct ClobOutput {
+        bytes32 stateRoot;
+        OrderMatch[] matches;
+        OrderCancellation[] cancellations;
+        OrderReprice[] reprices;
+        uint256[] triggeredStops;
+        PriceBandRejection[] priceBandRejections;
+        MarketHalt[] halts;
+        ExcludedMatch[] excludedMatches;
+        MarketPhaseUpdate[] phaseUpdates;
+    }
+
+    // Canonical market snapshot; keccak256(abi.encode(MarketSnapshot[])) is
+    // the state root operators compare to detect divergence. Iceberg orders
+    // show their displayed slice as remainingAmount and the rest as
+    // hiddenAmount. unfinishedAuctionPrice is zero unless the batch limit
+    // cut an auction short.
+    struct RestingOrder {
+        uint256 orderId;
+        address trader;
+        uint256 remainingAmount;
+        uint256 hiddenAmount;
+    }
+
+    struct PendingStop {
+        uint256 orderId;
+        address trader;
+        uint256 stopPrice;
+        uint256 remainingAmount;
+    }
+
+    struct PriceLevelSnapshot {
+        uint256 price;
+        RestingOrder[] orders;
+    }
+
+    struct MarketSnapshot {
+        address baseToken;
+        address quoteToken;
+        uint8 phase;
+        uint64 auctionCutoff;
+        uint256 unfinishedAuctionPrice;
+        PriceLevelSnapshot[] bids;
+        PriceLevelSnapshot[] asks;
+        PendingStop[] stops;
     }
 
     // Event definitions from CLOB.sol
@@ -17,10 +123,90 @@ sol! {
         address quoteToken,
         uint256 price,
         uint256 amount,
+        uint256 timestamp,
+        uint8 timeInForce,
+        uint256 expiry,
+        uint8 postOnly,
+        uint256 stopPrice,
+        uint256 displayAmount
+    );
+
+    event OrderCancelled(
+        uint256 indexed orderId,
+        address indexed trader,
+        uint256 remainingAmount
+    );
+
+    event OrderModified(
+        uint256 indexed orderId,
+        address indexed trader,
+        uint256 oldPrice,
+        uint256 newPrice,
+        uint256 newAmount,
+        uint256 remainingAmount
+    );
+
+    event OrderMatched(
+        uint256 indexed buyOrderId,
+        uint256 indexed sellOrderId,
+        address indexed baseToken,
+        address quoteToken,
+        uint256 price,
+        uint256 amount,
         uint256 timestamp
     );
 
+    event OrderPartiallyFilled(
+        uint256 indexed orderId,
+        uint256 filledAmount,
+        uint256 remainingAmount
+    );
+
     event CLOBTrigger(
         uint256 indexed orderId
     );
+
+    event MarketPhaseChanged(
+        address indexed baseToken,
+        address indexed quoteToken,
+        uint8 phase
+    );
+
+    // Read-only view of CLOB.sol used to load orders from chain state
+    struct Order {
+        uint256 id;
+        address trader;
+        uint8 orderType;
+        address baseToken;
+        address quoteToken;
+        uint256 price;
+        uint256 amount;
+        uint256 filledAmount;
+        uint8 status;
+        uint256 timestamp;
+        uint8 timeInForce;
+        uint256 expiry;
+        uint8 postOnly;
+        uint256 stopPrice;
+        uint256 displayAmount;
+    }
+
+    struct Market {
+        address baseToken;
+        address quoteToken;
+    }
+
+    interface ICLOB {
+        function getMarkets() external view returns (Market[] memory);
+        function getOrder(uint256 _orderId) external view returns (Order memory);
+        function getOrderBookOrders(address _baseToken, address _quoteToken) external view returns (uint256[] memory);
+        function getEscrowBalance(address _user, address _token) external view returns (uint256);
+        function getMarketPhase(address _baseToken, address _quoteToken) external view returns (bool isSet, uint8 phase);
+    }
+
+    // Reference price source for price bands, in quote units per base unit
+    // scaled like order prices
+    interface IPriceOracle {
+        function getPrice(address baseToken, address quoteToken) external view returns (uint256);
+    }
 }
@@ -0,0 +1,142 @@
+use crate::trigger::{price_scale, CancelReason, Denomination, Order, OrderKind};
+use wavs_wasi_utils::evm::alloy_primitives::U256;
+
+/// Trading rules of one market, in the units of `Order::price` and
+/// `Order::amount`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MarketSpec {
+    /// Limit prices must be a multiple of it.
+    pub tick_size: U256,
+    /// Amounts, and the displayed slice of an iceberg, must be a multiple of
+    /// it.
+    pub lot_size: U256,
+    pub min_amount: U256,
+    pub max_amount: Option<U256>,
+    /// Smallest `amount * price / 10^price_decimals` of a limit order, in
+    /// quote token units.
+    pub min_notional: U256,
+    /// Decimals of the fixed-point prices, as CLOB.sol divides by when it
+    /// converts an amount to quote tokens.
+    pub price_decimals: u8,
+}
+
+impl MarketSpec {
+    /// Checks `order` against the market's rules. The price of a market
+    /// order is only a slippage cap, so it is not held to the tick size or
+    /// the minimum notional. A quote-denominated buy has no base size to
+    /// hold to the lot and size limits; its amount is its notional.
+    pub fn check(&self, order: &Order) -> Result<(), CancelReason> {
+        if order.denomination == Denomination::Quote {
+            if order.kind == OrderKind::Limit && !(order.price % self.tick_size).is_zero() {
+                return Err(CancelReason::InvalidTick);
+            }
+            if order.amount < self.min_notional {
+                return Err(CancelReason::BelowMinNotional);
+            }
+            return Ok(());
+        }
+        if !(order.amount % self.lot_size).is_zero()
+            || !(order.display_amount % self.lot_size).is_zero()
+        {
+            return Err(CancelReason::InvalidLot);
+        }
+        if order.amount < self.min_amount {
+            return Err(CancelReason::BelowMinSize);
+        }
+        if self.max_amount.is_some_and(|max| order.amount > max) {
+            return Err(CancelReason::AboveMaxSize);
+        }
+        if order.kind == OrderKind::Market {
+            return Ok(());
+        }
+        if !(order.price % self.tick_size).is_zero() {
+            return Err(CancelReason::InvalidTick);
+        }
+        if self.notional(order) < self.min_notional {
+            return Err(CancelReason::BelowMinNotional);
+        }
+        Ok(())
+    }
+
+    /// Quote token value of the whole order at its limit price.
+    fn notional(&self, order: &Order) -> U256 {
+        order.amount.saturating_mul(order.price) / price_scale(self.price_decimals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trigger::test_orders::order;
+    use crate::trigger::OrderType;
+    use wavs_wasi_utils::evm::alloy_primitives::Address;
+
+    const BUYER: Address = Address::repeat_byte(1);
+
+    /// Prices carry two decimals, so a notional is `amount * price / 100`.
+    fn spec() -> MarketSpec {
+        MarketSpec {
+            tick_size: U256::from(5),
+            lot_size: U256::from(10),
+            min_amount: U256::from(20),
+            max_amount: Some(U256::from(1000)),
+            min_notional: U256::from(50),
+            price_decimals: 2,
+        }
+    }
+
+    fn check(price: u64, amount: u64) -> Result<(), CancelReason> {
+        spec().check(&order(1, BUYER, OrderType::Buy, price, amount))
+    }
+
+    #[test]
+    fn limit_orders_are_held_to_every_rule() {
+        assert_eq!(check(100, 100), Ok(()));
+        assert_eq!(check(102, 100), Err(CancelReason::InvalidTick));
+        assert_eq!(check(100, 105), Err(CancelReason::InvalidLot));
+        assert_eq!(check(1000, 10), Err(CancelReason::BelowMinSize));
+        assert_eq!(check(100, 1010), Err(CancelReason::AboveMaxSize));
+        // 40 * 100 / 100 = 40 quote units, short of 50.
+        assert_eq!(check(100, 40), Err(CancelReason::BelowMinNotional));
+        assert_eq!(check(125, 40), Ok(()));
+    }
+
+    #[test]
+    fn market_orders_skip_the_tick_and_notional() {
+        let mut market =
+            Order { kind: OrderKind::Market, ..order(1, BUYER, OrderType::Buy, 102, 20) };
+        assert_eq!(spec().check(&market), Ok(()));
+
+        market.amount = U256::from(25);
+        assert_eq!(spec().check(&market), Err(CancelReason::InvalidLot));
+    }
+
+    #[test]
+    fn iceberg_display_must_be_whole_lots() {
+        let mut iceberg =
+            Order { display_amount: U256::from(30), ..order(1, BUYER, OrderType::Buy, 100, 100) };
+        assert_eq!(spec().check(&iceberg), Ok(()));
+
+        iceberg.display_amount = U256::from(35);
+        assert_eq!(spec().check(&iceberg), Err(CancelReason::InvalidLot));
+    }
+
+    #[test]
+    fn quote_buys_are_held_to_tick_and_notional_only() {
+        // 55 quote tokens is off the lot, which only applies to base
+        // amounts.
+        let mut buy =
+            Order { denomination: Denomination::Quote, ..order(1, BUYER, OrderType::Buy, 100, 55) };
+        assert_eq!(spec().check(&buy), Ok(()));
+
+        buy.amount = U256::from(45);
+        assert_eq!(spec().check(&buy), Err(CancelReason::BelowMinNotional));
+
+        buy.amount = U256::from(55);
+        buy.price = U256::from(102);
+        assert_eq!(spec().check(&buy), Err(CancelReason::InvalidTick));
+
+        buy.kind = OrderKind::Market;
+        assert_eq!(spec().check(&buy), Ok(()));
+    }
+}
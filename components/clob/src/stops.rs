@@ -0,0 +1,256 @@
+use crate::market::{market_list, MarketKey};
+use crate::solidity::PendingStop;
+use crate::trigger::{CancelReason, Order, OrderCancellation, OrderStatus};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use wavs_wasi_utils::evm::alloy_primitives::U256;
+
+/// Stop orders of one market waiting for their stop price. The last trade
+/// price they are compared against is the market's
+/// [`crate::market::MarketRegistry::last_price`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StopMarket {
+    /// Pending stop orders by ascending order id.
+    pub orders: Vec<Order>,
+}
+
+/// Stop and stop-limit orders kept outside the order books until the last
+/// trade price crosses their stop price.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Stops")]
+pub struct StopBook {
+    #[serde(with = "market_list")]
+    pub markets: BTreeMap<MarketKey, StopMarket>,
+    /// Order id -> market of every held order. Rebuilt on load.
+    #[serde(skip)]
+    orders: BTreeMap<u64, MarketKey>,
+}
+
+/// The persisted part of a [`StopBook`].
+#[derive(Deserialize)]
+struct Stops {
+    #[serde(with = "market_list")]
+    markets: BTreeMap<MarketKey, StopMarket>,
+}
+
+impl From<Stops> for StopBook {
+    fn from(persisted: Stops) -> Self {
+        let orders = persisted
+            .markets
+            .iter()
+            .flat_map(|(key, market)| market.orders.iter().map(move |order| (order.id, *key)))
+            .collect();
+        Self { markets: persisted.markets, orders }
+    }
+}
+
+impl StopBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load_from_file(file_path: &str) -> Self {
+        if Path::new(file_path).exists() {
+            match fs::read_to_string(file_path) {
+                Ok(contents) => match serde_json::from_str::<StopBook>(&contents) {
+                    Ok(stops) => {
+                        println!("📂 Loaded {} pending stop orders from file", stops.order_count());
+                        return stops;
+                    }
+                    Err(e) => println!("⚠️ Failed to parse stop order file: {}", e),
+                },
+                Err(e) => println!("⚠️ Failed to read stop order file: {}", e),
+            }
+        } else {
+            println!("📁 Stop order file not found, starting with no stop orders");
+        }
+        Self::new()
+    }
+
+    pub fn save_to_file(&self, file_path: &str) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(file_path, contents)?;
+        println!("💾 Saved {} pending stop orders to file", self.order_count());
+        Ok(())
+    }
+
+    pub fn order_count(&self) -> usize {
+        self.markets.values().map(|market| market.orders.len()).sum()
+    }
+
+    /// The market a pending stop order belongs to, if it is held here.
+    pub fn market_of(&self, order_id: u64) -> Option<MarketKey> {
+        self.orders.get(&order_id).copied()
+    }
+
+    /// Holds a stop order until it triggers. Orders already held are left
+    /// untouched.
+    pub fn add_order(&mut self, order: Order) -> MarketKey {
+        let key = MarketKey::of(&order);
+        if self.market_of(order.id).is_none() {
+            let orders = &mut self.markets.entry(key).or_default().orders;
+            let position = orders.partition_point(|o| o.id < order.id);
+            self.orders.insert(order.id, key);
+            orders.insert(position, order);
+        }
+        key
+    }
+
+    pub fn remove_order(&mut self, order_id: u64) -> Option<Order> {
+        let key = self.orders.remove(&order_id)?;
+        let orders = &mut self.markets.get_mut(&key)?.orders;
+        let position = orders.binary_search_by_key(&order_id, |o| o.id).ok()?;
+        Some(orders.remove(position))
+    }
+
+    /// Canonical form of the stop orders waiting in `market`, by ascending
+    /// order id.
+    pub fn snapshot(&self, market: MarketKey) -> Vec<PendingStop> {
+        let Some(stops) = self.markets.get(&market) else {
+            return Vec::new();
+        };
+        stops
+            .orders
+            .iter()
+            .map(|order| PendingStop {
+                orderId: U256::from(order.id),
+                trader: order.trader,
+                stopPrice: order.stop_price,
+                remainingAmount: order.amount - order.filled_amount,
+            })
+            .collect()
+    }
+
+    /// Whether a trade at `last_price` makes any stop order in `market`
+    /// ready to enter the book.
+    pub fn has_triggered(&self, market: MarketKey, last_price: Option<U256>) -> bool {
+        self.markets.get(&market).is_some_and(|stops| {
+            stops
+                .orders
+                .iter()
+                .any(|order| last_price.is_some_and(|price| order.stop_triggered(price)))
+        })
+    }
+
+    /// Takes out every stop order in `market` that a trade at `last_price`
+    /// has triggered, in order id order, ready to be added to the book.
+    pub fn activate(&mut self, market: MarketKey, last_price: Option<U256>) -> Vec<Order> {
+        let Some(stops) = self.markets.get_mut(&market) else {
+            return Vec::new();
+        };
+        let (mut activated, waiting): (Vec<Order>, Vec<Order>) = std::mem::take(&mut stops.orders)
+            .into_iter()
+            .partition(|order| last_price.is_some_and(|price| order.stop_triggered(price)));
+        stops.orders = waiting;
+        for order in &mut activated {
+            self.orders.remove(&order.id);
+            order.status = OrderStatus::Open;
+        }
+        activated
+    }
+
+    /// Removes good-till-date stop orders that expired before triggering.
+    pub fn expire_orders(&mut self, now: u64) -> Vec<OrderCancellation> {
+        let mut expired = Vec::new();
+        for market in self.markets.values_mut() {
+            market.orders.retain(|order| {
+                if !order.is_expired(now) {
+                    return true;
+                }
+                expired.push(OrderCancellation {
+                    order_id: order.id,
+                    amount: order.amount - order.filled_amount,
+                    reason: CancelReason::Expired,
+                });
+                false
+            });
+        }
+        for cancellation in &expired {
+            self.orders.remove(&cancellation.order_id);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trigger::test_orders::order;
+    use crate::trigger::{OrderType, TimeInForce};
+    use wavs_wasi_utils::evm::alloy_primitives::Address;
+
+    const BUYER: Address = Address::repeat_byte(1);
+    const SELLER: Address = Address::repeat_byte(2);
+
+    /// A stop order waiting for a trade at `stop_price`.
+    fn stop(id: u64, trader: Address, order_type: OrderType, stop_price: u64) -> Order {
+        let mut order = order(id, trader, order_type, 100, 10);
+        order.status = OrderStatus::PendingTrigger;
+        order.stop_price = U256::from(stop_price);
+        order
+    }
+
+    fn ids(orders: &[Order]) -> Vec<u64> {
+        orders.iter().map(|order| order.id).collect()
+    }
+
+    #[test]
+    fn buy_stops_trigger_at_or_above_and_sell_stops_at_or_below() {
+        let mut stops = StopBook::new();
+        let market = stops.add_order(stop(1, BUYER, OrderType::Buy, 105));
+        stops.add_order(stop(2, SELLER, OrderType::Sell, 95));
+
+        assert!(!stops.has_triggered(market, None));
+        assert!(!stops.has_triggered(market, Some(U256::from(100))));
+        assert!(stops.activate(market, Some(U256::from(100))).is_empty());
+
+        assert!(stops.has_triggered(market, Some(U256::from(105))));
+        let activated = stops.activate(market, Some(U256::from(105)));
+        assert_eq!(ids(&activated), vec![1]);
+        assert_eq!(activated[0].status, OrderStatus::Open);
+
+        let activated = stops.activate(market, Some(U256::from(95)));
+        assert_eq!(ids(&activated), vec![2]);
+        assert_eq!(stops.order_count(), 0);
+        assert_eq!(stops.market_of(2), None);
+    }
+
+    #[test]
+    fn activation_follows_order_id_not_arrival() {
+        let mut stops = StopBook::new();
+        let market = stops.add_order(stop(3, SELLER, OrderType::Sell, 95));
+        stops.add_order(stop(1, SELLER, OrderType::Sell, 90));
+        stops.add_order(stop(2, SELLER, OrderType::Sell, 99));
+
+        // The stop at 90 has not triggered and keeps waiting.
+        let activated = stops.activate(market, Some(U256::from(95)));
+        assert_eq!(ids(&activated), vec![2, 3]);
+        assert_eq!(stops.market_of(1), Some(market));
+        assert!(stops.remove_order(1).is_some());
+        assert_eq!(stops.order_count(), 0);
+    }
+
+    #[test]
+    fn expired_stops_are_cancelled_before_triggering() {
+        let mut stops = StopBook::new();
+        let mut dated = stop(1, BUYER, OrderType::Buy, 105);
+        dated.time_in_force = TimeInForce::Gtd;
+        dated.expires_at = 50;
+        dated.filled_amount = U256::from(4);
+        let market = stops.add_order(dated);
+        stops.add_order(stop(2, BUYER, OrderType::Buy, 105));
+
+        assert!(stops.expire_orders(49).is_empty());
+        let expired = stops.expire_orders(50);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].order_id, 1);
+        assert_eq!(expired[0].amount, U256::from(6));
+        assert_eq!(expired[0].reason, CancelReason::Expired);
+
+        assert_eq!(stops.market_of(1), None);
+        assert_eq!(ids(&stops.activate(market, Some(U256::from(105)))), vec![2]);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::trigger::{Order, OrderKind, OrderType};
+use std::str::FromStr;
+use wavs_wasi_utils::evm::alloy_primitives::U256;
+
+/// The price a crossing pair trades at in continuous matching. Whatever the
+/// policy, the price never falls outside either order's limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TradePrice {
+    /// The resting order's price, so the taker gets any price improvement.
+    #[default]
+    Maker,
+    /// Halfway between the two limits, rounded down. A pair with a market
+    /// order trades at the maker's price, since a market order's price is
+    /// only its slippage cap.
+    Midpoint,
+    /// The single price an auction of the whole book would clear at, as of
+    /// the start of the matching pass. Falls back to the maker's price when
+    /// nothing crosses.
+    Auction,
+    /// One given price for every pair, as an auction clears at. Not
+    /// configurable.
+    Fixed(U256),
+}
+
+impl TradePrice {
+    /// The price `buy` and `sell` trade at. `taker_side` is the side of the
+    /// order that arrived last, and `uniform` the book's clearing price for
+    /// [`TradePrice::Auction`].
+    pub fn price(
+        self,
+        buy: &Order,
+        sell: &Order,
+        taker_side: OrderType,
+        uniform: Option<U256>,
+    ) -> U256 {
+        let maker_price = match taker_side {
+            OrderType::Buy => sell.price,
+            OrderType::Sell => buy.price,
+        };
+        match self {
+            Self::Maker => maker_price,
+            Self::Midpoint if buy.kind == OrderKind::Market || sell.kind == OrderKind::Market => {
+                maker_price
+            }
+            Self::Midpoint => sell.price + (buy.price - sell.price) / U256::from(2),
+            Self::Auction => {
+                uniform.map_or(maker_price, |price| price.clamp(sell.price, buy.price))
+            }
+            Self::Fixed(price) => price,
+        }
+    }
+}
+
+impl FromStr for TradePrice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "maker" => Ok(Self::Maker),
+            "midpoint" => Ok(Self::Midpoint),
+            "auction" => Ok(Self::Auction),
+            _ => Err(format!("unknown trade price {s:?}, expected maker, midpoint or auction")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trigger::test_orders;
+    use wavs_wasi_utils::evm::alloy_primitives::Address;
+
+    fn order(order_type: OrderType, kind: OrderKind, price: u64) -> Order {
+        Order { kind, ..test_orders::order(1, Address::ZERO, order_type, price, 1) }
+    }
+
+    fn price(policy: TradePrice, taker_side: OrderType, uniform: Option<u64>) -> U256 {
+        let buy = order(OrderType::Buy, OrderKind::Limit, 110);
+        let sell = order(OrderType::Sell, OrderKind::Limit, 101);
+        policy.price(&buy, &sell, taker_side, uniform.map(U256::from))
+    }
+
+    #[test]
+    fn maker_price_follows_the_resting_side() {
+        assert_eq!(price(TradePrice::Maker, OrderType::Buy, None), U256::from(101));
+        assert_eq!(price(TradePrice::Maker, OrderType::Sell, None), U256::from(110));
+    }
+
+    #[test]
+    fn midpoint_rounds_down() {
+        assert_eq!(price(TradePrice::Midpoint, OrderType::Buy, None), U256::from(105));
+        assert_eq!(price(TradePrice::Midpoint, OrderType::Sell, None), U256::from(105));
+    }
+
+    #[test]
+    fn midpoint_ignores_a_market_orders_cap() {
+        let buy = order(OrderType::Buy, OrderKind::Market, 1_000);
+        let sell = order(OrderType::Sell, OrderKind::Limit, 101);
+        let price = TradePrice::Midpoint.price(&buy, &sell, OrderType::Buy, None);
+        assert_eq!(price, U256::from(101));
+    }
+
+    #[test]
+    fn auction_price_stays_within_both_limits() {
+        assert_eq!(price(TradePrice::Auction, OrderType::Buy, Some(104)), U256::from(104));
+        assert_eq!(price(TradePrice::Auction, OrderType::Buy, Some(120)), U256::from(110));
+        assert_eq!(price(TradePrice::Auction, OrderType::Buy, Some(90)), U256::from(101));
+        assert_eq!(price(TradePrice::Auction, OrderType::Sell, None), U256::from(110));
+    }
+}
@@ -1,15 +1,23 @@
+use crate::config::SelfTradePrevention;
+use crate::market::{MarketKey, MarketPhase};
+use crate::settlement::ExcludedMatch;
 use crate::solidity;
 use alloy_sol_types::SolValue;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use wavs_wasi_utils::decode_event_log_data;
-use wavs_wasi_utils::evm::alloy_primitives::{Address, U256};
+use wavs_wasi_utils::evm::alloy_primitives::{Address, B256, U256};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Order {
     pub id: u64,
     pub trader: Address,
     pub order_type: OrderType,
+    #[serde(default)]
+    pub kind: OrderKind,
+    /// Units of `amount` and `filled_amount`.
+    #[serde(default)]
+    pub denomination: Denomination,
     pub base_token: Address,
     pub quote_token: Address,
     pub price: U256,
@@ -17,6 +25,83 @@ pub struct Order {
     pub filled_amount: U256,
     pub status: OrderStatus,
     pub timestamp: u64,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    /// Unix time at which a good-till-date order expires, zero otherwise.
+    #[serde(default)]
+    pub expires_at: u64,
+    #[serde(default)]
+    pub post_only: PostOnly,
+    /// Last trade price at which a stop order enters the book, zero for
+    /// orders that are not conditional.
+    #[serde(default)]
+    pub stop_price: U256,
+    /// Slice of an iceberg order shown in the book at a time, zero for
+    /// orders that show their whole size.
+    #[serde(default)]
+    pub display_amount: U256,
+}
+
+impl Order {
+    /// Whether the order must be filled on arrival instead of resting.
+    pub fn is_immediate(&self) -> bool {
+        self.kind == OrderKind::Market
+            || matches!(self.time_in_force, TimeInForce::Ioc | TimeInForce::Fok)
+    }
+
+    /// Whether a trade at `last_price` activates this stop order. Buy stops
+    /// trigger as the price rises to the stop, sell stops as it falls to it.
+    pub fn stop_triggered(&self, last_price: U256) -> bool {
+        match self.order_type {
+            OrderType::Buy => last_price >= self.stop_price,
+            OrderType::Sell => last_price <= self.stop_price,
+        }
+    }
+
+    /// How much of the order's own amount a fill of `amount` base units at
+    /// `price` uses up: the base amount itself, or for a quote-denominated
+    /// buy its quote cost, rounded down as CLOB.sol does.
+    pub fn fill_units(&self, amount: U256, price: U256, price_decimals: u8) -> U256 {
+        match self.denomination {
+            Denomination::Base => amount,
+            Denomination::Quote => amount * price / price_scale(price_decimals),
+        }
+    }
+
+    /// The most base units that `units` of the order's own amount pay for
+    /// at `price`. A quote-denominated buy is sized so that the cost of the
+    /// result never exceeds `units`, however it rounds.
+    pub fn capacity(&self, units: U256, price: U256, price_decimals: u8) -> U256 {
+        match self.denomination {
+            Denomination::Base => units,
+            Denomination::Quote => units
+                .saturating_mul(price_scale(price_decimals))
+                .checked_div(price)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether a fill of `amount` base units at `price` would cost a
+    /// quote-denominated buy nothing once rounded down. Such fills are never
+    /// made, as they would hand it base tokens for free.
+    pub fn is_free_fill(&self, amount: U256, price: U256, price_decimals: u8) -> bool {
+        self.denomination == Denomination::Quote
+            && self.fill_units(amount, price, price_decimals).is_zero()
+    }
+
+    /// Whether `units` left of the order can no longer fill anything. A
+    /// quote-denominated buy is done once what is left does not pay for a
+    /// fill that costs anything at its limit price; CLOB.sol then refunds the
+    /// rest.
+    pub fn is_exhausted(&self, units: U256, price_decimals: u8) -> bool {
+        let capacity = self.capacity(units, self.price, price_decimals);
+        capacity.is_zero() || self.is_free_fill(capacity, self.price, price_decimals)
+    }
+
+    /// Whether a good-till-date order has expired at block time `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.time_in_force == TimeInForce::Gtd && now >= self.expires_at
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -25,18 +110,233 @@ pub enum OrderType {
     Sell,
 }
 
+/// How an order executes. For a market order `price` is the worst price the
+/// trader accepts (the slippage cap) rather than a limit to rest at.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderKind {
+    #[default]
+    Limit,
+    /// Sweeps the opposite side up to `price`; whatever cannot fill is
+    /// cancelled instead of resting.
+    Market,
+}
+
+/// What an order's amount counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Denomination {
+    /// Base tokens to buy or sell.
+    #[default]
+    Base,
+    /// Quote tokens a buy order spends. It fills until they run out, at any
+    /// price up to `price`.
+    Quote,
+}
+
+/// `10^price_decimals`, what CLOB.sol divides `amount * price` by.
+pub fn price_scale(price_decimals: u8) -> U256 {
+    U256::from(10).pow(U256::from(price_decimals))
+}
+
+/// How long an order may stay in the book.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeInForce {
+    /// Rests until filled or cancelled.
+    #[default]
+    Gtc,
+    /// Fills what it can on arrival; the rest is cancelled.
+    Ioc,
+    /// Fills completely on arrival or is killed without filling.
+    Fok,
+    /// Rests until block time reaches `Order::expires_at`.
+    Gtd,
+}
+
+impl TimeInForce {
+    /// Decode the `TimeInForce` enum as emitted by CLOB.sol
+    pub fn from_sol(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(TimeInForce::Gtc),
+            1 => Ok(TimeInForce::Ioc),
+            2 => Ok(TimeInForce::Fok),
+            3 => Ok(TimeInForce::Gtd),
+            _ => Err(anyhow::anyhow!("Invalid time in force: {}", value)),
+        }
+    }
+}
+
+/// What to do with a post-only order that would take liquidity on arrival.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PostOnly {
+    /// Not post-only; the order may take liquidity.
+    #[default]
+    Off,
+    /// Cancel the order instead of letting it cross.
+    Reject,
+    /// Re-price the order to one tick behind the best opposite price.
+    Slide,
+}
+
+impl PostOnly {
+    /// Decode the `PostOnly` enum as emitted by CLOB.sol
+    pub fn from_sol(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(PostOnly::Off),
+            1 => Ok(PostOnly::Reject),
+            2 => Ok(PostOnly::Slide),
+            _ => Err(anyhow::anyhow!("Invalid post-only flag: {}", value)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OrderStatus {
     Open,
     PartiallyFilled,
     Filled,
     Cancelled,
+    /// A stop order waiting for its stop price
+    PendingTrigger,
+}
+
+/// Decode the `OrderType` enum as emitted by CLOB.sol into side, kind and
+/// denomination
+pub fn decode_order_type(value: u8) -> Result<(OrderType, OrderKind, Denomination)> {
+    match value {
+        0 => Ok((OrderType::Buy, OrderKind::Limit, Denomination::Base)),
+        1 => Ok((OrderType::Sell, OrderKind::Limit, Denomination::Base)),
+        2 => Ok((OrderType::Buy, OrderKind::Market, Denomination::Base)),
+        3 => Ok((OrderType::Sell, OrderKind::Market, Denomination::Base)),
+        4 => Ok((OrderType::Buy, OrderKind::Limit, Denomination::Quote)),
+        5 => Ok((OrderType::Buy, OrderKind::Market, Denomination::Quote)),
+        _ => Err(anyhow::anyhow!("Invalid order type: {}", value)),
+    }
+}
+
+impl OrderStatus {
+    /// Decode the `OrderStatus` enum as stored by CLOB.sol
+    pub fn from_sol(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(OrderStatus::Open),
+            1 => Ok(OrderStatus::PartiallyFilled),
+            2 => Ok(OrderStatus::Filled),
+            3 => Ok(OrderStatus::Cancelled),
+            4 => Ok(OrderStatus::PendingTrigger),
+            _ => Err(anyhow::anyhow!("Invalid order status: {}", value)),
+        }
+    }
+
+    /// Whether the contract will still accept matches against the order
+    pub fn is_open(self) -> bool {
+        matches!(self, OrderStatus::Open | OrderStatus::PartiallyFilled)
+    }
+
+    /// Whether the order still needs tracking, either in the book or as a
+    /// stop waiting to trigger
+    pub fn is_live(self) -> bool {
+        self.is_open() || self == OrderStatus::PendingTrigger
+    }
+}
+
+impl TryFrom<solidity::Order> for Order {
+    type Error = anyhow::Error;
+
+    fn try_from(order: solidity::Order) -> Result<Self> {
+        let (order_type, kind, denomination) = decode_order_type(order.orderType)?;
+        Ok(Order {
+            id: order.id.to::<u64>(),
+            trader: order.trader,
+            order_type,
+            kind,
+            denomination,
+            base_token: order.baseToken,
+            quote_token: order.quoteToken,
+            price: order.price,
+            amount: order.amount,
+            filled_amount: order.filledAmount,
+            status: OrderStatus::from_sol(order.status)?,
+            timestamp: order.timestamp.to::<u64>(),
+            time_in_force: TimeInForce::from_sol(order.timeInForce)?,
+            expires_at: order.expiry.to::<u64>(),
+            post_only: PostOnly::from_sol(order.postOnly)?,
+            stop_price: order.stopPrice,
+            display_amount: order.displayAmount,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OrderBookEntry {
     pub order: Order,
     pub remaining_amount: U256,
+    /// Currently displayed slice of an iceberg order. The rest of
+    /// `remaining_amount` is hidden.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visible_amount: Option<U256>,
+    /// Queue position an order took at the back of its level when it last
+    /// replenished as an iceberg or lost its priority to an amendment, as
+    /// `(order id watermark, sequence)`. Other entries are queued by
+    /// `(order id, 0)`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requeued_at: Option<(u64, u32)>,
+    /// When the order entered the book, as `(order id watermark,
+    /// sequence)`. Orders that rest as soon as they are placed arrive at
+    /// `(order id, 0)`; a stop order arrives when it triggers, after every
+    /// order placed before then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arrived_at: Option<(u64, u32)>,
+}
+
+impl OrderBookEntry {
+    pub fn new(order: Order, remaining_amount: U256) -> Self {
+        let visible_amount =
+            (!order.display_amount.is_zero()).then(|| order.display_amount.min(remaining_amount));
+        Self { order, remaining_amount, visible_amount, requeued_at: None, arrived_at: None }
+    }
+
+    /// The amount other orders can match against right now, in the
+    /// order's own units.
+    pub fn available(&self) -> U256 {
+        self.visible_amount.unwrap_or(self.remaining_amount)
+    }
+
+    /// The base amount other orders can match against right now at
+    /// `price`.
+    pub fn available_at(&self, price: U256, price_decimals: u8) -> U256 {
+        self.order.capacity(self.available(), price, price_decimals)
+    }
+
+    /// Position within the price level; lower keys match first.
+    pub fn queue_key(&self) -> (u64, u32) {
+        self.requeued_at.unwrap_or((self.order.id, 0))
+    }
+
+    /// Arrival sequence; of two crossing orders the one that arrived last
+    /// is the taker. Unlike the queue key it does not move when an iceberg
+    /// replenishes.
+    pub fn arrival(&self) -> (u64, u32) {
+        self.arrived_at.unwrap_or((self.order.id, 0))
+    }
+}
+
+/// New terms of an amended order, from an `OrderModified` event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrderModification {
+    pub order_id: u64,
+    pub price: U256,
+    /// Total amount, including what has already filled
+    pub amount: U256,
+    /// Amount still open on-chain
+    pub remaining_amount: U256,
+}
+
+impl OrderModification {
+    /// `order` with these terms applied.
+    pub fn apply(&self, mut order: Order) -> Order {
+        order.price = self.price;
+        order.amount = self.amount;
+        order.filled_amount = self.amount - self.remaining_amount;
+        order
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,6 +345,249 @@ pub struct MatchResult {
     pub sell_order_id: U256,
     pub match_amount: U256,
     pub match_price: U256,
+    /// Side of the order that arrived last and took the liquidity.
+    #[serde(default = "legacy_taker_side")]
+    pub taker_side: OrderType,
+    /// Fee the buyer pays out of the base tokens it receives.
+    #[serde(default)]
+    pub buyer_fee: U256,
+    /// Fee the seller pays out of the quote tokens it receives.
+    #[serde(default)]
+    pub seller_fee: U256,
+}
+
+/// Fills proposed before the taker was recorded carry no fees, so the side
+/// they report does not change what anyone pays.
+fn legacy_taker_side() -> OrderType {
+    OrderType::Buy
+}
+
+/// A match produced by the matching engine, together with both orders as
+/// they were when matched.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fill {
+    pub result: MatchResult,
+    pub buy_order: Order,
+    pub sell_order: Order,
+}
+
+impl Fill {
+    /// How much of order `order_id`'s own amount this fill uses up; zero if
+    /// the order is not part of it.
+    pub fn units_of(&self, order_id: u64, price_decimals: u8) -> U256 {
+        let result = &self.result;
+        [&self.buy_order, &self.sell_order]
+            .into_iter()
+            .find(|order| order.id == order_id)
+            .map_or(U256::ZERO, |order| {
+                order.fill_units(result.match_amount, result.match_price, price_decimals)
+            })
+    }
+}
+
+/// Why the engine released an order's remaining amount
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CancelReason {
+    /// A market order ran out of liquidity within its slippage cap
+    MarketUnfilled,
+    /// An immediate-or-cancel order had nothing left to match
+    IocUnfilled,
+    /// A fill-or-kill order could not be filled completely in one batch
+    FokKilled,
+    /// A good-till-date order reached its expiry
+    Expired,
+    /// A post-only order would have taken liquidity
+    PostOnlyRejected,
+    /// Self-trade prevention took the amount out of the order
+    SelfTrade,
+    /// The order would have traded outside the market's price band
+    PriceBand,
+    /// The limit price is not a multiple of the market's tick size
+    InvalidTick,
+    /// The amount is not a multiple of the market's lot size
+    InvalidLot,
+    /// The amount is below the market's minimum order size
+    BelowMinSize,
+    /// The amount is above the market's maximum order size
+    AboveMaxSize,
+    /// The order is worth less than the market's minimum notional
+    BelowMinNotional,
+}
+
+impl CancelReason {
+    /// Encoding of the reason in `OrderCancellation.reason`
+    pub fn to_sol(self) -> u8 {
+        match self {
+            CancelReason::MarketUnfilled => 0,
+            CancelReason::IocUnfilled => 1,
+            CancelReason::FokKilled => 2,
+            CancelReason::Expired => 3,
+            CancelReason::PostOnlyRejected => 4,
+            CancelReason::SelfTrade => 5,
+            CancelReason::PriceBand => 6,
+            CancelReason::InvalidTick => 7,
+            CancelReason::InvalidLot => 8,
+            CancelReason::BelowMinSize => 9,
+            CancelReason::AboveMaxSize => 10,
+            CancelReason::BelowMinNotional => 11,
+        }
+    }
+}
+
+/// An amount the engine took out of the book without filling it, so the
+/// contract can refund the escrow behind it. The order is cancelled once
+/// nothing is left open.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderCancellation {
+    pub order_id: u64,
+    pub amount: U256,
+    pub reason: CancelReason,
+}
+
+impl OrderCancellation {
+    pub fn to_solidity(&self) -> solidity::OrderCancellation {
+        solidity::OrderCancellation {
+            orderId: U256::from(self.order_id),
+            amount: self.amount,
+            reason: self.reason.to_sol(),
+        }
+    }
+}
+
+/// A post-only order moved away from the opposite side so it rests instead
+/// of crossing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderReprice {
+    pub order_id: u64,
+    pub old_price: U256,
+    pub new_price: U256,
+}
+
+impl OrderReprice {
+    pub fn to_solidity(&self) -> solidity::OrderReprice {
+        solidity::OrderReprice { orderId: U256::from(self.order_id), newPrice: self.new_price }
+    }
+}
+
+/// A match refused because its price was outside the market's price band.
+/// The order of the pair that arrived last is cancelled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceBandRejection {
+    pub buy_order_id: u64,
+    pub sell_order_id: u64,
+    pub price: U256,
+    pub lower: U256,
+    pub upper: U256,
+}
+
+impl PriceBandRejection {
+    pub fn to_solidity(&self) -> solidity::PriceBandRejection {
+        solidity::PriceBandRejection {
+            buyOrderId: U256::from(self.buy_order_id),
+            sellOrderId: U256::from(self.sell_order_id),
+            price: self.price,
+            lowerBound: self.lower,
+            upperBound: self.upper,
+        }
+    }
+}
+
+/// A matching price that tripped the circuit breaker, or an auction price
+/// outside the price band. `reference` is the price it moved away from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreak {
+    pub price: U256,
+    pub reference: U256,
+}
+
+/// A market the engine halted in this run
+#[derive(Debug, Clone)]
+pub struct MarketHalt {
+    pub market: MarketKey,
+    pub price: U256,
+    pub reference: U256,
+}
+
+impl MarketHalt {
+    pub fn to_solidity(&self) -> solidity::MarketHalt {
+        solidity::MarketHalt {
+            baseToken: self.market.base_token,
+            quoteToken: self.market.quote_token,
+            price: self.price,
+            referencePrice: self.reference,
+        }
+    }
+}
+
+/// A market a finished call auction moved on to `phase` in this run
+#[derive(Debug, Clone)]
+pub struct MarketPhaseUpdate {
+    pub market: MarketKey,
+    pub phase: MarketPhase,
+}
+
+impl MarketPhaseUpdate {
+    pub fn to_solidity(&self) -> solidity::MarketPhaseUpdate {
+        solidity::MarketPhaseUpdate {
+            baseToken: self.market.base_token,
+            quoteToken: self.market.quote_token,
+            phase: self.phase.to_sol(),
+        }
+    }
+}
+
+/// What the post-only check did to an incoming order
+#[derive(Debug, Clone)]
+pub enum PostOnlyNotice {
+    Rejected(OrderCancellation),
+    Repriced(OrderReprice),
+}
+
+/// A match between two orders of the same trader that self-trade
+/// prevention stopped
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelfTrade {
+    pub trader: Address,
+    pub resting_order_id: u64,
+    pub incoming_order_id: u64,
+    /// The amount that would have traded
+    pub amount: U256,
+    pub action: SelfTradePrevention,
+}
+
+impl SelfTrade {
+    /// Describes a crossing pair from one trader; the order that arrived
+    /// first is the resting one. The amount is in base units, a
+    /// quote-denominated buy counting what it pays for at its limit price.
+    pub fn between(
+        buy: &OrderBookEntry,
+        sell: &OrderBookEntry,
+        action: SelfTradePrevention,
+        price_decimals: u8,
+    ) -> Self {
+        let (resting, incoming) =
+            if buy.arrival() < sell.arrival() { (buy, sell) } else { (sell, buy) };
+        SelfTrade {
+            trader: buy.order.trader,
+            resting_order_id: resting.order.id,
+            incoming_order_id: incoming.order.id,
+            amount: buy.available_at(buy.order.price, price_decimals).min(sell.available()),
+            action,
+        }
+    }
+}
+
+/// Everything one matching pass produced
+#[derive(Debug, Clone, Default)]
+pub struct MatchOutcome {
+    pub fills: Vec<Fill>,
+    pub cancellations: Vec<OrderCancellation>,
+    pub self_trades: Vec<SelfTrade>,
+    pub band_rejections: Vec<PriceBandRejection>,
+    /// Set when matching stopped because the circuit breaker tripped
+    pub breaker: Option<CircuitBreak>,
+    /// Matching stopped at the batch limit with more left to match
+    pub carried_over: bool,
 }
 
 impl MatchResult {
@@ -54,14 +597,72 @@ impl MatchResult {
             sellOrderId: self.sell_order_id,
             matchAmount: self.match_amount,
             matchPrice: self.match_price,
+            buyerIsTaker: self.taker_side == OrderType::Buy,
+            buyerFee: self.buyer_fee,
+            sellerFee: self.seller_fee,
+        }
+    }
+}
+
+/// Everything a trigger run reports back to the CLOB contract
+#[derive(Debug, Clone, Default)]
+pub struct TriggerOutput {
+    pub matches: Vec<MatchResult>,
+    pub cancellations: Vec<OrderCancellation>,
+    pub reprices: Vec<OrderReprice>,
+    /// Self-trades prevented during this run, for the compliance report.
+    /// Not sent to the contract; the cancellations they caused are.
+    pub self_trades: Vec<SelfTrade>,
+    /// Stop orders that entered the book in this run
+    pub triggered_stops: Vec<u64>,
+    pub band_rejections: Vec<PriceBandRejection>,
+    pub halts: Vec<MarketHalt>,
+    pub phase_updates: Vec<MarketPhaseUpdate>,
+    /// Matches, or parts of them, the settlement check kept out of
+    /// `matches`
+    pub excluded: Vec<ExcludedMatch>,
+    /// Book state root to commit on-chain, or zero when not shared
+    pub state_root: B256,
+}
+
+impl TriggerOutput {
+    /// Whether there is anything for the contract to act on
+    pub fn is_empty(&self) -> bool {
+        self.matches.is_empty()
+            && self.cancellations.is_empty()
+            && self.reprices.is_empty()
+            && self.triggered_stops.is_empty()
+            && self.band_rejections.is_empty()
+            && self.halts.is_empty()
+            && self.phase_updates.is_empty()
+            && self.excluded.is_empty()
+    }
+
+    pub fn record(&mut self, notice: PostOnlyNotice) {
+        match notice {
+            PostOnlyNotice::Rejected(cancellation) => self.cancellations.push(cancellation),
+            PostOnlyNotice::Repriced(reprice) => self.reprices.push(reprice),
+        }
+    }
+
+    pub fn to_solidity(&self) -> solidity::ClobOutput {
+        solidity::ClobOutput {
+            stateRoot: self.state_root,
+            matches: self.matches.iter().map(|m| m.to_solidity()).collect(),
+            cancellations: self.cancellations.iter().map(|c| c.to_solidity()).collect(),
+            reprices: self.reprices.iter().map(|r| r.to_solidity()).collect(),
+            triggeredStops: self.triggered_stops.iter().map(|id| U256::from(*id)).collect(),
+            priceBandRejections: self.band_rejections.iter().map(|r| r.to_solidity()).collect(),
+            halts: self.halts.iter().map(|h| h.to_solidity()).collect(),
+            excludedMatches: self.excluded.iter().map(|e| e.to_solidity()).collect(),
+            phaseUpdates: self.phase_updates.iter().map(|u| u.to_solidity()).collect(),
         }
     }
 }
 
-/// Encode the matched orders for submission to the CLOB contract
-pub fn encode_matches_output(matches: &[MatchResult]) -> Result<Vec<u8>> {
-    let sol_matches: Vec<solidity::OrderMatch> = matches.iter().map(|m| m.to_solidity()).collect();
-    Ok(sol_matches.abi_encode())
+/// Encode the trigger output for submission to the CLOB contract
+pub fn encode_output(output: &TriggerOutput) -> Result<Vec<u8>> {
+    Ok(output.to_solidity().abi_encode())
 }
 
 /// Parse CLOBTrigger event to get order ID
@@ -80,22 +681,132 @@ pub fn parse_order_placed_event(
     let event: solidity::OrderPlaced = decode_event_log_data!(log_data)?;
 
     // Convert event data to our Order struct
-    let order_type = match event.orderType {
-        0 => OrderType::Buy,
-        1 => OrderType::Sell,
-        _ => return Err(anyhow::anyhow!("Invalid order type: {}", event.orderType)),
-    };
-
+    let (order_type, kind, denomination) = decode_order_type(event.orderType)?;
     Ok(Order {
         id: event.orderId.to::<u64>(),
         trader: event.trader,
         order_type,
+        kind,
+        denomination,
         base_token: event.baseToken,
         quote_token: event.quoteToken,
         price: event.price,
         amount: event.amount,
         filled_amount: U256::ZERO, // New orders start unfilled
-        status: OrderStatus::Open,
+        status: if event.stopPrice.is_zero() {
+            OrderStatus::Open
+        } else {
+            OrderStatus::PendingTrigger
+        },
         timestamp: event.timestamp.to::<u64>(),
+        time_in_force: TimeInForce::from_sol(event.timeInForce)?,
+        expires_at: event.expiry.to::<u64>(),
+        post_only: PostOnly::from_sol(event.postOnly)?,
+        stop_price: event.stopPrice,
+        display_amount: event.displayAmount,
+    })
+}
+
+/// Parse OrderModified event data into the order's new terms
+pub fn parse_order_modified_event(
+    log_data: crate::bindings::wavs::types::chain::EvmEventLogData,
+) -> Result<OrderModification> {
+    let event: solidity::OrderModified = decode_event_log_data!(log_data)?;
+    Ok(OrderModification {
+        order_id: event.orderId.to::<u64>(),
+        price: event.newPrice,
+        amount: event.newAmount,
+        remaining_amount: event.remainingAmount,
     })
 }
+
+/// Parse OrderCancelled event data from contract events
+pub fn parse_order_cancelled_event(
+    log_data: crate::bindings::wavs::types::chain::EvmEventLogData,
+) -> Result<u64> {
+    let event: solidity::OrderCancelled = decode_event_log_data!(log_data)?;
+    Ok(event.orderId.to::<u64>())
+}
+
+/// Parse OrderMatched event data into the fill it settled on-chain, its
+/// market and the block time it settled at
+pub fn parse_order_matched_event(
+    log_data: crate::bindings::wavs::types::chain::EvmEventLogData,
+) -> Result<(MatchResult, MarketKey, u64)> {
+    let event: solidity::OrderMatched = decode_event_log_data!(log_data)?;
+    let market = MarketKey::new(event.baseToken, event.quoteToken);
+    Ok((settled_match(&event), market, event.timestamp.to::<u64>()))
+}
+
+/// The fill an OrderMatched event reports as settled
+pub fn settled_match(event: &solidity::OrderMatched) -> MatchResult {
+    // The event leaves out fees and the taker; settling only compares ids
+    // and amounts
+    let taker_side =
+        if event.buyOrderId > event.sellOrderId { OrderType::Buy } else { OrderType::Sell };
+    MatchResult {
+        buy_order_id: event.buyOrderId,
+        sell_order_id: event.sellOrderId,
+        match_amount: event.amount,
+        match_price: event.price,
+        taker_side,
+        buyer_fee: U256::ZERO,
+        seller_fee: U256::ZERO,
+    }
+}
+
+/// Parse MarketPhaseChanged event data into the market and its new phase
+pub fn parse_market_phase_changed_event(
+    log_data: crate::bindings::wavs::types::chain::EvmEventLogData,
+) -> Result<(MarketKey, MarketPhase)> {
+    let event: solidity::MarketPhaseChanged = decode_event_log_data!(log_data)?;
+    Ok((MarketKey::new(event.baseToken, event.quoteToken), MarketPhase::from_sol(event.phase)?))
+}
+
+/// Parse OrderPartiallyFilled event data into
+/// `(order id, amount filled by this match, amount still open on-chain)`
+pub fn parse_order_partially_filled_event(
+    log_data: crate::bindings::wavs::types::chain::EvmEventLogData,
+) -> Result<(u64, U256, U256)> {
+    let event: solidity::OrderPartiallyFilled = decode_event_log_data!(log_data)?;
+    Ok((event.orderId.to::<u64>(), event.filledAmount, event.remainingAmount))
+}
+
+/// Orders for unit tests.
+#[cfg(test)]
+pub mod test_orders {
+    use super::*;
+
+    pub const BASE: Address = Address::repeat_byte(0xba);
+    pub const QUOTE: Address = Address::repeat_byte(0x0c);
+
+    /// An open good-till-cancelled limit order for `amount` base tokens of
+    /// the `BASE`/`QUOTE` market.
+    pub fn order(
+        id: u64,
+        trader: Address,
+        order_type: OrderType,
+        price: u64,
+        amount: u64,
+    ) -> Order {
+        Order {
+            id,
+            trader,
+            order_type,
+            kind: OrderKind::Limit,
+            denomination: Denomination::Base,
+            base_token: BASE,
+            quote_token: QUOTE,
+            price: U256::from(price),
+            amount: U256::from(amount),
+            filled_amount: U256::ZERO,
+            status: OrderStatus::Open,
+            timestamp: 0,
+            time_in_force: TimeInForce::Gtc,
+            expires_at: 0,
+            post_only: PostOnly::Off,
+            stop_price: U256::ZERO,
+            display_amount: U256::ZERO,
+        }
+    }
+}
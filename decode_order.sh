@@ -23,6 +23,11 @@ decode_order() {
     local filled_amount_hex="0x${hex_data:448:64}"
     local status_hex="0x${hex_data:512:64}"
     local timestamp_hex="0x${hex_data:576:64}"
+    local time_in_force_hex="0x${hex_data:640:64}"
+    local expiry_hex="0x${hex_data:704:64}"
+    local post_only_hex="0x${hex_data:768:64}"
+    local stop_price_hex="0x${hex_data:832:64}"
+    local display_amount_hex="0x${hex_data:896:64}"
 
     # Convert to decimal/addresses
     local id=$(cast to-dec "$id_hex")
@@ -35,12 +40,21 @@ decode_order() {
     local filled_amount_wei=$(cast to-dec "$filled_amount_hex")
     local status=$(cast to-dec "$status_hex")
     local timestamp=$(cast to-dec "$timestamp_hex")
+    local time_in_force=$(cast to-dec "$time_in_force_hex")
+    local expiry=$(cast to-dec "$expiry_hex")
+    local post_only=$(cast to-dec "$post_only_hex")
+    local stop_price_wei=$(cast to-dec "$stop_price_hex")
+    local display_amount_wei=$(cast to-dec "$display_amount_hex")
 
     # Convert order type
     local order_type_str
     case $order_type in
         0) order_type_str="BUY" ;;
         1) order_type_str="SELL" ;;
+        2) order_type_str="MARKET_BUY" ;;
+        3) order_type_str="MARKET_SELL" ;;
+        4) order_type_str="QUOTE_BUY" ;;
+        5) order_type_str="QUOTE_MARKET_BUY" ;;
         *) order_type_str="UNKNOWN($order_type)" ;;
     esac
 
@@ -51,9 +65,29 @@ decode_order() {
         1) status_str="PARTIALLY_FILLED" ;;
         2) status_str="FILLED" ;;
         3) status_str="CANCELLED" ;;
+        4) status_str="PENDING_TRIGGER" ;;
         *) status_str="UNKNOWN($status)" ;;
     esac
 
+    # Convert time in force
+    local time_in_force_str
+    case $time_in_force in
+        0) time_in_force_str="GTC" ;;
+        1) time_in_force_str="IOC" ;;
+        2) time_in_force_str="FOK" ;;
+        3) time_in_force_str="GTD" ;;
+        *) time_in_force_str="UNKNOWN($time_in_force)" ;;
+    esac
+
+    # Convert post-only flag
+    local post_only_str
+    case $post_only in
+        0) post_only_str="NONE" ;;
+        1) post_only_str="REJECT" ;;
+        2) post_only_str="SLIDE" ;;
+        *) post_only_str="UNKNOWN($post_only)" ;;
+    esac
+
     # Convert wei to ETH
     local price_eth=$(cast from-wei "$price_wei")
     local amount_eth=$(cast from-wei "$amount_wei")
@@ -78,6 +112,17 @@ decode_order() {
     echo "Filled Amount: $filled_amount_eth ETH ($filled_amount_wei wei)"
     echo "Status: $status_str ($status)"
     echo "Timestamp: $date_str"
+    echo "Time In Force: $time_in_force_str ($time_in_force)"
+    if [ "$time_in_force" = "3" ]; then
+        echo "Expiry: $(date -r "$expiry" 2>/dev/null || echo "$expiry")"
+    fi
+    echo "Post Only: $post_only_str ($post_only)"
+    if [ "$stop_price_wei" != "0" ]; then
+        echo "Stop Price: $(cast from-wei "$stop_price_wei") ETH ($stop_price_wei wei)"
+    fi
+    if [ "$display_amount_wei" != "0" ]; then
+        echo "Display Amount: $(cast from-wei "$display_amount_wei") ETH ($display_amount_wei wei)"
+    fi
 
     # Calculate fill percentage if amount > 0
     if [ "$amount_wei" != "0" ] && [ -n "$amount_wei" ]; then
@@ -9,16 +9,54 @@ import {IWavsServiceHandler} from "@wavs/src/eigenlayer/ecdsa/interfaces/IWavsSe
 contract CLOB is IWavsServiceHandler {
     using SafeERC20 for IERC20;
 
+    // MARKET_* orders fill against resting liquidity up to `price`, which is
+    // the slippage cap, and any unfilled remainder is cancelled. QUOTE_*
+    // buys size `amount` in quote tokens to spend rather than base tokens to
+    // buy; their `filledAmount` counts quote tokens spent
     enum OrderType {
         BUY,
-        SELL
+        SELL,
+        MARKET_BUY,
+        MARKET_SELL,
+        QUOTE_BUY,
+        QUOTE_MARKET_BUY
     }
 
+    // GTD orders carry an expiry timestamp; the others have expiry zero
+    enum TimeInForce {
+        GTC,
+        IOC,
+        FOK,
+        GTD
+    }
+
+    // Post-only orders never take liquidity; a crossing one is rejected or
+    // re-priced one tick behind the best opposite price by the operator
+    enum PostOnly {
+        NONE,
+        REJECT,
+        SLIDE
+    }
+
+    // Stop orders start as PENDING_TRIGGER and open once the operator sees
+    // the last trade price cross their stop price
     enum OrderStatus {
         OPEN,
         PARTIALLY_FILLED,
         FILLED,
-        CANCELLED
+        CANCELLED,
+        PENDING_TRIGGER
+    }
+
+    // Trading phases the operator follows per market. Orders are accepted in
+    // every phase; only matching depends on it
+    enum MarketPhase {
+        PRE_OPEN,
+        OPENING_AUCTION,
+        CONTINUOUS,
+        HALTED,
+        CLOSING_AUCTION,
+        CLOSED
     }
 
     struct Order {
@@ -32,6 +70,13 @@ contract CLOB is IWavsServiceHandler {
         uint256 filledAmount;
         OrderStatus status;
         uint256 timestamp;
+        TimeInForce timeInForce;
+        uint256 expiry;
+        PostOnly postOnly;
+        uint256 stopPrice;
+        // Iceberg orders show only this much of their remaining amount at a
+        // time (0 shows the whole order)
+        uint256 displayAmount;
     }
 
     struct OrderMatch {
@@ -39,20 +84,108 @@ contract CLOB is IWavsServiceHandler {
         uint256 sellOrderId;
         uint256 matchAmount;
         uint256 matchPrice;
+        // Aggressor side; the other order was resting
+        bool buyerIsTaker;
+        // Paid out of the base tokens the buyer receives
+        uint256 buyerFee;
+        // Paid out of the quote tokens the seller receives
+        uint256 sellerFee;
+    }
+
+    struct OrderCancellation {
+        uint256 orderId;
+        uint256 amount;
+        uint8 reason;
+    }
+
+    struct OrderReprice {
+        uint256 orderId;
+        uint256 newPrice;
+    }
+
+    struct PriceBandRejection {
+        uint256 buyOrderId;
+        uint256 sellOrderId;
+        uint256 price;
+        uint256 lowerBound;
+        uint256 upperBound;
+    }
+
+    struct MarketHalt {
+        address baseToken;
+        address quoteToken;
+        uint256 price;
+        uint256 referencePrice;
+    }
+
+    // reason: 0 missing order, 1 wrong side, 2 order not open, 3 exceeds
+    // remaining amount, 4 insufficient escrow, 5 free to a quote buy
+    struct ExcludedMatch {
+        uint256 buyOrderId;
+        uint256 sellOrderId;
+        uint256 amount;
+        uint8 reason;
     }
 
+    struct Market {
+        address baseToken;
+        address quoteToken;
+    }
+
+    // A call auction the operator finished, moving its market on
+    struct MarketPhaseUpdate {
+        address baseToken;
+        address quoteToken;
+        MarketPhase phase;
+    }
+
+    struct ClobOutput {
+        bytes32 stateRoot;
+        OrderMatch[] matches;
+        OrderCancellation[] cancellations;
+        OrderReprice[] reprices;
+        uint256[] triggeredStops;
+        PriceBandRejection[] priceBandRejections;
+        MarketHalt[] halts;
+        ExcludedMatch[] excludedMatches;
+        MarketPhaseUpdate[] phaseUpdates;
+    }
+
+    // OrderCancellation reasons from here on reject an order that breaks
+    // the market's rules: 7 tick, 8 lot, 9 below min size, 10 above max
+    // size, 11 below min notional
+    uint8 private constant MIN_REJECT_REASON = 7;
+
     IWavsServiceManager private _serviceManager;
     uint256 public nextOrderId = 1;
 
+    // May move markets between trading phases
+    address public admin;
+
+    // Collects trading fees in escrowBalances
+    address public feeRecipient;
+
     mapping(uint256 => Order) public orders;
     mapping(address => uint256[]) public userOrders;
     mapping(bytes32 => uint256[]) public orderBook;
 
+    // Every pair an order was ever placed in, in order of its first order
+    Market[] public markets;
+
+    // Phase the admin, a circuit breaker halt or a finished call auction
+    // last moved each market to. Markets never moved trade in the phase the
+    // operator's config gives them
+    mapping(bytes32 => MarketPhase) public marketPhases;
+    mapping(bytes32 => bool) public marketPhaseSet;
+
     mapping(address => mapping(address => uint256)) public escrowBalances;
 
     // Track processed envelopes to prevent replay
     mapping(bytes32 => bool) public processedEnvelopes;
 
+    // Off-chain order book state root from the most recent envelope that carried one
+    bytes32 public lastStateRoot;
+
     event OrderPlaced(
         uint256 indexed orderId,
         address indexed trader,
@@ -61,7 +194,38 @@ contract CLOB is IWavsServiceHandler {
         address quoteToken,
         uint256 price,
         uint256 amount,
-        uint256 timestamp
+        uint256 timestamp,
+        TimeInForce timeInForce,
+        uint256 expiry,
+        PostOnly postOnly,
+        uint256 stopPrice,
+        uint256 displayAmount
+    );
+
+    event StopTriggered(
+        uint256 indexed orderId,
+        uint256 stopPrice
+    );
+
+    event OrderReduced(
+        uint256 indexed orderId,
+        uint256 releasedAmount,
+        uint256 remainingAmount
+    );
+
+    event OrderRepriced(
+        uint256 indexed orderId,
+        uint256 oldPrice,
+        uint256 newPrice
+    );
+
+    event OrderModified(
+        uint256 indexed orderId,
+        address indexed trader,
+        uint256 oldPrice,
+        uint256 newPrice,
+        uint256 newAmount,
+        uint256 remainingAmount
     );
 
     event OrderCancelled(
@@ -102,8 +266,55 @@ contract CLOB is IWavsServiceHandler {
         uint256 indexed orderId
     );
 
+    event StateRootCommitted(
+        bytes32 indexed stateRoot
+    );
+
+    event MarketPhaseChanged(
+        address indexed baseToken,
+        address indexed quoteToken,
+        MarketPhase phase
+    );
+
+    event FeesCharged(
+        uint256 indexed buyOrderId,
+        uint256 indexed sellOrderId,
+        bool buyerIsTaker,
+        uint256 buyerFee,
+        uint256 sellerFee
+    );
+
+    event OrderRejected(
+        uint256 indexed orderId,
+        uint8 reason
+    );
+
+    event MatchRejected(
+        uint256 indexed buyOrderId,
+        uint256 indexed sellOrderId,
+        uint256 price,
+        uint256 lowerBound,
+        uint256 upperBound
+    );
+
+    event MarketHalted(
+        address indexed baseToken,
+        address indexed quoteToken,
+        uint256 price,
+        uint256 referencePrice
+    );
+
+    event MatchExcluded(
+        uint256 indexed buyOrderId,
+        uint256 indexed sellOrderId,
+        uint256 amount,
+        uint8 reason
+    );
+
     constructor(IWavsServiceManager serviceManager) {
         _serviceManager = serviceManager;
+        admin = msg.sender;
+        feeRecipient = msg.sender;
     }
 
     modifier validOrder(uint256 _orderId) {
@@ -111,6 +322,37 @@ contract CLOB is IWavsServiceHandler {
         _;
     }
 
+    modifier onlyAdmin() {
+        require(msg.sender == admin, "Only admin");
+        _;
+    }
+
+    // Pausing, opening and closing a market is done by the operator, which
+    // picks the new phase up from this event
+    function setMarketPhase(
+        address _baseToken,
+        address _quoteToken,
+        MarketPhase _phase
+    ) external onlyAdmin {
+        _setMarketPhase(_baseToken, _quoteToken, _phase);
+        emit MarketPhaseChanged(_baseToken, _quoteToken, _phase);
+    }
+
+    function _setMarketPhase(
+        address _baseToken,
+        address _quoteToken,
+        MarketPhase _phase
+    ) internal {
+        bytes32 bookKey = getOrderBookKey(_baseToken, _quoteToken);
+        marketPhases[bookKey] = _phase;
+        marketPhaseSet[bookKey] = true;
+    }
+
+    function setFeeRecipient(address _feeRecipient) external onlyAdmin {
+        require(_feeRecipient != address(0), "Invalid fee recipient");
+        feeRecipient = _feeRecipient;
+    }
+
     function placeOrder(
         OrderType _orderType,
         address _baseToken,
@@ -118,17 +360,87 @@ contract CLOB is IWavsServiceHandler {
         uint256 _price,
         uint256 _amount
     ) external  returns (uint256) { // nonReentrant
+        return _placeOrder(_orderType, _baseToken, _quoteToken, _price, _amount, TimeInForce.GTC, 0, PostOnly.NONE, 0, 0);
+    }
+
+    function placeOrder(
+        OrderType _orderType,
+        address _baseToken,
+        address _quoteToken,
+        uint256 _price,
+        uint256 _amount,
+        TimeInForce _timeInForce,
+        uint256 _expiry,
+        PostOnly _postOnly,
+        uint256 _stopPrice,
+        uint256 _displayAmount
+    ) external  returns (uint256) { // nonReentrant
+        return _placeOrder(
+            _orderType,
+            _baseToken,
+            _quoteToken,
+            _price,
+            _amount,
+            _timeInForce,
+            _expiry,
+            _postOnly,
+            _stopPrice,
+            _displayAmount
+        );
+    }
+
+    function _placeOrder(
+        OrderType _orderType,
+        address _baseToken,
+        address _quoteToken,
+        uint256 _price,
+        uint256 _amount,
+        TimeInForce _timeInForce,
+        uint256 _expiry,
+        PostOnly _postOnly,
+        uint256 _stopPrice,
+        uint256 _displayAmount
+    ) internal returns (uint256) {
         require(_baseToken != address(0), "Invalid base token");
         require(_quoteToken != address(0), "Invalid quote token");
         require(_price > 0, "Price must be greater than 0");
         require(_amount > 0, "Amount must be greater than 0");
+        if (_timeInForce == TimeInForce.GTD) {
+            require(_expiry > block.timestamp, "Expiry must be in the future");
+        } else {
+            require(_expiry == 0, "Expiry only applies to GTD orders");
+        }
+        if (_postOnly != PostOnly.NONE) {
+            require(!_isMarket(_orderType), "Market orders cannot be post-only");
+            require(
+                _timeInForce == TimeInForce.GTC || _timeInForce == TimeInForce.GTD,
+                "Post-only orders must rest"
+            );
+            require(_stopPrice == 0, "Stop orders cannot be post-only");
+        }
+        if (_isQuote(_orderType)) {
+            require(_timeInForce != TimeInForce.FOK, "Quote orders cannot be fill-or-kill");
+        }
+        if (_displayAmount != 0) {
+            require(_displayAmount < _amount, "Display amount must be below order amount");
+            require(!_isMarket(_orderType), "Market orders cannot be icebergs");
+            require(!_isQuote(_orderType), "Quote orders cannot be icebergs");
+            require(
+                _timeInForce == TimeInForce.GTC || _timeInForce == TimeInForce.GTD,
+                "Iceberg orders must rest"
+            );
+            require(_stopPrice == 0, "Stop orders cannot be icebergs");
+        }
 
         uint256 orderId = nextOrderId++;
 
         uint256 requiredAmount;
         address requiredToken;
 
-        if (_orderType == OrderType.BUY) {
+        if (_isQuote(_orderType)) {
+            requiredAmount = _amount;
+            requiredToken = _quoteToken;
+        } else if (_isBuy(_orderType)) {
             uint256 quoteAmount = (_amount * _price) / 1e18;
             requiredAmount = quoteAmount;
             requiredToken = _quoteToken;
@@ -154,14 +466,22 @@ contract CLOB is IWavsServiceHandler {
             price: _price,
             amount: _amount,
             filledAmount: 0,
-            status: OrderStatus.OPEN,
-            timestamp: block.timestamp
+            status: _stopPrice == 0 ? OrderStatus.OPEN : OrderStatus.PENDING_TRIGGER,
+            timestamp: block.timestamp,
+            timeInForce: _timeInForce,
+            expiry: _expiry,
+            postOnly: _postOnly,
+            stopPrice: _stopPrice,
+            displayAmount: _displayAmount
         });
 
         orders[orderId] = newOrder;
         userOrders[msg.sender].push(orderId);
 
         bytes32 bookKey = getOrderBookKey(_baseToken, _quoteToken);
+        if (orderBook[bookKey].length == 0) {
+            markets.push(Market({baseToken: _baseToken, quoteToken: _quoteToken}));
+        }
         orderBook[bookKey].push(orderId);
 
         emit OrderPlaced(
@@ -172,7 +492,12 @@ contract CLOB is IWavsServiceHandler {
             _quoteToken,
             _price,
             _amount,
-            block.timestamp
+            block.timestamp,
+            _timeInForce,
+            _expiry,
+            _postOnly,
+            _stopPrice,
+            _displayAmount
         );
 
         emit FundsDeposited(msg.sender, requiredToken, requiredAmount);
@@ -187,35 +512,146 @@ contract CLOB is IWavsServiceHandler {
         Order storage order = orders[_orderId];
         require(order.trader == msg.sender, "Not order owner");
         require(
-            order.status == OrderStatus.OPEN ||
-            order.status == OrderStatus.PARTIALLY_FILLED,
+            _isOpen(order) || order.status == OrderStatus.PENDING_TRIGGER,
             "Order cannot be cancelled"
         );
 
+        _cancel(order);
+    }
+
+    // Changes the price and total amount of an open limit order, keeping its
+    // id. The escrow backing the open amount is topped up from the trader or
+    // the excess refunded. The operators decide whether the order keeps its
+    // queue priority.
+    function modifyOrder(
+        uint256 _orderId,
+        uint256 _newPrice,
+        uint256 _newAmount
+    ) external validOrder(_orderId) { // nonReentrant
+        Order storage order = orders[_orderId];
+        require(order.trader == msg.sender, "Not order owner");
+        require(_isOpen(order), "Order cannot be modified");
+        require(!_isMarket(order.orderType), "Market orders cannot be modified");
+        require(_newPrice > 0, "Price must be greater than 0");
+        require(_newAmount > order.filledAmount, "Amount must exceed filled amount");
+        if (order.displayAmount != 0) {
+            require(order.displayAmount < _newAmount, "Display amount must be below order amount");
+        }
+
+        uint256 oldRemaining = order.amount - order.filledAmount;
+        uint256 newRemaining = _newAmount - order.filledAmount;
+        uint256 oldRequired;
+        uint256 newRequired;
+        address token;
+
+        if (_isQuote(order.orderType)) {
+            oldRequired = oldRemaining;
+            newRequired = newRemaining;
+            token = order.quoteToken;
+        } else if (_isBuy(order.orderType)) {
+            oldRequired = (oldRemaining * order.price) / 1e18;
+            newRequired = (newRemaining * _newPrice) / 1e18;
+            token = order.quoteToken;
+        } else {
+            oldRequired = oldRemaining;
+            newRequired = newRemaining;
+            token = order.baseToken;
+        }
+
+        uint256 oldPrice = order.price;
+        order.price = _newPrice;
+        order.amount = _newAmount;
+
+        if (newRequired > oldRequired) {
+            uint256 topUp = newRequired - oldRequired;
+            IERC20(token).safeTransferFrom(msg.sender, address(this), topUp);
+            escrowBalances[msg.sender][token] += topUp;
+
+            emit FundsDeposited(msg.sender, token, topUp);
+        } else if (oldRequired > newRequired) {
+            uint256 refundAmount = oldRequired - newRequired;
+            if (escrowBalances[msg.sender][token] >= refundAmount) {
+                escrowBalances[msg.sender][token] -= refundAmount;
+                IERC20(token).safeTransfer(msg.sender, refundAmount);
+
+                emit FundsWithdrawn(msg.sender, token, refundAmount);
+            }
+        }
+
+        emit OrderModified(_orderId, msg.sender, oldPrice, _newPrice, _newAmount, newRemaining);
+    }
+
+    function _cancel(Order storage order) internal {
+        uint256 remainingAmount = order.amount - order.filledAmount;
+
+        order.status = OrderStatus.CANCELLED;
+        _refund(order, remainingAmount);
+
+        emit OrderCancelled(order.id, order.trader, remainingAmount);
+    }
+
+    // Takes `_amount` out of an order without a match, cancelling it once
+    // nothing is left open
+    function _release(Order storage order, uint256 _amount) internal {
         uint256 remainingAmount = order.amount - order.filledAmount;
+        if (_amount >= remainingAmount) {
+            _cancel(order);
+            return;
+        }
+
+        order.amount -= _amount;
+        _refund(order, _amount);
 
+        emit OrderReduced(order.id, _amount, remainingAmount - _amount);
+    }
+
+    function _refund(Order storage order, uint256 _amount) internal {
         uint256 refundAmount;
         address refundToken;
 
-        if (order.orderType == OrderType.BUY) {
-            refundAmount = (remainingAmount * order.price) / 1e18;
+        if (_isQuote(order.orderType)) {
+            refundAmount = _amount;
+            refundToken = order.quoteToken;
+        } else if (_isBuy(order.orderType)) {
+            refundAmount = (_amount * order.price) / 1e18;
             refundToken = order.quoteToken;
         } else {
-            refundAmount = remainingAmount;
+            refundAmount = _amount;
             refundToken = order.baseToken;
         }
 
-        order.status = OrderStatus.CANCELLED;
+        if (refundAmount > 0 && escrowBalances[order.trader][refundToken] >= refundAmount) {
+            escrowBalances[order.trader][refundToken] -= refundAmount;
 
-        if (refundAmount > 0 && escrowBalances[msg.sender][refundToken] >= refundAmount) {
-            escrowBalances[msg.sender][refundToken] -= refundAmount;
+            IERC20(refundToken).safeTransfer(order.trader, refundAmount);
 
-            IERC20(refundToken).safeTransfer(msg.sender, refundAmount);
-
-            emit FundsWithdrawn(msg.sender, refundToken, refundAmount);
+            emit FundsWithdrawn(order.trader, refundToken, refundAmount);
         }
+    }
 
-        emit OrderCancelled(_orderId, msg.sender, remainingAmount);
+    function _isBuy(OrderType _orderType) internal pure returns (bool) {
+        return _orderType == OrderType.BUY
+            || _orderType == OrderType.MARKET_BUY
+            || _isQuote(_orderType);
+    }
+
+    function _isMarket(OrderType _orderType) internal pure returns (bool) {
+        return _orderType == OrderType.MARKET_BUY
+            || _orderType == OrderType.MARKET_SELL
+            || _orderType == OrderType.QUOTE_MARKET_BUY;
+    }
+
+    function _isQuote(OrderType _orderType) internal pure returns (bool) {
+        return _orderType == OrderType.QUOTE_BUY || _orderType == OrderType.QUOTE_MARKET_BUY;
+    }
+
+    // What the most base `_quote` buys at `_price` costs, both rounded down
+    function _quoteCost(uint256 _quote, uint256 _price) internal pure returns (uint256) {
+        return (((_quote * 1e18) / _price) * _price) / 1e18;
+    }
+
+    function _isOpen(Order storage order) internal view returns (bool) {
+        return order.status == OrderStatus.OPEN || order.status == OrderStatus.PARTIALLY_FILLED;
     }
 
     function withdrawFunds(address _token, uint256 _amount) external { // nonReentrant
@@ -246,15 +682,90 @@ contract CLOB is IWavsServiceHandler {
         require(!processedEnvelopes[envelopeHash], "Envelope already processed");
         processedEnvelopes[envelopeHash] = true;
 
-        // Decode the order match data from the envelope
-        OrderMatch[] memory matches = abi.decode(
+        // Decode the matching output from the envelope
+        ClobOutput memory output = abi.decode(
             envelope.payload,
-            (OrderMatch[])
+            (ClobOutput)
         );
 
+        if (output.stateRoot != bytes32(0)) {
+            lastStateRoot = output.stateRoot;
+            emit StateRootCommitted(output.stateRoot);
+        }
+
+        // Open stop orders the operator triggered, before matches fill them
+        for (uint256 i = 0; i < output.triggeredStops.length; i++) {
+            Order storage order = orders[output.triggeredStops[i]];
+            if (order.status == OrderStatus.PENDING_TRIGGER) {
+                order.status = OrderStatus.OPEN;
+                emit StopTriggered(order.id, order.stopPrice);
+            }
+        }
+
         // Execute all order matches
-        for (uint256 i = 0; i < matches.length; i++) {
-            executeMatch(matches[i]);
+        for (uint256 i = 0; i < output.matches.length; i++) {
+            executeMatch(output.matches[i]);
+        }
+
+        // Release amounts the operator took out of the book: unfilled market
+        // and IOC orders, killed FOK orders, expired GTD orders, self-trades
+        // and orders breaking the market's tick, lot, size or notional rules
+        for (uint256 i = 0; i < output.cancellations.length; i++) {
+            Order storage order = orders[output.cancellations[i].orderId];
+            if (order.id != 0 && (_isOpen(order) || order.status == OrderStatus.PENDING_TRIGGER)) {
+                if (output.cancellations[i].reason >= MIN_REJECT_REASON) {
+                    emit OrderRejected(order.id, output.cancellations[i].reason);
+                }
+                _release(order, output.cancellations[i].amount);
+            }
+        }
+
+        // Move sliding post-only orders behind the opposite side. The buyer's
+        // escrow freed by a lower price stays withdrawable in escrowBalances;
+        // a quote buy keeps its quote and buys more at the lower price.
+        for (uint256 i = 0; i < output.reprices.length; i++) {
+            Order storage order = orders[output.reprices[i].orderId];
+            if (order.id != 0 && _isOpen(order) && order.postOnly == PostOnly.SLIDE) {
+                uint256 oldPrice = order.price;
+                order.price = output.reprices[i].newPrice;
+                emit OrderRepriced(order.id, oldPrice, order.price);
+            }
+        }
+
+        // Record matches the operator refused for trading outside the price
+        // band; the newer order of each pair is among the cancellations
+        for (uint256 i = 0; i < output.priceBandRejections.length; i++) {
+            PriceBandRejection memory rejection = output.priceBandRejections[i];
+            emit MatchRejected(
+                rejection.buyOrderId,
+                rejection.sellOrderId,
+                rejection.price,
+                rejection.lowerBound,
+                rejection.upperBound
+            );
+        }
+
+        // Markets the circuit breaker halted stay halted until the admin
+        // moves them on with setMarketPhase
+        for (uint256 i = 0; i < output.halts.length; i++) {
+            MarketHalt memory halt = output.halts[i];
+            _setMarketPhase(halt.baseToken, halt.quoteToken, MarketPhase.HALTED);
+            emit MarketHalted(halt.baseToken, halt.quoteToken, halt.price, halt.referencePrice);
+        }
+
+        // Record where finished opening and closing auctions moved their
+        // markets
+        for (uint256 i = 0; i < output.phaseUpdates.length; i++) {
+            MarketPhaseUpdate memory update = output.phaseUpdates[i];
+            _setMarketPhase(update.baseToken, update.quoteToken, update.phase);
+            emit MarketPhaseChanged(update.baseToken, update.quoteToken, update.phase);
+        }
+
+        // Record what the operator left out of the matches because it would
+        // have reverted executeMatch
+        for (uint256 i = 0; i < output.excludedMatches.length; i++) {
+            ExcludedMatch memory excluded = output.excludedMatches[i];
+            emit MatchExcluded(excluded.buyOrderId, excluded.sellOrderId, excluded.amount, excluded.reason);
         }
     }
 
@@ -267,34 +778,37 @@ contract CLOB is IWavsServiceHandler {
         // Validate orders exist and are valid
         require(buyOrder.id != 0, "Buy order does not exist");
         require(sellOrder.id != 0, "Sell order does not exist");
-        require(buyOrder.orderType == OrderType.BUY, "Invalid buy order");
-        require(sellOrder.orderType == OrderType.SELL, "Invalid sell order");
+        require(_isBuy(buyOrder.orderType), "Invalid buy order");
+        require(!_isBuy(sellOrder.orderType), "Invalid sell order");
 
         // Check order status
-        require(
-            buyOrder.status == OrderStatus.OPEN || buyOrder.status == OrderStatus.PARTIALLY_FILLED,
-            "Buy order not open"
-        );
-        require(
-            sellOrder.status == OrderStatus.OPEN || sellOrder.status == OrderStatus.PARTIALLY_FILLED,
-            "Sell order not open"
-        );
+        require(_isOpen(buyOrder), "Buy order not open");
+        require(_isOpen(sellOrder), "Sell order not open");
+
+        // Calculate quote amount based on match price
+        uint256 quoteAmount = (matchData.matchAmount * matchData.matchPrice) / 1e18;
 
-        // Validate match parameters
+        // Validate match parameters; a quote buy is sized in the quote it spends
+        uint256 buyFill = _isQuote(buyOrder.orderType) ? quoteAmount : matchData.matchAmount;
+        require(!_isQuote(buyOrder.orderType) || buyFill > 0, "Match costs the quote buy nothing");
         uint256 remainingBuy = buyOrder.amount - buyOrder.filledAmount;
         uint256 remainingSell = sellOrder.amount - sellOrder.filledAmount;
-        require(matchData.matchAmount <= remainingBuy, "Match amount exceeds buy order");
+        require(buyFill <= remainingBuy, "Match amount exceeds buy order");
         require(matchData.matchAmount <= remainingSell, "Match amount exceeds sell order");
 
         // Update order states
-        buyOrder.filledAmount += matchData.matchAmount;
+        buyOrder.filledAmount += buyFill;
         sellOrder.filledAmount += matchData.matchAmount;
 
-        if (buyOrder.filledAmount == buyOrder.amount) {
+        // A quote buy is done once what it has left cannot pay for a fill
+        // that costs anything at its limit; that dust goes back to the
+        // trader below
+        uint256 leftBuy = buyOrder.amount - buyOrder.filledAmount;
+        if (leftBuy == 0 || (_isQuote(buyOrder.orderType) && _quoteCost(leftBuy, buyOrder.price) == 0)) {
             buyOrder.status = OrderStatus.FILLED;
         } else {
             buyOrder.status = OrderStatus.PARTIALLY_FILLED;
-            emit OrderPartiallyFilled(buyOrder.id, matchData.matchAmount, buyOrder.amount - buyOrder.filledAmount);
+            emit OrderPartiallyFilled(buyOrder.id, buyFill, leftBuy);
         }
 
         if (sellOrder.filledAmount == sellOrder.amount) {
@@ -304,15 +818,31 @@ contract CLOB is IWavsServiceHandler {
             emit OrderPartiallyFilled(sellOrder.id, matchData.matchAmount, sellOrder.amount - sellOrder.filledAmount);
         }
 
-        // Calculate quote amount based on match price
-        uint256 quoteAmount = (matchData.matchAmount * matchData.matchPrice) / 1e18;
+        require(matchData.buyerFee <= matchData.matchAmount, "Buyer fee exceeds amount");
+        require(matchData.sellerFee <= quoteAmount, "Seller fee exceeds amount");
 
-        // Update escrow balances
+        // Update escrow balances, each side paying its fee out of what it receives
         escrowBalances[buyOrder.trader][buyOrder.quoteToken] -= quoteAmount;
         escrowBalances[sellOrder.trader][sellOrder.baseToken] -= matchData.matchAmount;
 
-        escrowBalances[buyOrder.trader][buyOrder.baseToken] += matchData.matchAmount;
-        escrowBalances[sellOrder.trader][sellOrder.quoteToken] += quoteAmount;
+        escrowBalances[buyOrder.trader][buyOrder.baseToken] += matchData.matchAmount - matchData.buyerFee;
+        escrowBalances[sellOrder.trader][sellOrder.quoteToken] += quoteAmount - matchData.sellerFee;
+
+        if (buyOrder.status == OrderStatus.FILLED) {
+            _refund(buyOrder, leftBuy);
+        }
+
+        if (matchData.buyerFee > 0 || matchData.sellerFee > 0) {
+            escrowBalances[feeRecipient][buyOrder.baseToken] += matchData.buyerFee;
+            escrowBalances[feeRecipient][buyOrder.quoteToken] += matchData.sellerFee;
+            emit FeesCharged(
+                buyOrder.id,
+                sellOrder.id,
+                matchData.buyerIsTaker,
+                matchData.buyerFee,
+                matchData.sellerFee
+            );
+        }
 
         // Emit match event
         emit OrderMatched(
@@ -353,6 +883,19 @@ contract CLOB is IWavsServiceHandler {
         return orderBook[bookKey];
     }
 
+    function getMarkets() external view returns (Market[] memory) {
+        return markets;
+    }
+
+    // isSet is false for a market whose phase was never moved
+    function getMarketPhase(
+        address _baseToken,
+        address _quoteToken
+    ) external view returns (bool isSet, MarketPhase phase) {
+        bytes32 bookKey = getOrderBookKey(_baseToken, _quoteToken);
+        return (marketPhaseSet[bookKey], marketPhases[bookKey]);
+    }
+
     function getServiceManager() external view returns (address) {
         return address(_serviceManager);
     }